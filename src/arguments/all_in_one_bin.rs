@@ -1,156 +1,584 @@
-﻿use super::Arguments;
+﻿use super::{Architecture, Arguments};
 use crate::{kernel::slice, tokenizer::utok};
 use memmap2::Mmap;
-use std::fs::File;
+use std::{
+    fs::File,
+    io::{self, Write},
+};
+
+/// llama2.c 带版本的模型文件的魔数 `ak42`。
+const MAGIC: u32 = 0x616b3432;
+/// 带版本的模型文件的头部填充到 256 字节。
+const HEADER_LEN: usize = 256;
+
+/// llama2.c 格式的模型文件，支持没有版本号的旧格式、fp32 的 v1 和 Q8_0 量化的 v2。
+///
+/// 旧格式和 v1 的权重直接从映射的文件中读取，v2 的权重在加载时反量化为 fp32。
+pub(crate) struct AllInOneBin {
+    config: Config,
+    shared: bool,
+    data: Data,
+    offsets: Offsets,
+}
 
-pub(crate) struct AllInOneBin(Mmap);
+enum Data {
+    /// 映射的文件和头部的字节数。
+    Mapped(Mmap, usize),
+    /// 反量化的权重。
+    Owned(Vec<f32>),
+}
+
+/// 各个权重在 fp32 数据中的起始位置。
+struct Offsets {
+    token_embedding_table: usize,
+    rms_att_weight: usize,
+    rms_ffn_weight: usize,
+    wq: usize,
+    wk: usize,
+    wv: usize,
+    wo: usize,
+    w1: usize,
+    w2: usize,
+    w3: usize,
+    rms_final_weight: usize,
+    wcls: usize,
+}
 
 impl AllInOneBin {
-    #[inline]
     pub fn new(file: File) -> Self {
-        Self(unsafe { Mmap::map(&file).expect("failed to map the weights file") })
+        let mmap = unsafe { Mmap::map(&file).expect("failed to map the weights file") };
+        let read_i32 = |offset: usize| {
+            let bytes = mmap
+                .get(offset..offset + 4)
+                .expect("the weights file is too short for the header");
+            i32::from_le_bytes(bytes.try_into().unwrap())
+        };
+
+        if read_i32(0) as u32 != MAGIC {
+            // 旧格式：词表大小为负表示不共享分类器
+            let config = Config::read(&mmap[..]);
+            let shared = config.vocab_size > 0;
+            let mut layout = Layout::default();
+            let offsets = layout.legacy(&config, shared);
+            check_len(&mmap, size_of::<Config>() + layout.0 * 4);
+            return Self {
+                config,
+                shared,
+                data: Data::Mapped(mmap, size_of::<Config>()),
+                offsets,
+            };
+        }
+
+        let version = read_i32(4);
+        let config = Config::read(&mmap[8..]);
+        let shared = match mmap.get(8 + size_of::<Config>()) {
+            Some(0) => false,
+            Some(1) => true,
+            _ => panic!("invalid shared classifier flag"),
+        };
+        assert!(config.vocab_size > 0, "invalid config {config:?}");
+        let mut layout = Layout::default();
+        let offsets = layout.versioned(&config, shared);
+        match version {
+            1 => {
+                check_len(&mmap, HEADER_LEN + layout.0 * 4);
+                Self {
+                    config,
+                    shared,
+                    data: Data::Mapped(mmap, HEADER_LEN),
+                    offsets,
+                }
+            }
+            2 => {
+                let group_size = read_i32(9 + size_of::<Config>());
+                assert!(group_size > 0, "invalid group size {group_size}");
+                let group_size = group_size as usize;
+                // 归一化的权重是 fp32，其余每个张量依次存放 int8 的值和每组的缩放系数
+                let n_norms = offsets.token_embedding_table;
+                let tensors = quantized_lens(&config, shared);
+                let n_quantized = tensors.iter().sum::<usize>();
+                assert!(
+                    tensors.iter().all(|len| len % group_size == 0),
+                    "group size {group_size} does not divide the weights"
+                );
+                check_len(
+                    &mmap,
+                    HEADER_LEN + n_norms * 4 + n_quantized + n_quantized / group_size * 4,
+                );
+
+                let mut data = Vec::with_capacity(layout.0);
+                let (norms, mut quantized) = mmap[HEADER_LEN..].split_at(n_norms * 4);
+                data.extend(read_f32(norms));
+                for len in tensors {
+                    let (q, tail) = quantized.split_at(len);
+                    let (scales, tail) = tail.split_at(len / group_size * 4);
+                    quantized = tail;
+                    let scales = read_f32(scales).collect::<Vec<_>>();
+                    data.extend(
+                        q.iter()
+                            .enumerate()
+                            .map(|(i, &q)| q as i8 as f32 * scales[i / group_size]),
+                    );
+                }
+                debug_assert_eq!(data.len(), layout.0);
+                Self {
+                    config,
+                    shared,
+                    data: Data::Owned(data),
+                    offsets,
+                }
+            }
+            _ => panic!("unsupported llama2.c version {version}"),
+        }
     }
 
     #[inline(always)]
-    fn config(&self) -> &Config {
-        self.config_weights().0
+    fn data(&self) -> &[f32] {
+        match &self.data {
+            Data::Mapped(mmap, header) => {
+                let (head, data, tail) = unsafe { mmap[*header..].align_to::<f32>() };
+                debug_assert!(head.is_empty() && tail.is_empty());
+                data
+            }
+            Data::Owned(data) => data,
+        }
     }
 
     #[inline(always)]
-    fn weights(&self) -> Weights<'_> {
-        let (config, weights) = self.config_weights();
-        let (head, data, tail) = unsafe { weights.align_to::<f32>() };
-        debug_assert!(head.is_empty() && tail.is_empty());
-        Weights(config, data)
+    fn tensor(&self, offset: usize) -> &[f32] {
+        &self.data()[offset..]
     }
+}
 
-    #[inline(always)]
-    fn config_weights(&self) -> (&Config, &[u8]) {
-        let (config, weights) = self.0.split_at(std::mem::size_of::<Config>());
-        (unsafe { &*config.as_ptr().cast() }, weights)
+/// 按顺序为权重分配位置，记录已经分配的长度。
+#[derive(Default)]
+struct Layout(usize);
+
+impl Layout {
+    fn next(&mut self, len: usize) -> usize {
+        let offset = self.0;
+        self.0 += len;
+        offset
+    }
+
+    /// 旧格式：词嵌入、注意力、前馈网络、最后一层的归一化、rope 频率的空隙、分类器。
+    fn legacy(&mut self, config: &Config, shared: bool) -> Offsets {
+        let (dim, kv_dim, hidden_dim) = (config.dim(), config.kv_dim(), config.hidden_dim());
+        let n_layers = config.n_layers();
+        let token_embedding_table = self.next(config.vocab_size() * dim);
+        let rms_att_weight = self.next(n_layers * dim);
+        let wq = self.next(n_layers * dim * dim);
+        let wk = self.next(n_layers * kv_dim * dim);
+        let wv = self.next(n_layers * kv_dim * dim);
+        let wo = self.next(n_layers * dim * dim);
+        let rms_ffn_weight = self.next(n_layers * dim);
+        let w1 = self.next(n_layers * hidden_dim * dim);
+        let w2 = self.next(n_layers * dim * hidden_dim);
+        let w3 = self.next(n_layers * hidden_dim * dim);
+        let rms_final_weight = self.next(dim);
+        // freq_cis_real 和 freq_cis_imag
+        self.next(config.seq_len() * config.head_size() / 2 * 2);
+        let wcls = if shared {
+            token_embedding_table
+        } else {
+            self.next(config.vocab_size() * dim)
+        };
+        Offsets {
+            token_embedding_table,
+            rms_att_weight,
+            rms_ffn_weight,
+            wq,
+            wk,
+            wv,
+            wo,
+            w1,
+            w2,
+            w3,
+            rms_final_weight,
+            wcls,
+        }
+    }
+
+    /// 带版本的格式：先是所有归一化的权重，然后是词嵌入、各个矩阵和分类器，与 [`quantized_lens`] 的顺序相同。
+    fn versioned(&mut self, config: &Config, shared: bool) -> Offsets {
+        let (dim, kv_dim, hidden_dim) = (config.dim(), config.kv_dim(), config.hidden_dim());
+        let n_layers = config.n_layers();
+        let rms_att_weight = self.next(n_layers * dim);
+        let rms_ffn_weight = self.next(n_layers * dim);
+        let rms_final_weight = self.next(dim);
+        let token_embedding_table = self.next(config.vocab_size() * dim);
+        let wq = self.next(n_layers * dim * dim);
+        let wk = self.next(n_layers * kv_dim * dim);
+        let wv = self.next(n_layers * kv_dim * dim);
+        let wo = self.next(n_layers * dim * dim);
+        let w1 = self.next(n_layers * hidden_dim * dim);
+        let w2 = self.next(n_layers * dim * hidden_dim);
+        let w3 = self.next(n_layers * hidden_dim * dim);
+        let wcls = if shared {
+            token_embedding_table
+        } else {
+            self.next(config.vocab_size() * dim)
+        };
+        Offsets {
+            token_embedding_table,
+            rms_att_weight,
+            rms_ffn_weight,
+            wq,
+            wk,
+            wv,
+            wo,
+            w1,
+            w2,
+            w3,
+            rms_final_weight,
+            wcls,
+        }
     }
 }
 
+/// v2 中每个量化张量的长度：词嵌入、每层的各个矩阵、不共享时的分类器。
+fn quantized_lens(config: &Config, shared: bool) -> Vec<usize> {
+    let (dim, kv_dim, hidden_dim) = (config.dim(), config.kv_dim(), config.hidden_dim());
+    let n_layers = config.n_layers();
+    let mut lens = vec![config.vocab_size() * dim];
+    for len in [
+        dim * dim,
+        kv_dim * dim,
+        kv_dim * dim,
+        dim * dim,
+        hidden_dim * dim,
+        dim * hidden_dim,
+        hidden_dim * dim,
+    ] {
+        lens.extend(std::iter::repeat_n(len, n_layers));
+    }
+    if !shared {
+        lens.push(config.vocab_size() * dim);
+    }
+    lens
+}
+
+fn check_len(mmap: &Mmap, expected: usize) {
+    assert_eq!(
+        mmap.len(),
+        expected,
+        "the size of the weights file does not match the config"
+    );
+}
+
+#[inline]
+fn read_f32(bytes: &[u8]) -> impl Iterator<Item = f32> + '_ {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+}
+
 impl Arguments for AllInOneBin {
     #[inline]
     fn dim(&self) -> usize {
-        self.config().dim()
+        self.config.dim()
     }
 
     #[inline]
     fn hidden_dim(&self) -> usize {
-        self.config().hidden_dim()
+        self.config.hidden_dim()
     }
 
     #[inline]
     fn n_layers(&self) -> usize {
-        self.config().n_layers()
+        self.config.n_layers()
     }
 
     #[inline]
     fn n_heads(&self) -> usize {
-        self.config().n_heads()
+        self.config.n_heads()
     }
 
     #[inline]
     fn n_kv_heads(&self) -> usize {
-        self.config().n_kv_heads()
+        self.config.n_kv_heads()
     }
 
     #[inline]
     fn vocab_size(&self) -> usize {
-        self.config().vocab_size()
+        self.config.vocab_size()
     }
 
     #[inline]
     fn seq_len(&self) -> usize {
-        self.config().seq_len()
+        self.config.seq_len()
+    }
+
+    #[inline]
+    fn tie_word_embeddings(&self) -> bool {
+        self.shared
     }
 
     fn token_embedding_table(&self, token: utok) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.token_embedding_table().0;
-        let dim = weights.0.dim();
+        let data = self.tensor(self.offsets.token_embedding_table);
+        let dim = self.config.dim();
         &slice!(data; dim; [token as usize])
     }
 
     fn rms_att_weight(&self, layer: usize) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.rms_att_weight().0;
-        let dim = weights.0.dim();
+        let data = self.tensor(self.offsets.rms_att_weight);
+        let dim = self.config.dim();
         &slice!(data; dim; [layer])
     }
 
     fn rms_ffn_weight(&self, layer: usize) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.rms_ffn_weight().0;
-        let dim = weights.0.dim();
+        let data = self.tensor(self.offsets.rms_ffn_weight);
+        let dim = self.config.dim();
         &slice!(data; dim; [layer])
     }
 
     fn wq(&self, layer: usize) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.wq().0;
-        let dim = weights.0.dim();
+        let data = self.tensor(self.offsets.wq);
+        let dim = self.config.dim();
         &slice!(data; dim * dim; [layer])
     }
 
     fn wk(&self, layer: usize) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.wk().0;
-        let kv_dim = weights.0.kv_dim();
-        let dim = weights.0.dim();
+        let data = self.tensor(self.offsets.wk);
+        let kv_dim = self.config.kv_dim();
+        let dim = self.config.dim();
         &slice!(data; kv_dim * dim; [layer])
     }
 
     fn wv(&self, layer: usize) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.wv().0;
-        let kv_dim = weights.0.kv_dim();
-        let dim = weights.0.dim();
+        let data = self.tensor(self.offsets.wv);
+        let kv_dim = self.config.kv_dim();
+        let dim = self.config.dim();
         &slice!(data; kv_dim * dim; [layer])
     }
 
     fn wo(&self, layer: usize) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.wo().0;
-        let dim = weights.0.dim();
+        let data = self.tensor(self.offsets.wo);
+        let dim = self.config.dim();
         &slice!(data; dim * dim; [layer])
     }
 
     fn w1(&self, layer: usize) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.w1().0;
-        let dim = weights.0.dim();
-        let hidden_dim = weights.0.hidden_dim();
+        let data = self.tensor(self.offsets.w1);
+        let dim = self.config.dim();
+        let hidden_dim = self.config.hidden_dim();
         &slice!(data; dim * hidden_dim; [layer])
     }
 
     fn w2(&self, layer: usize) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.w2().0;
-        let dim = weights.0.dim();
-        let hidden_dim = weights.0.hidden_dim();
+        let data = self.tensor(self.offsets.w2);
+        let dim = self.config.dim();
+        let hidden_dim = self.config.hidden_dim();
         &slice!(data; hidden_dim * dim; [layer])
     }
 
     fn w3(&self, layer: usize) -> &[f32] {
-        let weights = self.weights();
-        let data = weights.w3().0;
-        let dim = weights.0.dim();
-        let hidden_dim = weights.0.hidden_dim();
+        let data = self.tensor(self.offsets.w3);
+        let dim = self.config.dim();
+        let hidden_dim = self.config.hidden_dim();
         &slice!(data; dim * hidden_dim; [layer])
     }
 
     fn rms_final_weight(&self) -> &[f32] {
-        self.weights().rms_final_weight().0
+        &self.tensor(self.offsets.rms_final_weight)[..self.config.dim()]
     }
 
     fn wcls(&self) -> &[f32] {
-        self.weights().wcls().0
+        &self.tensor(self.offsets.wcls)[..self.config.vocab_size() * self.config.dim()]
     }
 }
 
+/// 按 llama2.c 的格式写出模型：`version` 为 0 时是没有版本号的旧格式，1 为 fp32，2 为 Q8_0 量化。
+///
+/// 推理时的 q、k 已经是 llama2.c 相邻两维配对旋转的排列，[`SafeTensors`](super::SafeTensors) 加载时做的重排正是转换到这个排列，
+/// 所以直接写出。llama2.c 只能表示 llama 结构，模型有其他结构时报错。
+pub(crate) fn export(arguments: &dyn Arguments, version: u32, w: &mut dyn Write) -> io::Result<()> {
+    check_exportable(arguments)?;
+    let dim = arguments.dim();
+    let n_layers = arguments.n_layers();
+    let head_size = arguments.head_size();
+    let seq_len = arguments.seq_len();
+    let shared = arguments.tie_word_embeddings();
+    let vocab_size = arguments.vocab_size();
+    let header = [
+        dim,
+        arguments.hidden_dim(),
+        n_layers,
+        arguments.n_heads(),
+        arguments.n_kv_heads(),
+        vocab_size,
+        seq_len,
+    ]
+    .map(|n| n as i32);
+
+    let embedding = (0..vocab_size)
+        .flat_map(|t| arguments.token_embedding_table(t as _))
+        .copied()
+        .collect::<Vec<_>>();
+    let layers = |f: fn(&dyn Arguments, usize) -> &[f32]| {
+        (0..n_layers).map(|l| f(arguments, l)).collect::<Vec<_>>()
+    };
+    let att_norm = layers(|a, l| a.rms_att_weight(l));
+    let ffn_norm = layers(|a, l| a.rms_ffn_weight(l));
+    let matrices = [
+        layers(|a, l| a.wq(l)),
+        layers(|a, l| a.wk(l)),
+        layers(|a, l| a.wv(l)),
+        layers(|a, l| a.wo(l)),
+        layers(|a, l| a.w1(l)),
+        layers(|a, l| a.w2(l)),
+        layers(|a, l| a.w3(l)),
+    ];
+    let wcls = (!shared).then(|| arguments.wcls());
+
+    match version {
+        // 旧格式：词表大小为负表示不共享分类器，最后一层的归一化之后有 rope 频率的空隙
+        0 => {
+            let mut header = header;
+            if !shared {
+                header[5] = -header[5];
+            }
+            header
+                .iter()
+                .try_for_each(|n| w.write_all(&n.to_le_bytes()))?;
+            write_f32(w, &embedding)?;
+            att_norm.iter().try_for_each(|x| write_f32(w, x))?;
+            for m in &matrices[..4] {
+                m.iter().try_for_each(|x| write_f32(w, x))?;
+            }
+            ffn_norm.iter().try_for_each(|x| write_f32(w, x))?;
+            for m in &matrices[4..] {
+                m.iter().try_for_each(|x| write_f32(w, x))?;
+            }
+            write_f32(w, arguments.rms_final_weight())?;
+            // freq_cis_real 和 freq_cis_imag，推理时不再使用
+            let freqs = (0..head_size / 2)
+                .map(|i| 1e4f32.powf(-((2 * i) as f32) / head_size as f32))
+                .collect::<Vec<_>>();
+            let angles = (0..seq_len).flat_map(|t| freqs.iter().map(move |f| t as f32 * f));
+            write_f32(w, &angles.clone().map(f32::cos).collect::<Vec<_>>())?;
+            write_f32(w, &angles.map(f32::sin).collect::<Vec<_>>())?;
+            wcls.map_or(Ok(()), |x| write_f32(w, x))
+        }
+        // 带版本的格式：256 字节的头部，先写出所有归一化的权重，再写出词嵌入和各个矩阵
+        1 | 2 => {
+            let group_size = group_size(dim);
+            let mut head = Vec::with_capacity(HEADER_LEN);
+            head.extend_from_slice(&MAGIC.to_le_bytes());
+            head.extend_from_slice(&(version as i32).to_le_bytes());
+            header
+                .iter()
+                .for_each(|n| head.extend_from_slice(&n.to_le_bytes()));
+            head.push(shared as u8);
+            if version == 2 {
+                head.extend_from_slice(&(group_size as i32).to_le_bytes());
+            }
+            head.resize(HEADER_LEN, 0);
+            w.write_all(&head)?;
+
+            att_norm.iter().try_for_each(|x| write_f32(w, x))?;
+            ffn_norm.iter().try_for_each(|x| write_f32(w, x))?;
+            write_f32(w, arguments.rms_final_weight())?;
+            let tensors = std::iter::once(&embedding[..])
+                .chain(matrices.iter().flatten().copied())
+                .chain(wcls);
+            for x in tensors {
+                if version == 1 {
+                    write_f32(w, x)?;
+                } else {
+                    let (q, scales) = quantize_q8_0(x, group_size);
+                    w.write_all(&q.iter().map(|&q| q as u8).collect::<Vec<_>>())?;
+                    write_f32(w, &scales)?;
+                }
+            }
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported llama2.c version {version}"),
+        )),
+    }
+}
+
+/// llama2.c 的格式只有 llama 结构的权重，也不记录 rope 和归一化的参数。
+fn check_exportable(arguments: &dyn Arguments) -> io::Result<()> {
+    let n_layers = arguments.n_layers();
+    let has_bias = (0..n_layers).any(|l| {
+        [
+            arguments.att_norm_bias(l),
+            arguments.ffn_norm_bias(l),
+            arguments.bq(l),
+            arguments.bk(l),
+            arguments.bv(l),
+            arguments.bo(l),
+            arguments.b1(l),
+            arguments.b2(l),
+            arguments.b3(l),
+        ]
+        .iter()
+        .any(Option::is_some)
+    }) || arguments.final_norm_bias().is_some()
+        || arguments.bcls().is_some();
+    let unsupported = if arguments.architecture() != Architecture::LLAMA {
+        Some("architectures other than llama")
+    } else if arguments.q_dim() != arguments.dim() {
+        Some("head_dim other than dim / n_heads")
+    } else if has_bias {
+        Some("biases")
+    } else if arguments.n_experts() > 0 {
+        Some("mixture of experts")
+    } else if arguments.rms_norm_eps() != 1e-5 {
+        Some("rms_norm_eps other than 1e-5")
+    } else if arguments.rope_theta() != 1e4 || arguments.rope_scaling().is_some() {
+        Some("rope other than theta 10000 without scaling")
+    } else if arguments
+        .sliding_window()
+        .is_some_and(|window| window < arguments.seq_len())
+    {
+        Some("sliding window attention")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(what) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("llama2.c format does not support {what}"),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Q8_0 量化的分组大小：不超过 64 且整除 `dim`。
+fn group_size(dim: usize) -> usize {
+    let mut group_size = 64;
+    while !dim.is_multiple_of(group_size) {
+        group_size /= 2;
+    }
+    group_size
+}
+
+/// 每 `group_size` 个数一组对称量化到 int8，返回量化的值和每组的缩放系数。
+fn quantize_q8_0(x: &[f32], group_size: usize) -> (Vec<i8>, Vec<f32>) {
+    assert_eq!(x.len() % group_size, 0);
+    let mut q = Vec::with_capacity(x.len());
+    let mut scales = Vec::with_capacity(x.len() / group_size);
+    for group in x.chunks(group_size) {
+        let max = group.iter().fold(0f32, |max, x| max.max(x.abs()));
+        let scale = max / 127.;
+        let inv = if scale == 0. { 0. } else { 1. / scale };
+        q.extend(group.iter().map(|x| (x * inv).round() as i8));
+        scales.push(scale);
+    }
+    (q, scales)
+}
+
+#[inline]
+fn write_f32(w: &mut dyn Write, data: &[f32]) -> io::Result<()> {
+    w.write_all(unsafe {
+        std::slice::from_raw_parts(data.as_ptr().cast(), std::mem::size_of_val(data))
+    })
+}
+
 #[derive(Debug)]
 #[repr(C)]
 struct Config {
@@ -164,6 +592,31 @@ struct Config {
 }
 
 impl Config {
+    /// 读取并检查文件头中的配置。
+    fn read(bytes: &[u8]) -> Self {
+        assert!(
+            bytes.len() >= size_of::<Self>(),
+            "the weights file is too short for the header"
+        );
+        let config = unsafe { bytes.as_ptr().cast::<Self>().read_unaligned() };
+        let positive = [
+            config.dim,
+            config.hidden_dim,
+            config.n_layers,
+            config.n_heads,
+            config.n_kv_heads,
+            config.seq_len,
+        ]
+        .iter()
+        .all(|&n| n > 0);
+        let valid = positive
+            && config.vocab_size != 0
+            && config.dim % config.n_heads == 0
+            && config.n_heads % config.n_kv_heads == 0;
+        assert!(valid, "invalid config {config:?}");
+        config
+    }
+
     #[inline]
     pub const fn dim(&self) -> usize {
         self.dim as _
@@ -189,11 +642,6 @@ impl Config {
         self.n_kv_heads as _
     }
 
-    #[inline]
-    pub const fn shared_weight(&self) -> bool {
-        self.vocab_size > 0
-    }
-
     #[inline]
     pub const fn vocab_size(&self) -> usize {
         self.vocab_size.unsigned_abs() as _
@@ -204,85 +652,98 @@ impl Config {
         self.seq_len as _
     }
 
+    #[inline]
+    pub const fn head_size(&self) -> usize {
+        (self.dim / self.n_heads) as _
+    }
+
     #[inline]
     pub const fn kv_dim(&self) -> usize {
         (self.dim * self.n_kv_heads / self.n_heads) as _
     }
 }
 
-pub(super) struct Weights<'a>(&'a Config, &'a [f32]);
-
-impl<'a> Weights<'a> {
-    fn token_embedding_table(&self) -> (&'a [f32], &'a [f32]) {
-        self.1.split_at(self.0.vocab_size() * self.0.dim())
-    }
-
-    fn rms_att_weight(&self) -> (&'a [f32], &'a [f32]) {
-        self.token_embedding_table()
-            .1
-            .split_at(self.0.n_layers() * self.0.dim())
-    }
-
-    fn rms_ffn_weight(&self) -> (&'a [f32], &'a [f32]) {
-        self.wo().1.split_at(self.0.n_layers() * self.0.dim())
-    }
-
-    fn wq(&self) -> (&'a [f32], &'a [f32]) {
-        self.rms_att_weight()
-            .1
-            .split_at(self.0.n_layers() * self.0.dim() * self.0.dim())
-    }
-
-    fn wk(&self) -> (&'a [f32], &'a [f32]) {
-        self.wq()
-            .1
-            .split_at(self.0.n_layers() * self.0.kv_dim() * self.0.dim())
-    }
-
-    fn wv(&self) -> (&'a [f32], &'a [f32]) {
-        self.wk()
-            .1
-            .split_at(self.0.n_layers() * self.0.kv_dim() * self.0.dim())
-    }
-
-    fn wo(&self) -> (&'a [f32], &'a [f32]) {
-        self.wv()
-            .1
-            .split_at(self.0.n_layers() * self.0.dim() * self.0.dim())
-    }
-
-    fn w1(&self) -> (&'a [f32], &'a [f32]) {
-        self.rms_ffn_weight()
-            .1
-            .split_at(self.0.n_layers() * self.0.dim() * self.0.hidden_dim())
-    }
-
-    fn w2(&self) -> (&'a [f32], &'a [f32]) {
-        self.w1()
-            .1
-            .split_at(self.0.n_layers() * self.0.hidden_dim() * self.0.dim())
-    }
-
-    fn w3(&self) -> (&'a [f32], &'a [f32]) {
-        self.w2()
-            .1
-            .split_at(self.0.n_layers() * self.0.dim() * self.0.hidden_dim())
-    }
-
-    fn rms_final_weight(&self) -> (&'a [f32], &'a [f32]) {
-        self.w3().1.split_at(self.0.dim())
-    }
-
-    fn wcls(&self) -> (&'a [f32], &'a [f32]) {
-        let data = &self.rms_final_weight().1
-            [self.0.seq_len() * (self.0.dim() / self.0.n_heads()) / 2 * 2..];
-        let wcls = if self.0.shared_weight() {
-            self.token_embedding_table().0
+#[test]
+fn test_versions() {
+    // 构造一个不共享分类器的旧格式模型，导出为 v1 和 v2 再加载
+    let dir = std::env::temp_dir().join(format!("llama2-rs-bin-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut bytes = [64i32, 96, 2, 4, 2, -40, 8]
+        .iter()
+        .flat_map(|n| n.to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut layout = Layout::default();
+    layout.legacy(&Config::read(&bytes), false);
+    (0..layout.0)
+        .map(|i| ((i * 7919 % 1000) as f32 / 500. - 1.) * 0.1)
+        .for_each(|x| bytes.extend_from_slice(&x.to_le_bytes()));
+    let path = dir.join("v0.bin");
+    std::fs::write(&path, &bytes).unwrap();
+    let v0 = AllInOneBin::new(File::open(&path).unwrap());
+    assert!(!v0.tie_word_embeddings());
+
+    for version in [1, 2] {
+        let path = dir.join(format!("v{version}.bin"));
+        let mut out = Vec::new();
+        export(&v0, version, &mut out).unwrap();
+        std::fs::write(&path, &out).unwrap();
+        let bin = AllInOneBin::new(File::open(&path).unwrap());
+        assert_eq!(bin.vocab_size(), v0.vocab_size());
+        assert!(!bin.tie_word_embeddings());
+
+        // Q8_0 每组的误差不超过缩放系数的一半
+        let tolerance = if version == 1 {
+            0.
         } else {
-            let (wcls, data) = data.split_at(self.0.vocab_size() * self.0.dim());
-            debug_assert!(data.is_empty());
-            wcls
+            0.1 / 127. / 2. + 1e-7
+        };
+        let check = |a: &[f32], b: &[f32]| {
+            assert_eq!(a.len(), b.len());
+            assert!(std::iter::zip(a, b).all(|(a, b)| (a - b).abs() <= tolerance));
         };
-        (wcls, &[])
+        for t in 0..v0.vocab_size() as utok {
+            check(bin.token_embedding_table(t), v0.token_embedding_table(t));
+        }
+        for l in 0..v0.n_layers() {
+            assert_eq!(bin.rms_att_weight(l), v0.rms_att_weight(l));
+            assert_eq!(bin.rms_ffn_weight(l), v0.rms_ffn_weight(l));
+            check(bin.wq(l), v0.wq(l));
+            check(bin.wk(l), v0.wk(l));
+            check(bin.wv(l), v0.wv(l));
+            check(bin.wo(l), v0.wo(l));
+            check(bin.w1(l), v0.w1(l));
+            check(bin.w2(l), v0.w2(l));
+            check(bin.w3(l), v0.w3(l));
+        }
+        assert_eq!(bin.rms_final_weight(), v0.rms_final_weight());
+        check(bin.wcls(), v0.wcls());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_export_eps() {
+    use super::TinyModel;
+    use crate::Transformer;
+
+    // 加载 `.bin` 时 epsilon 固定为 1e-5，其他 epsilon 的模型不能导出
+    let model = TinyModel::new(serde_json::json!({ "rms_norm_eps": 1e-6 }), 0xe95);
+    let err = model.load("export-eps").export_bin(1, &mut Vec::new());
+    assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    let model = TinyModel::new(serde_json::json!({}), 0xe95);
+    let mut out = Vec::new();
+    model
+        .load("export-eps-default")
+        .export_bin(1, &mut out)
+        .unwrap();
+    let dir = super::TempDir::new("export-eps-bin");
+    std::fs::write(dir.join("model.bin"), out).unwrap();
+    let tokens = [1, 9, 4, 20];
+    let actual = Transformer::read_checkpoint(dir.join("model.bin"))
+        .forward_all(&tokens, 0, &mut ())
+        .unwrap();
+    for (a, b) in std::iter::zip(actual, model.forward(&tokens)) {
+        assert!((a - b).abs() < 1e-4, "{a} != {b}");
     }
 }
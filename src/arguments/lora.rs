@@ -0,0 +1,327 @@
+﻿use super::{
+    safetensors::{cast_slice, permute, MetaJson},
+    Arguments,
+};
+use memmap2::Mmap;
+use std::{collections::HashMap, fs::File, io::Read};
+
+/// LoRA 作用的投影矩阵。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Proj {
+    Q,
+    K,
+    V,
+    O,
+    /// 前馈网络的 `w1`。
+    Gate,
+    /// 前馈网络的 `w3`。
+    Up,
+    /// 前馈网络的 `w2`。
+    Down,
+}
+
+const N_PROJ: usize = 7;
+
+/// 一个投影的 `(A, B)`：`A` 为 `r x in`，`B` 为 `out x r`。
+type LowRank = (Vec<f32>, Vec<f32>);
+
+/// PEFT 格式的 LoRA 适配器，推理时作为低秩旁路 `y += B * (A * x)` 叠加到基础模型的投影上，
+/// 不修改基础模型的权重，可以随时换入换出。
+///
+/// `B` 在加载时乘上缩放系数 `lora_alpha / r`（`use_rslora` 时为 `lora_alpha / sqrt(r)`），
+/// q、k 的 `B` 与基础模型的权重一样按 rope 重排。只支持注意力和稠密前馈网络的投影。
+pub struct Lora {
+    /// 每层每个投影的低秩矩阵，按 [`Proj`] 的顺序存放。
+    layers: Vec<[Option<LowRank>; N_PROJ]>,
+}
+
+impl Lora {
+    /// 读取 `adapter_config.json` 和 `adapter_model.safetensors`，`arguments` 是适配器对应的基础模型。
+    pub fn new(config: File, safetensors: File, arguments: &dyn Arguments) -> Self {
+        let n_layers = arguments.n_layers();
+        let dim = arguments.dim();
+        let q_dim = arguments.q_dim();
+        let kv_dim = arguments.kv_dim();
+        let hidden_dim = arguments.hidden_dim();
+        let head_size = arguments.head_size();
+        let rotary_dim = arguments.architecture().rotary_dim(head_size);
+
+        let mut layers = (0..n_layers)
+            .map(|_| Default::default())
+            .collect::<Vec<_>>();
+        for (name, (r, a, b)) in read_pairs(config, safetensors) {
+            let path = name.split('.').collect::<Vec<_>>();
+            let ["model", "layers", l, module @ .., "weight"] = path.as_slice() else {
+                panic!("unsupported lora target {name}");
+            };
+            let (l, module) = (l.parse::<usize>().unwrap(), module.join("."));
+            assert!(l < n_layers);
+            let (in_, out) = (a.len() / r, b.len() / r);
+            let check = |rows: usize, cols: usize| {
+                assert_eq!((out, in_), (rows, cols), "shape of lora for {name}")
+            };
+
+            let layer: &mut [Option<LowRank>; N_PROJ] = &mut layers[l];
+            let mut set = |proj: Proj, b: &[f32], perm: bool| {
+                let b = if perm {
+                    let mut dst = vec![0.; b.len()];
+                    permute(&mut dst, b, r, head_size, rotary_dim);
+                    dst
+                } else {
+                    b.to_vec()
+                };
+                layer[proj as usize] = Some((a.clone(), b));
+            };
+            match module.as_str() {
+                "self_attn.q_proj" => {
+                    check(q_dim, dim);
+                    set(Proj::Q, &b, true);
+                }
+                "self_attn.k_proj" => {
+                    check(kv_dim, dim);
+                    set(Proj::K, &b, true);
+                }
+                "self_attn.v_proj" => {
+                    check(kv_dim, dim);
+                    set(Proj::V, &b, false);
+                }
+                // phi3 合并的 qkv_proj 和 gate_up_proj 共用 A，按行拆开 B
+                "self_attn.qkv_proj" => {
+                    check(q_dim + 2 * kv_dim, dim);
+                    let (q, kv) = b.split_at(q_dim * r);
+                    let (k, v) = kv.split_at(kv_dim * r);
+                    set(Proj::Q, q, true);
+                    set(Proj::K, k, true);
+                    set(Proj::V, v, false);
+                }
+                "self_attn.o_proj" | "self_attn.dense" => {
+                    check(dim, q_dim);
+                    set(Proj::O, &b, false);
+                }
+                "mlp.gate_proj" | "mlp.fc1" => {
+                    check(hidden_dim, dim);
+                    set(Proj::Gate, &b, false);
+                }
+                "mlp.up_proj" => {
+                    check(hidden_dim, dim);
+                    set(Proj::Up, &b, false);
+                }
+                "mlp.gate_up_proj" => {
+                    check(2 * hidden_dim, dim);
+                    let (gate, up) = b.split_at(hidden_dim * r);
+                    set(Proj::Gate, gate, false);
+                    set(Proj::Up, up, false);
+                }
+                "mlp.down_proj" | "mlp.fc2" => {
+                    check(dim, hidden_dim);
+                    set(Proj::Down, &b, false);
+                }
+                _ => panic!("unsupported lora target {name}"),
+            }
+        }
+        Self { layers }
+    }
+
+    /// 第 `layer` 层 `proj` 投影的 `(A, B)`，适配器没有这个投影时为 `None`。
+    #[inline]
+    pub(crate) fn get(&self, layer: usize, proj: Proj) -> Option<(&[f32], &[f32])> {
+        self.layers[layer][proj as usize]
+            .as_ref()
+            .map(|(a, b)| (&a[..], &b[..]))
+    }
+}
+
+/// 读取适配器中每个模块的 `(r, A, B)`，按基础模型中对应权重的名字索引，`B` 已经乘上缩放系数。
+pub(super) fn read_pairs(
+    mut config: File,
+    safetensors: File,
+) -> HashMap<String, (usize, Vec<f32>, Vec<f32>)> {
+    let mut config_string = String::new();
+    config.read_to_string(&mut config_string).unwrap();
+    let config = serde_json::from_str::<LoraConfig>(&config_string).unwrap();
+    assert!(
+        !config.fan_in_fan_out,
+        "fan_in_fan_out adapters are not supported"
+    );
+    assert!(
+        config.alpha_pattern.is_empty() && config.rank_pattern.is_empty(),
+        "per-module alpha_pattern and rank_pattern are not supported"
+    );
+
+    let mmap = unsafe { Mmap::map(&safetensors) }.unwrap();
+    let (len, tail) = mmap.split_at(std::mem::size_of::<u64>());
+    let len = unsafe { *len.as_ptr().cast::<u64>() } as usize;
+    let (meta_json, data) = tail.split_at(len);
+    let meta_json = serde_json::from_slice::<MetaJson>(meta_json).unwrap();
+
+    // 同一个模块的 A 和 B 是两个张量，先按名字收集
+    let mut pairs = HashMap::<String, [Option<(Vec<usize>, Vec<f32>)>; 2]>::new();
+    for (name, tensor) in meta_json.tensors {
+        let path = name.split('.').collect::<Vec<_>>();
+        let (module, kind) = match path.as_slice() {
+            [module @ .., kind @ ("lora_A" | "lora_B"), "weight"]
+            | [module @ .., kind @ ("lora_A" | "lora_B"), _, "weight"] => (module, *kind),
+            _ => panic!("unsupported lora tensor {name}"),
+        };
+        // PEFT 在基础模型的名字前加上 `base_model.model.`
+        let module = module
+            .strip_prefix(&["base_model", "model"][..])
+            .unwrap_or(module);
+        let data = cast_slice(data, &tensor).into_owned();
+        let pair = pairs
+            .entry(format!("{}.weight", module.join(".")))
+            .or_default();
+        pair[(kind == "lora_B") as usize] = Some((tensor.shape, data));
+    }
+
+    pairs
+        .into_iter()
+        .map(|(name, pair)| {
+            let [Some((a_shape, a)), Some((b_shape, mut b))] = pair else {
+                panic!("lora_A or lora_B for {name} is missing");
+            };
+            let [r, _] = a_shape[..] else {
+                panic!("lora_A for {name} is not a matrix");
+            };
+            assert_eq!(&b_shape, &[b_shape[0], r]);
+            let scaling = if config.use_rslora {
+                config.lora_alpha / (r as f32).sqrt()
+            } else {
+                config.lora_alpha / r as f32
+            };
+            b.iter_mut().for_each(|x| *x *= scaling);
+            (name, (r, a, b))
+        })
+        .collect()
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct LoraConfig {
+    lora_alpha: f32,
+    #[serde(default)]
+    use_rslora: bool,
+    /// 只有 gpt2 的 `Conv1D` 转置存放权重。
+    #[serde(default)]
+    fan_in_fan_out: bool,
+    /// 按模块设置的 `lora_alpha` 和 `r`，不支持。
+    #[serde(default)]
+    alpha_pattern: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    rank_pattern: HashMap<String, serde_json::Value>,
+}
+
+#[cfg(test)]
+fn write_adapter(
+    name: &str,
+    config: serde_json::Value,
+    tensors: &std::collections::BTreeMap<String, (Vec<usize>, Vec<f32>)>,
+) -> super::TempDir {
+    let dir = super::TempDir::new(name);
+    std::fs::write(dir.join("adapter_config.json"), config.to_string()).unwrap();
+    super::write_safetensors(&dir.join("adapter_model.safetensors"), tensors);
+    dir
+}
+
+#[test]
+fn test_adapter() {
+    use super::{Rng, TinyModel};
+    use serde_json::json;
+    use std::{collections::BTreeMap, iter::zip};
+
+    let tokens = (0..12).map(|i| (i * 7 + 2) % 32).collect::<Vec<_>>();
+    let llama = [
+        "self_attn.q_proj",
+        "self_attn.k_proj",
+        "self_attn.v_proj",
+        "self_attn.o_proj",
+        "mlp.gate_proj",
+        "mlp.up_proj",
+        "mlp.down_proj",
+    ];
+    let phi3 = [
+        "self_attn.qkv_proj",
+        "self_attn.o_proj",
+        "mlp.gate_up_proj",
+        "mlp.down_proj",
+    ];
+    let cases = [
+        ("llama", json!({}), &llama[..], false),
+        (
+            "phi3",
+            json!({ "architectures": ["Phi3ForCausalLM"] }),
+            &phi3[..],
+            true,
+        ),
+    ];
+    for (name, config, targets, use_rslora) in cases {
+        // 随机的适配器，同时把 scale * B * A 合并到 huggingface 布局的权重中作为参照
+        let base = TinyModel::new(config, 0x10a);
+        let mut merged = TinyModel {
+            config: base.config.clone(),
+            tensors: base.tensors.clone(),
+        };
+        let mut adapter = BTreeMap::new();
+        let mut rng = Rng(0x10b);
+        let alpha = 8.;
+        for l in 0..2 {
+            let r = 2 + l;
+            let scale = if use_rslora {
+                alpha / (r as f32).sqrt()
+            } else {
+                alpha / r as f32
+            };
+            for target in targets {
+                let module = format!("model.layers.{l}.{target}");
+                let (shape, w) = merged.tensors.get_mut(&format!("{module}.weight")).unwrap();
+                let (out, in_) = (shape[0], shape[1]);
+                let a = (0..r * in_)
+                    .map(|_| 0.3 * rng.uniform())
+                    .collect::<Vec<_>>();
+                let b = (0..out * r)
+                    .map(|_| 0.3 * rng.uniform())
+                    .collect::<Vec<_>>();
+                for i in 0..out {
+                    for j in 0..in_ {
+                        let ba = (0..r).map(|k| b[i * r + k] * a[k * in_ + j]).sum::<f32>();
+                        w[i * in_ + j] += scale * ba;
+                    }
+                }
+                let prefix = format!("base_model.model.{module}");
+                adapter.insert(format!("{prefix}.lora_A.weight"), (vec![r, in_], a));
+                adapter.insert(format!("{prefix}.lora_B.weight"), (vec![out, r], b));
+            }
+        }
+        let config = json!({
+            "peft_type": "LORA",
+            "r": 2,
+            "lora_alpha": alpha,
+            "use_rslora": use_rslora,
+            "target_modules": targets,
+        });
+        let dir = write_adapter(&format!("lora-{name}"), config, &adapter);
+
+        let mut transformer = base.load(&format!("lora-{name}-base"));
+        let lora = transformer.read_adapter(&*dir);
+        assert!(transformer.set_adapter(Some(lora)).is_none());
+        let actual = transformer.forward_all(&tokens, 0, &mut ()).unwrap();
+        let expected = merged.forward(&tokens);
+        for (a, b) in zip(&actual, &expected) {
+            assert!((a - b).abs() < 1e-4, "{name}: {a} != {b}");
+        }
+        // 适配器确实改变了输出
+        let base = base.forward(&tokens);
+        assert!(zip(&actual, &base).any(|(a, b)| (a - b).abs() > 1e-2));
+    }
+}
+
+#[test]
+#[should_panic(expected = "alpha_pattern and rank_pattern are not supported")]
+fn test_patterns() {
+    let config = serde_json::json!({ "lora_alpha": 16, "rank_pattern": { "q_proj": 4 } });
+    let dir = write_adapter("lora-patterns", config, &Default::default());
+    let file = |name: &str| File::open(dir.join(name)).unwrap();
+    read_pairs(
+        file("adapter_config.json"),
+        file("adapter_model.safetensors"),
+    );
+}
@@ -1,10 +1,163 @@
 ﻿mod all_in_one_bin;
+mod lora;
 mod safetensors;
+#[cfg(test)]
+mod tiny;
 
 use crate::tokenizer::utok;
 
-pub(crate) use all_in_one_bin::AllInOneBin;
+pub(crate) use all_in_one_bin::{export, AllInOneBin};
+pub use lora::Lora;
+pub(crate) use lora::Proj;
 pub use safetensors::SafeTensors;
+#[cfg(test)]
+pub(crate) use tiny::{write_safetensors, Rng, TempDir, TinyModel};
+
+/// rope 的位置缩放方式，用于超出训练长度的上下文。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RopeScaling {
+    /// 位置线性插值，所有频率除以 `factor`。
+    Linear { factor: f32 },
+    /// NTK-aware 缩放，上下文超过训练长度时按比例放大 rope base。
+    Dynamic { factor: f32 },
+    /// YaRN：高频维度保持不变，低频维度线性插值，中间平滑过渡，并按 `attention_factor` 放大注意力。
+    Yarn {
+        factor: f32,
+        original_max_position_embeddings: usize,
+        beta_fast: f32,
+        beta_slow: f32,
+        attention_factor: f32,
+    },
+    /// llama3.1 的按波长分段缩放。
+    Llama3 {
+        factor: f32,
+        original_max_position_embeddings: usize,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+    },
+}
+
+impl RopeScaling {
+    /// 解析 huggingface `config.json` 中的 `rope_scaling`，`max_position_embeddings` 为模型配置的上下文长度。
+    pub fn from_config(value: &serde_json::Value, max_position_embeddings: usize) -> Option<Self> {
+        let f32 = |key: &str, default: f32| value[key].as_f64().map_or(default, |x| x as f32);
+        let factor = f32("factor", 1.);
+        let original_max_position_embeddings = value["original_max_position_embeddings"]
+            .as_u64()
+            .map_or(max_position_embeddings, |x| x as usize);
+        let ty = value["rope_type"].as_str().or(value["type"].as_str())?;
+        match ty {
+            "default" => None,
+            "linear" => Some(Self::Linear { factor }),
+            "dynamic" => Some(Self::Dynamic { factor }),
+            "yarn" => Some(Self::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast: f32("beta_fast", 32.),
+                beta_slow: f32("beta_slow", 1.),
+                attention_factor: f32("attention_factor", 0.1 * factor.ln() + 1.),
+            }),
+            "llama3" => Some(Self::Llama3 {
+                factor,
+                original_max_position_embeddings,
+                low_freq_factor: f32("low_freq_factor", 1.),
+                high_freq_factor: f32("high_freq_factor", 4.),
+            }),
+            _ => panic!("unsupported rope_scaling type: {ty}"),
+        }
+    }
+}
+
+/// 归一化层的类型。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Norm {
+    /// `x / rms(x) * w`。
+    #[default]
+    RmsNorm,
+    /// gemma 的 rmsnorm：`x / rms(x) * (1 + w)`。
+    GemmaRmsNorm,
+    /// `(x - mean(x)) / std(x) * w + b`。
+    LayerNorm,
+}
+
+/// 前馈网络的激活函数。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Activation {
+    /// 门控的 silu（SwiGLU）：`w2 * (silu(w1 * x) * (w3 * x))`。
+    #[default]
+    SiLU,
+    /// 门控的 gelu：`w2 * (gelu(w1 * x) * (w3 * x))`。
+    GeGLU,
+    /// 没有门控的 gelu：`w2 * gelu(w1 * x)`，没有 w3。
+    Gelu,
+}
+
+impl Activation {
+    /// 是否有 w3。
+    #[inline]
+    pub fn gated(self) -> bool {
+        self != Self::Gelu
+    }
+}
+
+/// 模型结构中与 llama 不同的部分。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Architecture {
+    pub norm: Norm,
+    pub activation: Activation,
+    /// 每个头中应用 rope 的维度比例。
+    pub partial_rotary_factor: f32,
+    /// 注意力和前馈网络并行：两者的输入是同一个归一化的结果，输出一起加到残差上，每层只有一个归一化。
+    pub parallel: bool,
+    /// 词嵌入的缩放系数。
+    pub embedding_scale: f32,
+}
+
+impl Default for Architecture {
+    #[inline]
+    fn default() -> Self {
+        Self::LLAMA
+    }
+}
+
+impl Architecture {
+    pub const LLAMA: Self = Self {
+        norm: Norm::RmsNorm,
+        activation: Activation::SiLU,
+        partial_rotary_factor: 1.,
+        parallel: false,
+        embedding_scale: 1.,
+    };
+
+    /// 按 huggingface `config.json` 中 `architectures` 的模型类名确定结构，未知的结构视为 llama。
+    pub fn new(name: &str, hidden_size: usize, partial_rotary_factor: Option<f32>) -> Self {
+        match name {
+            "GemmaForCausalLM" => Self {
+                norm: Norm::GemmaRmsNorm,
+                activation: Activation::GeGLU,
+                embedding_scale: (hidden_size as f32).sqrt(),
+                ..Self::LLAMA
+            },
+            "PhiForCausalLM" => Self {
+                norm: Norm::LayerNorm,
+                activation: Activation::Gelu,
+                partial_rotary_factor: partial_rotary_factor.unwrap_or(0.5),
+                parallel: true,
+                ..Self::LLAMA
+            },
+            _ => Self {
+                partial_rotary_factor: partial_rotary_factor.unwrap_or(1.),
+                ..Self::LLAMA
+            },
+        }
+    }
+
+    /// 每个头中应用 rope 的维度数。
+    #[inline]
+    pub fn rotary_dim(&self, head_size: usize) -> usize {
+        (head_size as f32 * self.partial_rotary_factor) as usize
+    }
+}
 
 pub trait Arguments {
     fn dim(&self) -> usize;
@@ -14,8 +167,46 @@ pub trait Arguments {
     fn n_kv_heads(&self) -> usize;
     fn vocab_size(&self) -> usize;
     fn seq_len(&self) -> usize;
+    fn head_size(&self) -> usize {
+        self.dim() / self.n_heads()
+    }
+    fn q_dim(&self) -> usize {
+        self.head_size() * self.n_heads()
+    }
     fn kv_dim(&self) -> usize {
-        self.dim() * self.n_kv_heads() / self.n_heads()
+        self.head_size() * self.n_kv_heads()
+    }
+    /// 归一化层、激活函数等与 llama 不同的结构。
+    fn architecture(&self) -> Architecture {
+        Architecture::LLAMA
+    }
+    /// rmsnorm 或 layernorm 的 epsilon。
+    fn rms_norm_eps(&self) -> f32 {
+        1e-5
+    }
+    /// 输出层是否与词嵌入共享权重，此时 [`Arguments::wcls`] 返回词嵌入表。
+    fn tie_word_embeddings(&self) -> bool {
+        false
+    }
+    /// rope 的 base。
+    fn rope_theta(&self) -> f32 {
+        1e4
+    }
+    /// rope 的位置缩放，默认不缩放。
+    fn rope_scaling(&self) -> Option<RopeScaling> {
+        None
+    }
+    /// 滑动窗口注意力的窗口大小，每个位置只关注包括自己在内的前 `W` 个位置，默认不限制。
+    fn sliding_window(&self) -> Option<usize> {
+        None
+    }
+    /// 每层前馈网络的专家数，稠密模型为 0。
+    fn n_experts(&self) -> usize {
+        0
+    }
+    /// 每个 token 激活的专家数。
+    fn n_experts_per_tok(&self) -> usize {
+        0
     }
 
     /// `vocab_size * dim`.
@@ -24,23 +215,83 @@ pub trait Arguments {
     fn rms_att_weight(&self, layer: usize) -> &[f32];
     /// `dim`.
     fn rms_ffn_weight(&self, layer: usize) -> &[f32];
-    /// `dim * dim`.
+    /// `q_dim * dim`.
     fn wq(&self, layer: usize) -> &[f32];
     /// `kv_dim * dim`.
     fn wk(&self, layer: usize) -> &[f32];
     /// `kv_dim * dim`.
     fn wv(&self, layer: usize) -> &[f32];
-    /// `dim * dim`.
+    /// `dim * q_dim`.
     fn wo(&self, layer: usize) -> &[f32];
     /// `dim * hidden_dim`.
     fn w1(&self, layer: usize) -> &[f32];
     /// `hidden_dim * dim`.
     fn w2(&self, layer: usize) -> &[f32];
-    /// `dim * hidden_dim`.
+    /// `dim * hidden_dim`，没有门控的激活函数不使用 w3。
     fn w3(&self, layer: usize) -> &[f32];
+    /// `n_experts * dim`，MoE 的路由门控。
+    fn moe_gate(&self, _layer: usize) -> &[f32] {
+        &[]
+    }
+    /// `dim * hidden_dim`，第 `expert` 个专家的 w1，稠密模型视为只有一个专家，下同。
+    fn expert_w1(&self, layer: usize, _expert: usize) -> &[f32] {
+        self.w1(layer)
+    }
+    /// `hidden_dim * dim`.
+    fn expert_w2(&self, layer: usize, _expert: usize) -> &[f32] {
+        self.w2(layer)
+    }
+    /// `dim * hidden_dim`.
+    fn expert_w3(&self, layer: usize, _expert: usize) -> &[f32] {
+        self.w3(layer)
+    }
+    /// `dim`，layernorm 的偏置，没有偏置时为 `None`，下同。
+    fn att_norm_bias(&self, _layer: usize) -> Option<&[f32]> {
+        None
+    }
+    /// `dim`.
+    fn ffn_norm_bias(&self, _layer: usize) -> Option<&[f32]> {
+        None
+    }
+    /// `q_dim`.
+    fn bq(&self, _layer: usize) -> Option<&[f32]> {
+        None
+    }
+    /// `kv_dim`.
+    fn bk(&self, _layer: usize) -> Option<&[f32]> {
+        None
+    }
+    /// `kv_dim`.
+    fn bv(&self, _layer: usize) -> Option<&[f32]> {
+        None
+    }
+    /// `dim`.
+    fn bo(&self, _layer: usize) -> Option<&[f32]> {
+        None
+    }
+    /// `hidden_dim`.
+    fn b1(&self, _layer: usize) -> Option<&[f32]> {
+        None
+    }
+    /// `dim`.
+    fn b2(&self, _layer: usize) -> Option<&[f32]> {
+        None
+    }
+    /// `hidden_dim`.
+    fn b3(&self, _layer: usize) -> Option<&[f32]> {
+        None
+    }
     /// `dim`.
     fn rms_final_weight(&self) -> &[f32];
+    /// `dim`.
+    fn final_norm_bias(&self) -> Option<&[f32]> {
+        None
+    }
     fn wcls(&self) -> &[f32];
+    /// `vocab_size`.
+    fn bcls(&self) -> Option<&[f32]> {
+        None
+    }
 }
 
 #[test]
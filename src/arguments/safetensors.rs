@@ -1,5 +1,8 @@
-﻿use super::Arguments;
-use crate::{kernel::slice, tokenizer::utok};
+﻿use super::{lora::read_pairs, Architecture, Arguments, Norm, RopeScaling};
+use crate::{
+    kernel::{gemm, slice},
+    tokenizer::utok,
+};
 use half::{bf16, f16};
 use memmap2::Mmap;
 use safetensors::{tensor::TensorInfo, Dtype};
@@ -8,7 +11,6 @@ use std::{
     collections::{BTreeMap, HashMap},
     fs::File,
     io::{Read, Write},
-    iter::zip,
 };
 
 pub struct SafeTensors {
@@ -23,8 +25,22 @@ pub struct SafeTensors {
     w1: Vec<f32>,
     w2: Vec<f32>,
     w3: Vec<f32>,
+    /// MoE 的路由门控，稠密模型为空。
+    moe_gate: Vec<f32>,
+    /// 偏置，模型没有时为空。
+    att_norm_bias: Vec<f32>,
+    ffn_norm_bias: Vec<f32>,
+    bq: Vec<f32>,
+    bk: Vec<f32>,
+    bv: Vec<f32>,
+    bo: Vec<f32>,
+    b1: Vec<f32>,
+    b2: Vec<f32>,
+    b3: Vec<f32>,
     rms_final_weight: Vec<f32>,
+    final_norm_bias: Vec<f32>,
     wcls: Vec<f32>,
+    bcls: Vec<f32>,
 }
 
 impl SafeTensors {
@@ -42,21 +58,36 @@ impl SafeTensors {
         let vocab_size = config.vocab_size;
         let n_layers = config.num_hidden_layers;
         let dim = config.hidden_size;
-        let kv_dim = dim * config.num_key_value_heads / config.num_attention_heads;
+        let head_size = config.head_size();
+        let q_dim = config.num_attention_heads * head_size;
+        let kv_dim = config.n_kv_heads() * head_size;
         let hidden_dim = config.intermediate_size;
+        let architecture = config.architecture();
+        let rotary_dim = architecture.rotary_dim(head_size);
+        // MoE 模型每层有 `n_experts` 组前馈网络权重，按层、专家的顺序存放
+        let n_experts = config.num_local_experts;
+        let n_ffn = n_layers * n_experts.max(1);
 
         let mut token_embedding_table = vec![0.; vocab_size * dim];
         let mut rms_att_weight = vec![0.; n_layers * dim];
         let mut rms_ffn_weight = vec![0.; n_layers * dim];
-        let mut wq = vec![0.; n_layers * dim * dim];
+        let mut wq = vec![0.; n_layers * q_dim * dim];
         let mut wk = vec![0.; n_layers * kv_dim * dim];
         let mut wv = vec![0.; n_layers * kv_dim * dim];
-        let mut wo = vec![0.; n_layers * dim * dim];
-        let mut w1 = vec![0.; n_layers * dim * hidden_dim];
-        let mut w2 = vec![0.; n_layers * hidden_dim * dim];
-        let mut w3 = vec![0.; n_layers * dim * hidden_dim];
+        let mut wo = vec![0.; n_layers * dim * q_dim];
+        let mut w1 = vec![0.; n_ffn * dim * hidden_dim];
+        let mut w2 = vec![0.; n_ffn * hidden_dim * dim];
+        let mut w3 = if architecture.activation.gated() {
+            vec![0.; n_ffn * dim * hidden_dim]
+        } else {
+            Vec::new()
+        };
+        let mut moe_gate = vec![0.; n_layers * n_experts * dim];
         let mut rms_final_weight = vec![0.; dim];
-        let mut wcls = vec![0.; vocab_size * dim];
+        let [mut bq, mut bk, mut bv, mut bo, mut b1, mut b2, mut b3] = Default::default();
+        let [mut att_norm_bias, mut ffn_norm_bias, mut final_norm_bias, mut bcls] =
+            Default::default();
+        let mut wcls = None;
 
         for (name, tensor) in meta_json.tensors {
             let path = name.split('.').collect::<Vec<_>>();
@@ -72,24 +103,15 @@ impl SafeTensors {
 
                     let copy_slice =
                         |dst: &mut [f32]| slice!(dst; data.len(); [layer]).copy_from_slice(&data);
-                    let perm_copy = |dst: &mut [f32]| {
-                        let dst = &mut slice!(dst; data.len(); [layer]);
-                        let (head, part) = if tensor.shape[0] == tensor.shape[1] {
-                            let n_head = config.num_attention_heads;
-                            (n_head, dim / n_head / 2)
-                        } else {
-                            let n_kv_head = config.num_key_value_heads;
-                            (n_kv_head, kv_dim / n_kv_head / 2)
-                        };
-                        for i in 0..head {
-                            let t = i * part * 2;
-                            for j in 0..part {
-                                slice!(dst; dim; [t + 2 * j    ])
-                                    .copy_from_slice(&slice!(data; dim; [t        + j]));
-                                slice!(dst; dim; [t + 2 * j + 1])
-                                    .copy_from_slice(&slice!(data; dim; [t + part + j]));
-                            }
-                        }
+                    let copy_expert = |dst: &mut [f32], expert: &str| {
+                        let expert = expert.parse::<usize>().unwrap();
+                        assert!(expert < n_experts);
+                        slice!(dst; data.len(); [layer * n_experts + expert])
+                            .copy_from_slice(&data);
+                    };
+                    let perm_copy = |dst: &mut [f32], src: &[f32]| {
+                        let dst = &mut slice!(dst; src.len(); [layer]);
+                        permute(dst, src, dim, head_size, rotary_dim);
                     };
 
                     match path {
@@ -98,30 +120,39 @@ impl SafeTensors {
                             copy_slice(&mut rms_att_weight);
                         }
                         ["self_attn", "q_proj"] => {
-                            assert_eq!(&tensor.shape, &[dim, dim]);
-                            perm_copy(&mut wq);
+                            assert_eq!(&tensor.shape, &[q_dim, dim]);
+                            perm_copy(&mut wq, &data);
                         }
                         ["self_attn", "k_proj"] => {
                             assert_eq!(&tensor.shape, &[kv_dim, dim]);
-                            perm_copy(&mut wk);
+                            perm_copy(&mut wk, &data);
                         }
                         ["self_attn", "v_proj"] => {
                             assert_eq!(&tensor.shape, &[kv_dim, dim]);
                             copy_slice(&mut wv);
                         }
-                        ["self_attn", "o_proj"] => {
-                            assert_eq!(&tensor.shape, &[dim, dim]);
+                        // phi3 把 q、k、v 合并成一个矩阵
+                        ["self_attn", "qkv_proj"] => {
+                            assert_eq!(&tensor.shape, &[q_dim + 2 * kv_dim, dim]);
+                            let (q, kv) = data.split_at(q_dim * dim);
+                            let (k, v) = kv.split_at(kv_dim * dim);
+                            perm_copy(&mut wq, q);
+                            perm_copy(&mut wk, k);
+                            slice!(wv; v.len(); [layer]).copy_from_slice(v);
+                        }
+                        ["self_attn", "o_proj" | "dense"] => {
+                            assert_eq!(&tensor.shape, &[dim, q_dim]);
                             copy_slice(&mut wo);
                         }
                         ["post_attention_layernorm"] => {
                             assert_eq!(&tensor.shape, &[dim]);
                             copy_slice(&mut rms_ffn_weight);
                         }
-                        ["mlp", "gate_proj"] => {
+                        ["mlp", "gate_proj" | "fc1"] => {
                             assert_eq!(&tensor.shape, &[hidden_dim, dim]);
                             copy_slice(&mut w1);
                         }
-                        ["mlp", "down_proj"] => {
+                        ["mlp", "down_proj" | "fc2"] => {
                             assert_eq!(&tensor.shape, &[dim, hidden_dim]);
                             copy_slice(&mut w2);
                         }
@@ -129,20 +160,112 @@ impl SafeTensors {
                             assert_eq!(&tensor.shape, &[hidden_dim, dim]);
                             copy_slice(&mut w3);
                         }
+                        // phi3 把 gate 和 up 合并成一个矩阵
+                        ["mlp", "gate_up_proj"] => {
+                            assert_eq!(&tensor.shape, &[2 * hidden_dim, dim]);
+                            let (gate, up) = data.split_at(hidden_dim * dim);
+                            slice!(w1; gate.len(); [layer]).copy_from_slice(gate);
+                            slice!(w3; up.len(); [layer]).copy_from_slice(up);
+                        }
+                        ["block_sparse_moe", "gate"] => {
+                            assert_eq!(&tensor.shape, &[n_experts, dim]);
+                            copy_slice(&mut moe_gate);
+                        }
+                        ["block_sparse_moe", "experts", e, "w1"] => {
+                            assert_eq!(&tensor.shape, &[hidden_dim, dim]);
+                            copy_expert(&mut w1, e);
+                        }
+                        ["block_sparse_moe", "experts", e, "w2"] => {
+                            assert_eq!(&tensor.shape, &[dim, hidden_dim]);
+                            copy_expert(&mut w2, e);
+                        }
+                        ["block_sparse_moe", "experts", e, "w3"] => {
+                            assert_eq!(&tensor.shape, &[hidden_dim, dim]);
+                            copy_expert(&mut w3, e);
+                        }
                         [..] => {}
                     };
                 }
-                ["model", "norm", "weight"] => {
+                ["model", "layers", n, path @ .., "bias"] => {
+                    let layer = n.parse::<usize>().unwrap();
+                    let len = data.len();
+                    // 偏置在第一次出现时分配，q 和 k 的偏置与权重一样重排
+                    let copy = |dst: &mut Vec<f32>, perm: bool| {
+                        if dst.is_empty() {
+                            *dst = vec![0.; n_layers * len];
+                        }
+                        let dst = &mut slice!(dst; len; [layer]);
+                        if perm {
+                            permute(dst, &data, 1, head_size, rotary_dim);
+                        } else {
+                            dst.copy_from_slice(&data);
+                        }
+                    };
+                    match path {
+                        ["input_layernorm"] => {
+                            assert_eq!(&tensor.shape, &[dim]);
+                            copy(&mut att_norm_bias, false);
+                        }
+                        ["post_attention_layernorm"] => {
+                            assert_eq!(&tensor.shape, &[dim]);
+                            copy(&mut ffn_norm_bias, false);
+                        }
+                        ["self_attn", "q_proj"] => {
+                            assert_eq!(&tensor.shape, &[q_dim]);
+                            copy(&mut bq, true);
+                        }
+                        ["self_attn", "k_proj"] => {
+                            assert_eq!(&tensor.shape, &[kv_dim]);
+                            copy(&mut bk, true);
+                        }
+                        ["self_attn", "v_proj"] => {
+                            assert_eq!(&tensor.shape, &[kv_dim]);
+                            copy(&mut bv, false);
+                        }
+                        ["self_attn", "o_proj" | "dense"] => {
+                            assert_eq!(&tensor.shape, &[dim]);
+                            copy(&mut bo, false);
+                        }
+                        ["mlp", "gate_proj" | "fc1"] => {
+                            assert_eq!(&tensor.shape, &[hidden_dim]);
+                            copy(&mut b1, false);
+                        }
+                        ["mlp", "down_proj" | "fc2"] => {
+                            assert_eq!(&tensor.shape, &[dim]);
+                            copy(&mut b2, false);
+                        }
+                        ["mlp", "up_proj"] => {
+                            assert_eq!(&tensor.shape, &[hidden_dim]);
+                            copy(&mut b3, false);
+                        }
+                        [..] => {}
+                    };
+                }
+                ["model", "norm" | "final_layernorm", "weight"] => {
                     assert_eq!(&tensor.shape, &[dim]);
                     rms_final_weight.copy_from_slice(&data);
                 }
+                ["model", "final_layernorm", "bias"] => {
+                    assert_eq!(&tensor.shape, &[dim]);
+                    final_norm_bias = data.into_owned();
+                }
                 ["lm_head", "weight"] => {
                     assert_eq!(&tensor.shape, &[vocab_size, dim]);
-                    wcls.copy_from_slice(&data);
+                    wcls = Some(data.into_owned());
+                }
+                ["lm_head", "bias"] => {
+                    assert_eq!(&tensor.shape, &[vocab_size]);
+                    bcls = data.into_owned();
                 }
                 [..] => {}
             }
         }
+        // 共享词嵌入的模型没有 `lm_head.weight`，`wcls()` 返回词嵌入表
+        let wcls = match wcls {
+            Some(wcls) => wcls,
+            None if config.tie_word_embeddings() => Vec::new(),
+            None => panic!("lm_head.weight is missing and tie_word_embeddings is not set"),
+        };
 
         Self {
             config,
@@ -156,15 +279,44 @@ impl SafeTensors {
             w1,
             w2,
             w3,
+            moe_gate,
+            att_norm_bias,
+            ffn_norm_bias,
+            bq,
+            bk,
+            bv,
+            bo,
+            b1,
+            b2,
+            b3,
             rms_final_weight,
+            final_norm_bias,
             wcls,
+            bcls,
         }
     }
 
-    pub fn cast_f32(mut config: File, safetensors: File, stream: &mut dyn Write) -> String {
+    /// 把模型转换为 `dtype`（`F32`、`F16` 或 `BF16`）的 safetensors 写入 `stream`，返回新的 `config.json`。
+    ///
+    /// `adapters` 是 `(adapter_config.json, adapter_model.safetensors, scale)`，
+    /// 每个 LoRA 适配器按 `scale` 倍合并到权重中：`W += scale * B * A`。
+    pub fn cast(
+        mut config: File,
+        safetensors: File,
+        adapters: Vec<(File, File, f32)>,
+        dtype: Dtype,
+        stream: &mut dyn Write,
+    ) -> String {
+        // 只修改 `torch_dtype`，其他字段原样保留
         let mut config_string = String::new();
         config.read_to_string(&mut config_string).unwrap();
-        let config = serde_json::from_str::<LLamaConfig>(&config_string).unwrap();
+        let mut config = serde_json::from_str::<serde_json::Value>(&config_string).unwrap();
+        let torch_dtype = match dtype {
+            Dtype::F32 => "float32",
+            Dtype::F16 => "float16",
+            Dtype::BF16 => "bfloat16",
+            _ => panic!("unsupported dtype {dtype:?}"),
+        };
 
         let mmap = unsafe { Mmap::map(&safetensors) }.unwrap();
         let (len, tail) = mmap.split_at(std::mem::size_of::<u64>());
@@ -172,6 +324,16 @@ impl SafeTensors {
         let (meta_json, data) = tail.split_at(len);
         let MetaJson { tensors, meta } = serde_json::from_slice::<MetaJson>(meta_json).unwrap();
 
+        // 每个权重要合并的低秩矩阵
+        let mut deltas = HashMap::<String, Vec<(usize, Vec<f32>, Vec<f32>, f32)>>::new();
+        for (config, safetensors, scale) in adapters {
+            for (name, (r, a, b)) in read_pairs(config, safetensors) {
+                assert!(tensors.contains_key(&name), "lora target {name} is missing");
+                deltas.entry(name).or_default().push((r, a, b, scale));
+            }
+        }
+
+        let is_float = |dtype| matches!(dtype, Dtype::F32 | Dtype::F16 | Dtype::BF16);
         let mut out_meta = MetaJson {
             tensors: Default::default(),
             meta,
@@ -181,15 +343,13 @@ impl SafeTensors {
         let order = tensors.keys().collect::<Vec<_>>();
         for &name in &order {
             let tensor = &tensors[name];
-            let dtype = match tensor.dtype {
-                Dtype::F16 | Dtype::BF16 => {
-                    range.end += (tensor.data_offsets.1 - tensor.data_offsets.0) * 2;
-                    Dtype::F32
-                }
-                others => {
-                    range.end += tensor.data_offsets.1 - tensor.data_offsets.0;
-                    others
-                }
+            let len = tensor.data_offsets.1 - tensor.data_offsets.0;
+            let dtype = if is_float(tensor.dtype) {
+                range.end += len / tensor.dtype.size() * dtype.size();
+                dtype
+            } else {
+                range.end += len;
+                tensor.dtype
             };
             out_meta.tensors.insert(
                 name.clone(),
@@ -215,65 +375,100 @@ impl SafeTensors {
         }
         for name in order {
             let tensor = &tensors[name];
-            let data = &data[tensor.data_offsets.0..tensor.data_offsets.1];
-            match tensor.dtype {
-                Dtype::F16 => {
-                    let src = reslice::<f16>(data);
-                    let mut buf = vec![0u8; src.len() * std::mem::size_of::<f32>()];
-                    let dst = reslice_mut::<f32>(&mut buf);
-                    for (dst, src) in zip(dst, src) {
-                        *dst = src.to_f32();
-                    }
-                    print!("writeing {:>10} bytes... ", buf.len());
-                    stream.write_all(&buf).unwrap();
-                }
-                Dtype::BF16 => {
-                    let src = reslice::<bf16>(data);
-                    let mut buf = vec![0u8; src.len() * std::mem::size_of::<f32>()];
-                    let dst = reslice_mut::<f32>(&mut buf);
-                    for (dst, src) in zip(dst, src) {
-                        *dst = src.to_f32();
-                    }
-                    print!("writeing {:>10} bytes... ", buf.len());
-                    stream.write_all(&buf).unwrap();
-                }
-                _ => {
-                    print!("writeing {:>10} bytes... ", data.len());
-                    stream.write_all(data).unwrap();
-                }
+            if !is_float(tensor.dtype) {
+                let data = &data[tensor.data_offsets.0..tensor.data_offsets.1];
+                print!("writeing {:>10} bytes... ", data.len());
+                stream.write_all(data).unwrap();
+                println!("copied: \"{name}\".");
+                continue;
+            }
+            let mut weight = cast_slice(data, tensor);
+            // W += scale * B * A;
+            for (r, lora_a, lora_b, scale) in deltas.get(name).into_iter().flatten() {
+                let w = weight.to_mut();
+                let m = tensor.shape[0];
+                let k = *r;
+                let n = w.len() / m;
+                let alpha = *scale;
+                let beta = 1.;
+                let a = lora_b.as_ptr();
+                let b = lora_a.as_ptr();
+                let c = w.as_mut_ptr();
+                let rsa = k as _;
+                let csa = 1;
+                let rsb = n as _;
+                let csb = 1;
+                let rsc = n as _;
+                let csc = 1;
+                unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+                print!("merged lora... ");
             }
+            let buf = match dtype {
+                Dtype::F16 => weight
+                    .iter()
+                    .flat_map(|x| f16::from_f32(*x).to_le_bytes())
+                    .collect(),
+                Dtype::BF16 => weight
+                    .iter()
+                    .flat_map(|x| bf16::from_f32(*x).to_le_bytes())
+                    .collect(),
+                _ => weight
+                    .iter()
+                    .flat_map(|x| x.to_le_bytes())
+                    .collect::<Vec<_>>(),
+            };
+            print!("writeing {:>10} bytes... ", buf.len());
+            stream.write_all(&buf).unwrap();
             println!("copied: \"{name}\".");
         }
 
-        serde_json::to_string_pretty(&LLamaConfig {
-            torch_dtype: "float32".to_string(),
-            ..config
-        })
-        .unwrap()
+        config["torch_dtype"] = torch_dtype.into();
+        serde_json::to_string_pretty(&config).unwrap()
     }
 }
 
-#[inline]
-fn reslice<T>(slice: &[u8]) -> &[T] {
-    unsafe {
-        std::slice::from_raw_parts(
-            slice.as_ptr().cast(),
-            slice.len() / std::mem::size_of::<T>(),
-        )
+/// 把 huggingface 的 q、k 每个头的前一半和后一半配对旋转，重排为相邻的两行配对旋转，与推理时的 rope 一致。
+///
+/// 每行 `width` 个元素，每个头 `head_size` 行，只有前 `rotary_dim` 行参与旋转。
+pub(super) fn permute(
+    dst: &mut [f32],
+    src: &[f32],
+    width: usize,
+    head_size: usize,
+    rotary_dim: usize,
+) {
+    let half = rotary_dim / 2;
+    for t in (0..src.len() / width).step_by(head_size) {
+        for j in 0..half {
+            slice!(dst; width; [t + 2 * j    ])
+                .copy_from_slice(&slice!(src; width; [t        + j]));
+            slice!(dst; width; [t + 2 * j + 1])
+                .copy_from_slice(&slice!(src; width; [t + half + j]));
+        }
+        for j in rotary_dim..head_size {
+            slice!(dst; width; [t + j]).copy_from_slice(&slice!(src; width; [t + j]));
+        }
     }
 }
 
+/// 第 `layer` 层的偏置，`data` 为空时模型没有这个偏置。
 #[inline]
-fn reslice_mut<T>(slice: &mut [u8]) -> &mut [T] {
+fn bias(data: &[f32], n_layers: usize, layer: usize) -> Option<&[f32]> {
+    let len = data.len() / n_layers;
+    (!data.is_empty()).then(|| &slice!(data; len; [layer]))
+}
+
+#[inline]
+fn reslice<T>(slice: &[u8]) -> &[T] {
     unsafe {
-        std::slice::from_raw_parts_mut(
-            slice.as_mut_ptr().cast(),
+        std::slice::from_raw_parts(
+            slice.as_ptr().cast(),
             slice.len() / std::mem::size_of::<T>(),
         )
     }
 }
 
-fn cast_slice<'a>(data: &'a [u8], tensor: &TensorInfo) -> Cow<'a, [f32]> {
+pub(super) fn cast_slice<'a>(data: &'a [u8], tensor: &TensorInfo) -> Cow<'a, [f32]> {
     let slice = &data[tensor.data_offsets.0..tensor.data_offsets.1];
     match tensor.dtype {
         Dtype::F32 => Cow::Borrowed(reslice(slice)),
@@ -311,7 +506,7 @@ impl Arguments for SafeTensors {
     }
 
     fn n_kv_heads(&self) -> usize {
-        self.config.num_key_value_heads
+        self.config.n_kv_heads()
     }
 
     fn vocab_size(&self) -> usize {
@@ -322,6 +517,25 @@ impl Arguments for SafeTensors {
         self.config.max_position_embeddings
     }
 
+    fn head_size(&self) -> usize {
+        self.config.head_size()
+    }
+
+    fn architecture(&self) -> Architecture {
+        self.config.architecture()
+    }
+
+    fn rope_theta(&self) -> f32 {
+        self.config.rope_theta
+    }
+
+    fn rope_scaling(&self) -> Option<RopeScaling> {
+        self.config
+            .rope_scaling
+            .as_ref()
+            .and_then(|value| RopeScaling::from_config(value, self.seq_len()))
+    }
+
     fn token_embedding_table(&self, token: utok) -> &[f32] {
         &slice!(self.token_embedding_table; self.dim(); [token as usize])
     }
@@ -335,7 +549,7 @@ impl Arguments for SafeTensors {
     }
 
     fn wq(&self, layer: usize) -> &[f32] {
-        &slice!(self.wq; self.dim() * self.dim(); [layer])
+        &slice!(self.wq; self.q_dim() * self.dim(); [layer])
     }
 
     fn wk(&self, layer: usize) -> &[f32] {
@@ -347,50 +561,242 @@ impl Arguments for SafeTensors {
     }
 
     fn wo(&self, layer: usize) -> &[f32] {
-        &slice!(self.wo; self.dim() * self.dim(); [layer])
+        &slice!(self.wo; self.dim() * self.q_dim(); [layer])
     }
 
     fn w1(&self, layer: usize) -> &[f32] {
-        &slice!(self.w1; self.dim() * self.hidden_dim(); [layer])
+        self.expert_w1(layer, 0)
     }
 
     fn w2(&self, layer: usize) -> &[f32] {
-        &slice!(self.w2; self.hidden_dim() * self.dim(); [layer])
+        self.expert_w2(layer, 0)
     }
 
     fn w3(&self, layer: usize) -> &[f32] {
-        &slice!(self.w3; self.dim() * self.hidden_dim(); [layer])
+        self.expert_w3(layer, 0)
+    }
+
+    fn sliding_window(&self) -> Option<usize> {
+        match self.config.use_sliding_window {
+            Some(false) => None,
+            _ => self.config.sliding_window,
+        }
+    }
+
+    fn n_experts(&self) -> usize {
+        self.config.num_local_experts
+    }
+
+    fn n_experts_per_tok(&self) -> usize {
+        self.config.num_experts_per_tok
+    }
+
+    fn moe_gate(&self, layer: usize) -> &[f32] {
+        &slice!(self.moe_gate; self.n_experts() * self.dim(); [layer])
+    }
+
+    fn expert_w1(&self, layer: usize, expert: usize) -> &[f32] {
+        let i = layer * self.n_experts().max(1) + expert;
+        &slice!(self.w1; self.dim() * self.hidden_dim(); [i])
+    }
+
+    fn expert_w2(&self, layer: usize, expert: usize) -> &[f32] {
+        let i = layer * self.n_experts().max(1) + expert;
+        &slice!(self.w2; self.hidden_dim() * self.dim(); [i])
+    }
+
+    fn expert_w3(&self, layer: usize, expert: usize) -> &[f32] {
+        let i = layer * self.n_experts().max(1) + expert;
+        &slice!(self.w3; self.dim() * self.hidden_dim(); [i])
+    }
+
+    fn att_norm_bias(&self, layer: usize) -> Option<&[f32]> {
+        bias(&self.att_norm_bias, self.n_layers(), layer)
+    }
+
+    fn ffn_norm_bias(&self, layer: usize) -> Option<&[f32]> {
+        bias(&self.ffn_norm_bias, self.n_layers(), layer)
+    }
+
+    fn bq(&self, layer: usize) -> Option<&[f32]> {
+        bias(&self.bq, self.n_layers(), layer)
+    }
+
+    fn bk(&self, layer: usize) -> Option<&[f32]> {
+        bias(&self.bk, self.n_layers(), layer)
+    }
+
+    fn bv(&self, layer: usize) -> Option<&[f32]> {
+        bias(&self.bv, self.n_layers(), layer)
+    }
+
+    fn bo(&self, layer: usize) -> Option<&[f32]> {
+        bias(&self.bo, self.n_layers(), layer)
+    }
+
+    fn b1(&self, layer: usize) -> Option<&[f32]> {
+        bias(&self.b1, self.n_layers(), layer)
+    }
+
+    fn b2(&self, layer: usize) -> Option<&[f32]> {
+        bias(&self.b2, self.n_layers(), layer)
+    }
+
+    fn b3(&self, layer: usize) -> Option<&[f32]> {
+        bias(&self.b3, self.n_layers(), layer)
     }
 
     fn rms_final_weight(&self) -> &[f32] {
         &self.rms_final_weight
     }
 
+    fn final_norm_bias(&self) -> Option<&[f32]> {
+        bias(&self.final_norm_bias, 1, 0)
+    }
+
+    fn rms_norm_eps(&self) -> f32 {
+        match self.architecture().norm {
+            Norm::LayerNorm => self.config.layer_norm_eps.unwrap_or(1e-5),
+            Norm::RmsNorm | Norm::GemmaRmsNorm => self.config.rms_norm_eps,
+        }
+    }
+
+    fn tie_word_embeddings(&self) -> bool {
+        self.config.tie_word_embeddings()
+    }
+
     fn wcls(&self) -> &[f32] {
-        &self.wcls
+        if self.wcls.is_empty() {
+            &self.token_embedding_table
+        } else {
+            &self.wcls
+        }
+    }
+
+    fn bcls(&self) -> Option<&[f32]> {
+        bias(&self.bcls, 1, 0)
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Deserialize, Debug)]
 struct LLamaConfig {
-    bos_token_id: utok,
-    eos_token_id: utok,
+    /// 模型类名，决定 [`Architecture`]。
+    #[serde(default)]
+    architectures: Vec<String>,
 
     hidden_size: usize,
     intermediate_size: usize,
     max_position_embeddings: usize,
     num_attention_heads: usize,
     num_hidden_layers: usize,
-    num_key_value_heads: usize,
+    /// phi 的配置中为 `null`，与 `num_attention_heads` 相同。
+    #[serde(default)]
+    num_key_value_heads: Option<usize>,
+    /// gemma 的头大小不等于 `hidden_size / num_attention_heads`。
+    #[serde(default)]
+    head_dim: Option<usize>,
+    #[serde(default)]
+    partial_rotary_factor: Option<f32>,
     vocab_size: usize,
+    #[serde(default = "default_rms_norm_eps")]
+    rms_norm_eps: f32,
+    #[serde(default)]
+    layer_norm_eps: Option<f32>,
+    #[serde(default)]
+    tie_word_embeddings: Option<bool>,
+    #[serde(default = "default_rope_theta")]
+    rope_theta: f32,
+    #[serde(default)]
+    rope_scaling: Option<serde_json::Value>,
+    /// mistral 的滑动窗口，qwen2 另外用 `use_sliding_window` 控制是否启用。
+    #[serde(default)]
+    sliding_window: Option<usize>,
+    #[serde(default)]
+    use_sliding_window: Option<bool>,
+    /// Mixtral 的专家数，稠密模型没有这一项。
+    #[serde(default)]
+    num_local_experts: usize,
+    #[serde(default)]
+    num_experts_per_tok: usize,
+}
 
-    torch_dtype: String,
+impl LLamaConfig {
+    #[inline]
+    fn n_kv_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    #[inline]
+    fn head_size(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    fn architecture(&self) -> Architecture {
+        let name = self.architectures.first().map_or("", String::as_str);
+        Architecture::new(name, self.hidden_size, self.partial_rotary_factor)
+    }
+
+    /// gemma 的配置没有这一项，总是共享词嵌入。
+    fn tie_word_embeddings(&self) -> bool {
+        self.tie_word_embeddings.unwrap_or_else(|| {
+            self.architectures.first().map(String::as_str) == Some("GemmaForCausalLM")
+        })
+    }
+}
+
+#[inline]
+fn default_rms_norm_eps() -> f32 {
+    1e-6
+}
+
+#[inline]
+fn default_rope_theta() -> f32 {
+    1e4
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
-struct MetaJson {
+pub(super) struct MetaJson {
     #[serde(flatten)]
-    tensors: BTreeMap<String, TensorInfo>,
+    pub tensors: BTreeMap<String, TensorInfo>,
     #[serde(rename = "__metadata__")]
-    meta: HashMap<String, serde_json::Value>,
+    pub meta: HashMap<String, serde_json::Value>,
+}
+
+#[test]
+fn test_cast() {
+    use super::TinyModel;
+    use crate::Transformer;
+
+    // 没有对应字段的配置原样保留
+    let model = TinyModel::new(
+        serde_json::json!({ "model_type": "llama", "quantization": { "bits": 4 } }),
+        0xca57,
+    );
+    let dir = model.save("cast");
+    let open = |name: &str| File::open(dir.join(name)).unwrap();
+    let mut out = Vec::new();
+    let config = SafeTensors::cast(
+        open("config.json"),
+        open("model.safetensors"),
+        Vec::new(),
+        Dtype::F16,
+        &mut out,
+    );
+    let config = serde_json::from_str::<serde_json::Value>(&config).unwrap();
+    let mut expected = model.config.clone();
+    expected["torch_dtype"] = "float16".into();
+    assert_eq!(config, expected);
+
+    // 转换后的模型与原模型的输出接近
+    std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+    std::fs::write(dir.join("model.safetensors"), out).unwrap();
+    let tokens = [1, 7, 3, 12, 5];
+    let actual = Transformer::read_checkpoint(dir.join("model.safetensors"))
+        .forward_all(&tokens, 0, &mut ())
+        .unwrap();
+    let expected = model.forward(&tokens);
+    for (a, b) in std::iter::zip(actual, expected) {
+        assert!((a - b).abs() < 1e-2, "{a} != {b}");
+    }
 }
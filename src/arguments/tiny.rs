@@ -0,0 +1,382 @@
+﻿use crate::{tokenizer::utok, Transformer};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    f32::consts::PI,
+    iter::zip,
+    path::{Path, PathBuf},
+};
+
+/// 测试用的随机权重的 huggingface 格式小模型。
+///
+/// 权重按 huggingface 的布局保存在 `tensors` 中，测试可以在写出之前修改，再用 [`Transformer::read_checkpoint`] 加载。
+pub(crate) struct TinyModel {
+    pub config: Value,
+    pub tensors: BTreeMap<String, (Vec<usize>, Vec<f32>)>,
+}
+
+impl TinyModel {
+    /// 按 `config` 生成随机权重，`config` 中的字段覆盖默认的小尺寸 llama 配置。
+    pub fn new(config: Value, seed: u32) -> Self {
+        let mut merged = json!({
+            "architectures": ["LlamaForCausalLM"],
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+            "hidden_size": 16,
+            "intermediate_size": 32,
+            "max_position_embeddings": 64,
+            "num_attention_heads": 4,
+            "num_hidden_layers": 2,
+            "num_key_value_heads": 2,
+            "vocab_size": 32,
+            "rms_norm_eps": 1e-5,
+            "torch_dtype": "float32",
+        });
+        for (k, v) in config.as_object().unwrap() {
+            merged[k] = v.clone();
+        }
+        let config = merged;
+        let usize = |key: &str| config[key].as_u64().unwrap() as usize;
+        let dim = usize("hidden_size");
+        let hidden_dim = usize("intermediate_size");
+        let n_heads = usize("num_attention_heads");
+        let n_kv_heads = usize("num_key_value_heads");
+        let head_size = config["head_dim"]
+            .as_u64()
+            .map_or(dim / n_heads, |x| x as usize);
+        let (q_dim, kv_dim) = (n_heads * head_size, n_kv_heads * head_size);
+        let vocab_size = usize("vocab_size");
+        let n_layers = usize("num_hidden_layers");
+        let n_experts = config["num_local_experts"].as_u64().unwrap_or(0) as usize;
+        let architecture = config["architectures"][0].as_str().unwrap().to_string();
+        let (gemma, phi, phi3) = (
+            architecture == "GemmaForCausalLM",
+            architecture == "PhiForCausalLM",
+            architecture == "Phi3ForCausalLM",
+        );
+        let tie = config["tie_word_embeddings"].as_bool().unwrap_or(gemma);
+
+        assert_ne!(seed, 0);
+        let mut rng = Rng(seed);
+        let mut tensors = BTreeMap::new();
+        let mut add = |name: String, shape: Vec<usize>| {
+            let data = match *shape.as_slice() {
+                _ if name.ends_with(".bias") => {
+                    (0..shape[0]).map(|_| 0.1 * rng.uniform()).collect()
+                }
+                // 均匀分布的方差为 1 / cols，矩阵乘的输出与输入的尺度相同
+                [rows, cols] => {
+                    let a = (3. / cols as f32).sqrt();
+                    (0..rows * cols).map(|_| rng.uniform() * a).collect()
+                }
+                // 归一化的权重在 1 附近，gemma 的归一化乘 `1 + w`
+                [len] => (0..len)
+                    .map(|_| if gemma { 0. } else { 1. } + 0.2 * rng.uniform())
+                    .collect(),
+                _ => unreachable!(),
+            };
+            tensors.insert(name, (shape, data));
+        };
+        add("model.embed_tokens.weight".into(), vec![vocab_size, dim]);
+        // phi 的每个线性层和 layernorm 都有偏置
+        let mut linear = |name: String, shape: Vec<usize>| {
+            if phi {
+                add(format!("{name}.bias"), vec![shape[0]]);
+            }
+            add(format!("{name}.weight"), shape);
+        };
+        for l in 0..n_layers {
+            let name = |s: &str| format!("model.layers.{l}.{s}");
+            linear(name("input_layernorm"), vec![dim]);
+            if phi3 {
+                // phi3 合并 q、k、v 和 gate、up
+                linear(name("self_attn.qkv_proj"), vec![q_dim + 2 * kv_dim, dim]);
+            } else {
+                linear(name("self_attn.q_proj"), vec![q_dim, dim]);
+                linear(name("self_attn.k_proj"), vec![kv_dim, dim]);
+                linear(name("self_attn.v_proj"), vec![kv_dim, dim]);
+            }
+            if phi {
+                // 注意力和前馈网络并行，共用 `input_layernorm`
+                linear(name("self_attn.dense"), vec![dim, q_dim]);
+                linear(name("mlp.fc1"), vec![hidden_dim, dim]);
+                linear(name("mlp.fc2"), vec![dim, hidden_dim]);
+                continue;
+            }
+            linear(name("self_attn.o_proj"), vec![dim, q_dim]);
+            linear(name("post_attention_layernorm"), vec![dim]);
+            if phi3 {
+                linear(name("mlp.gate_up_proj"), vec![2 * hidden_dim, dim]);
+                linear(name("mlp.down_proj"), vec![dim, hidden_dim]);
+            } else if n_experts == 0 {
+                linear(name("mlp.gate_proj"), vec![hidden_dim, dim]);
+                linear(name("mlp.up_proj"), vec![hidden_dim, dim]);
+                linear(name("mlp.down_proj"), vec![dim, hidden_dim]);
+            } else {
+                linear(name("block_sparse_moe.gate"), vec![n_experts, dim]);
+                for e in 0..n_experts {
+                    let name = |w: &str| name(&format!("block_sparse_moe.experts.{e}.{w}"));
+                    linear(name("w1"), vec![hidden_dim, dim]);
+                    linear(name("w2"), vec![dim, hidden_dim]);
+                    linear(name("w3"), vec![hidden_dim, dim]);
+                }
+            }
+        }
+        if phi {
+            linear("model.final_layernorm".into(), vec![dim]);
+        } else {
+            linear("model.norm".into(), vec![dim]);
+        }
+        if !tie {
+            linear("lm_head".into(), vec![vocab_size, dim]);
+        }
+        Self { config, tensors }
+    }
+
+    /// 按 huggingface 的定义逐个位置朴素地计算 `tokens` 的 logits：`tokens.len() x vocab_size`，用于检查推理的实现。
+    pub fn forward(&self, tokens: &[utok]) -> Vec<f32> {
+        let config = &self.config;
+        let usize = |key: &str| config[key].as_u64().unwrap() as usize;
+        let dim = usize("hidden_size");
+        let n_layers = usize("num_hidden_layers");
+        let n_heads = usize("num_attention_heads");
+        let n_kv_heads = usize("num_key_value_heads");
+        let head_size = config["head_dim"]
+            .as_u64()
+            .map_or(dim / n_heads, |x| x as usize);
+        let architecture = config["architectures"][0].as_str().unwrap();
+        let (gemma, phi) = (
+            architecture == "GemmaForCausalLM",
+            architecture == "PhiForCausalLM",
+        );
+        let f32 = |key: &str, default: f64| config[key].as_f64().unwrap_or(default) as f32;
+        let rotary_dim =
+            (head_size as f32 * f32("partial_rotary_factor", if phi { 0.5 } else { 1. })) as usize;
+        let theta = f32("rope_theta", 1e4);
+        let eps = if phi {
+            f32("layer_norm_eps", 1e-5)
+        } else {
+            f32("rms_norm_eps", 1e-6)
+        };
+        assert!(config["num_local_experts"].is_null());
+
+        let dot = |a: &[f32], b: &[f32]| zip(a, b).map(|(a, b)| a * b).sum::<f32>();
+        // y = W x + b
+        let linear = |name: &str, x: &[f32]| {
+            let w = &self.tensors[&format!("{name}.weight")].1;
+            let mut y = w.chunks(x.len()).map(|row| dot(row, x)).collect::<Vec<_>>();
+            if let Some((_, b)) = self.tensors.get(&format!("{name}.bias")) {
+                zip(&mut y, b).for_each(|(y, b)| *y += b);
+            }
+            y
+        };
+        let norm = |name: &str, x: &[f32]| {
+            let w = &self.tensors[&format!("{name}.weight")].1;
+            let n = x.len() as f32;
+            if phi {
+                let b = &self.tensors[&format!("{name}.bias")].1;
+                let mean = x.iter().sum::<f32>() / n;
+                let var = x.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / n;
+                let std = (var + eps).sqrt();
+                zip(x, zip(w, b))
+                    .map(|(x, (w, b))| (x - mean) / std * w + b)
+                    .collect::<Vec<_>>()
+            } else {
+                let rms = (dot(x, x) / n + eps).sqrt();
+                zip(x, w)
+                    .map(|(x, w)| x / rms * if gemma { 1. + w } else { *w })
+                    .collect()
+            }
+        };
+        // 每个头的前 `rotary_dim` 维中，前一半与后一半配对旋转
+        let rope = |x: &mut [f32], pos: usize| {
+            let half = rotary_dim / 2;
+            for head in x.chunks_mut(head_size) {
+                for i in 0..half {
+                    let freq = theta.powf(-((2 * i) as f32 / rotary_dim as f32));
+                    let (sin, cos) = (pos as f32 * freq).sin_cos();
+                    let (a, b) = (head[i], head[i + half]);
+                    head[i] = a * cos - b * sin;
+                    head[i + half] = b * cos + a * sin;
+                }
+            }
+        };
+        let gelu = |x: f32| 0.5 * x * (1. + ((2. / PI).sqrt() * (x + 0.044715 * x.powi(3))).tanh());
+        let silu = |x: f32| x / (1. + (-x).exp());
+        let ffn = |l: usize, x: &[f32]| {
+            let name = |s: &str| format!("model.layers.{l}.mlp.{s}");
+            if phi {
+                let hidden = linear(&name("fc1"), x)
+                    .into_iter()
+                    .map(gelu)
+                    .collect::<Vec<_>>();
+                return linear(&name("fc2"), &hidden);
+            }
+            let act = if gemma { gelu } else { silu };
+            let (gate, up) = match self
+                .tensors
+                .get(&format!("{}.weight", name("gate_up_proj")))
+            {
+                Some(_) => {
+                    let mut gate = linear(&name("gate_up_proj"), x);
+                    let up = gate.split_off(gate.len() / 2);
+                    (gate, up)
+                }
+                None => (linear(&name("gate_proj"), x), linear(&name("up_proj"), x)),
+            };
+            let hidden = zip(gate, up)
+                .map(|(gate, up)| act(gate) * up)
+                .collect::<Vec<_>>();
+            linear(&name("down_proj"), &hidden)
+        };
+
+        let mut keys = vec![Vec::<Vec<f32>>::new(); n_layers];
+        let mut values = vec![Vec::<Vec<f32>>::new(); n_layers];
+        let mut logits = Vec::new();
+        for (pos, &token) in tokens.iter().enumerate() {
+            let embedding = &self.tensors["model.embed_tokens.weight"].1;
+            let scale = if gemma { (dim as f32).sqrt() } else { 1. };
+            let mut x = embedding[token as usize * dim..][..dim]
+                .iter()
+                .map(|x| x * scale)
+                .collect::<Vec<_>>();
+            for l in 0..n_layers {
+                let name = |s: &str| format!("model.layers.{l}.{s}");
+                let h = norm(&name("input_layernorm"), &x);
+                let (mut q, mut k, v) = match self.tensors.get(&name("self_attn.qkv_proj.weight")) {
+                    Some(_) => {
+                        let mut q = linear(&name("self_attn.qkv_proj"), &h);
+                        let mut k = q.split_off(n_heads * head_size);
+                        let v = k.split_off(n_kv_heads * head_size);
+                        (q, k, v)
+                    }
+                    None => (
+                        linear(&name("self_attn.q_proj"), &h),
+                        linear(&name("self_attn.k_proj"), &h),
+                        linear(&name("self_attn.v_proj"), &h),
+                    ),
+                };
+                rope(&mut q, pos);
+                rope(&mut k, pos);
+                keys[l].push(k);
+                values[l].push(v);
+
+                let mut o = vec![0.; q.len()];
+                for (head, o) in o.chunks_mut(head_size).enumerate() {
+                    let kv = head / (n_heads / n_kv_heads) * head_size..;
+                    let q = &q[head * head_size..][..head_size];
+                    let scores = keys[l]
+                        .iter()
+                        .map(|k| dot(q, &k[kv.clone()][..head_size]) / (head_size as f32).sqrt())
+                        .collect::<Vec<_>>();
+                    let max = scores.iter().copied().fold(f32::MIN, f32::max);
+                    let exp = scores.iter().map(|s| (s - max).exp()).collect::<Vec<_>>();
+                    let sum = exp.iter().sum::<f32>();
+                    for (p, v) in zip(exp, &values[l]) {
+                        zip(&mut *o, &v[kv.clone()]).for_each(|(o, v)| *o += p / sum * v);
+                    }
+                }
+                if phi {
+                    let attention = linear(&name("self_attn.dense"), &o);
+                    let ffn = ffn(l, &h);
+                    zip(&mut x, zip(attention, ffn)).for_each(|(x, (a, f))| *x += a + f);
+                } else {
+                    let attention = linear(&name("self_attn.o_proj"), &o);
+                    zip(&mut x, attention).for_each(|(x, a)| *x += a);
+                    let h = norm(&name("post_attention_layernorm"), &x);
+                    zip(&mut x, ffn(l, &h)).for_each(|(x, f)| *x += f);
+                }
+            }
+            let x = norm(
+                if phi {
+                    "model.final_layernorm"
+                } else {
+                    "model.norm"
+                },
+                &x,
+            );
+            let tie = !self.tensors.contains_key("lm_head.weight");
+            logits.extend(linear(
+                if tie { "model.embed_tokens" } else { "lm_head" },
+                &x,
+            ));
+        }
+        logits
+    }
+
+    /// 把 `config.json` 和 `model.safetensors` 写入临时目录 `name`，目录在返回值析构时删除。
+    pub fn save(&self, name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        let config = serde_json::to_string(&self.config).unwrap();
+        std::fs::write(dir.join("config.json"), config).unwrap();
+        write_safetensors(&dir.join("model.safetensors"), &self.tensors);
+        dir
+    }
+
+    /// 写出并加载模型。
+    pub fn load(&self, name: &str) -> Transformer {
+        Transformer::read_checkpoint(self.save(name).join("model.safetensors"))
+    }
+}
+
+/// 把 fp32 的张量按 safetensors 格式写入 `path`。
+pub(crate) fn write_safetensors(path: &Path, tensors: &BTreeMap<String, (Vec<usize>, Vec<f32>)>) {
+    let mut header = serde_json::Map::new();
+    let mut offset = 0;
+    for (name, (shape, data)) in tensors {
+        let end = offset + data.len() * std::mem::size_of::<f32>();
+        header.insert(
+            name.clone(),
+            json!({ "dtype": "F32", "shape": shape, "data_offsets": [offset, end] }),
+        );
+        offset = end;
+    }
+    header.insert("__metadata__".into(), json!({ "format": "pt" }));
+    let mut header = serde_json::to_vec(&header).unwrap();
+    // 数据按 8 字节对齐，加载时可以直接重解释为 f32
+    header.resize(header.len().next_multiple_of(8), b' ');
+    let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+    bytes.extend_from_slice(&header);
+    for (_, data) in tensors.values() {
+        bytes.extend(data.iter().flat_map(|x| x.to_le_bytes()));
+    }
+    std::fs::write(path, bytes).unwrap();
+}
+
+/// 测试用的临时目录，析构时删除。
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("llama2-rs-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+    #[inline]
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// xorshift 随机数，种子不能为 0。
+pub(crate) struct Rng(pub u32);
+
+impl Rng {
+    /// `[-1, 1)` 上的均匀分布。
+    pub fn uniform(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / (1u64 << 31) as f32 - 1.
+    }
+}
@@ -1,15 +1,81 @@
 ﻿use llama2_rs::SafeTensors;
-use std::{fs::File, io::Write};
+use safetensors::Dtype;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
 
 fn main() {
-    let mut args = std::env::args();
+    struct Args {
+        config: PathBuf,
+        safetensors: PathBuf,
+        out_config: PathBuf,
+        out: PathBuf,
+        dtype: Dtype,
+        /// LoRA 适配器目录和合并的倍数。
+        adapters: Vec<(PathBuf, f32)>,
+    }
 
-    let _ = args.next().unwrap();
-    let config = File::open(args.next().unwrap()).unwrap();
-    let safetensors = File::open(args.next().unwrap()).unwrap();
-    let mut out_config = File::create(args.next().unwrap()).unwrap();
-    let mut out = File::create(args.next().unwrap()).unwrap();
+    let mut process_args = std::env::args();
+    process_args.next().unwrap();
+    let mut next_path = || process_args.next().map(PathBuf::from).expect(USAGE_HELP);
+    let mut args = Args {
+        config: next_path(),
+        safetensors: next_path(),
+        out_config: next_path(),
+        out: next_path(),
+        dtype: Dtype::F32,
+        adapters: Vec::new(),
+    };
+    loop {
+        match process_args.next() {
+            Some(s) if s == "--dtype" => {
+                args.dtype = match process_args.next().expect(USAGE_HELP).as_str() {
+                    "f32" => Dtype::F32,
+                    "f16" => Dtype::F16,
+                    "bf16" => Dtype::BF16,
+                    _ => panic!("{USAGE_HELP}"),
+                };
+            }
+            Some(s) if s == "--lora" => {
+                let dir = process_args.next().map(PathBuf::from).expect(USAGE_HELP);
+                args.adapters.push((dir, 1.));
+            }
+            Some(s) if s == "--lora-scale" => {
+                let scale = process_args.next().expect(USAGE_HELP).parse().unwrap();
+                args.adapters.last_mut().expect(USAGE_HELP).1 = scale;
+            }
+            None => break,
+            _ => panic!("{USAGE_HELP}"),
+        }
+    }
 
-    let config = SafeTensors::cast_f32(config, safetensors, &mut out);
-    out_config.write_all(config.as_bytes()).unwrap();
+    let open = |path: PathBuf| {
+        File::open(&path).unwrap_or_else(|_| panic!("Could not open {}", path.display()))
+    };
+    let adapters = args
+        .adapters
+        .into_iter()
+        .map(|(dir, scale)| {
+            let config = open(dir.join("adapter_config.json"));
+            let tensors = open(dir.join("adapter_model.safetensors"));
+            (config, tensors, scale)
+        })
+        .collect();
+
+    let config = open(args.config);
+    let safetensors = open(args.safetensors);
+    let mut out = BufWriter::new(File::create(args.out).unwrap());
+    let config = SafeTensors::cast(config, safetensors, adapters, args.dtype, &mut out);
+    out.flush().unwrap();
+    std::fs::write(args.out_config, config).unwrap();
 }
+
+const USAGE_HELP: &str = "\
+Usage: cargo run --bin cast <config> <safetensors> <out-config> <out-safetensors> [OPTIONS]
+Options:
+     --dtype <f32|f16|bf16>  输出的数据类型，默认 f32
+     --lora <dir>            合并 PEFT 格式的 LoRA 适配器，可以指定多个
+     --lora-scale <float>    前一个适配器合并的倍数，默认 1
+";
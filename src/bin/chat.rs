@@ -1,9 +1,12 @@
-use core::panic;
-use llama2_rs::{BpeTokenizer, Sampler, Tokenizer, Transformer, BOS, EOS};
+﻿use core::panic;
+use llama2_rs::{
+    read_tokenizer, ChatMessage, ChatTemplate, KvDtype, Sampler, Session, TemplateError, Tokenizer,
+    Transformer,
+};
 use std::{
-    collections::VecDeque,
     fs::canonicalize,
     io::Write,
+    iter::zip,
     path::PathBuf,
     time::{SystemTime, UNIX_EPOCH},
 };
@@ -18,7 +21,11 @@ fn main() {
         temperature: f32,
         top_p: f32,
         system: String,
+        template: Option<String>,
         rng_seed: u64,
+        kv_dtype: KvDtype,
+        seq_len: Option<usize>,
+        lora: Option<PathBuf>,
     }
 
     let mut process_args = std::env::args();
@@ -33,7 +40,11 @@ fn main() {
         temperature: 1.0,
         top_p: 0.9,
         system: String::new(),
+        template: None,
         rng_seed: 0,
+        kv_dtype: KvDtype::F32,
+        seq_len: None,
+        lora: None,
     };
     loop {
         match process_args.next() {
@@ -49,6 +60,18 @@ fn main() {
             Some(s) if s == "--system" => {
                 args.system = process_args.next().expect(USAGE_HELP);
             }
+            Some(s) if s == "--template" => {
+                args.template = process_args.next();
+            }
+            Some(s) if s == "--seq-len" => {
+                args.seq_len = Some(process_args.next().expect(USAGE_HELP).parse().unwrap());
+            }
+            Some(s) if s == "--kv-dtype" => {
+                args.kv_dtype = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
+            Some(s) if s == "--lora" => {
+                args.lora = process_args.next().map(PathBuf::from);
+            }
             Some(s) if s == "--rng-seed" => {
                 args.rng_seed = process_args.next().expect(USAGE_HELP).parse().unwrap();
             }
@@ -77,7 +100,15 @@ fn main() {
     }
 
     let mut transformer: Transformer = Transformer::read_checkpoint(&args.check_point);
-    let tokenizer = BpeTokenizer::new(&args.tokenizer_path, transformer.vocab_size());
+    transformer.set_kv_dtype(args.kv_dtype);
+    if let Some(seq_len) = args.seq_len {
+        transformer.set_seq_len(seq_len);
+    }
+    if let Some(lora) = args.lora {
+        let adapter = transformer.read_adapter(lora);
+        transformer.set_adapter(Some(adapter));
+    }
+    let tokenizer = read_tokenizer(&args.tokenizer_path, transformer.vocab_size());
     let mut sampler = Sampler::new(
         transformer.vocab_size(),
         args.temperature,
@@ -85,7 +116,18 @@ fn main() {
         args.rng_seed,
     );
 
-    chat(&mut transformer, &tokenizer, &mut sampler, args.system);
+    let template = match args.template {
+        None => ChatTemplate::for_model(&args.check_point),
+        Some(name) => ChatTemplate::load(name),
+    };
+
+    chat(
+        &mut transformer,
+        &*tokenizer,
+        &mut sampler,
+        &template,
+        args.system,
+    );
 }
 
 const USAGE_HELP: &str = "\
@@ -95,95 +137,222 @@ Options:
      --temperature <float>
      --top-p <float>
      --system <string>
+     --template <llama2|chatml|zephyr|alpaca|string>
      --rng-seed <int>
+     --kv-dtype <f32|f16|int8>
+     --seq-len <int>
+     --lora <dir>          PEFT 格式的 LoRA 适配器目录
 ";
 
 fn chat(
     transformer: &mut Transformer,
-    tokenizer: &impl Tokenizer,
+    tokenizer: &dyn Tokenizer,
     sampler: &mut Sampler,
+    template: &ChatTemplate,
     system: String,
 ) {
     let mut logger = ();
-    let system = format!(
-        "\
-<|system|>
-{}</s>
-",
-        system.trim()
-    );
-
-    let system_tokens = tokenizer.encode(&system, true, false);
-    transformer.update(&system_tokens, 0, &mut logger);
+    let mut messages = Vec::new();
+    if !system.trim().is_empty() {
+        messages.push(ChatMessage::new("system", system.trim()));
+    }
+    // 系统提示词之后的消息在上下文不足时可以丢弃
+    let mut n_system = messages.len();
+    // 已经存入 kv cache 的 token，每轮只需要计算与新提示词不同的部分
+    let mut history = Vec::new();
+    let stops = template.stops();
+    let max_stop_len = stops.iter().map(String::len).max().unwrap_or(0);
+    let seq_len = transformer.seq_len();
 
-    let mut pos = system_tokens.len();
     loop {
         let mut user = String::new();
         print!("user: ");
         std::io::stdout().flush().unwrap();
-        std::io::stdin().read_line(&mut user).unwrap();
-        let addition = format!(
-            "\
-<|user|>
-{}</s>
-<|assistant|>
-",
-            user.trim()
-        );
-        let addition_tokens = tokenizer.encode(&addition, false, false);
-        let (last, tokens) = addition_tokens.split_last().unwrap();
-        transformer.update(&addition_tokens, pos as _, &mut logger);
-        pos += tokens.len();
+        if std::io::stdin().read_line(&mut user).unwrap() == 0 {
+            return;
+        }
+        let user = user.trim();
+        if let Some(path) = user.strip_prefix("/save ") {
+            let session = Session::new(messages.clone(), history.clone(), sampler);
+            match session.save(transformer, path.trim()) {
+                Ok(()) => println!(
+                    "saved {} messages ({} tokens)",
+                    messages.len(),
+                    history.len()
+                ),
+                Err(e) => println!("error: {e}"),
+            }
+            continue;
+        }
+        if let Some(path) = user.strip_prefix("/load ") {
+            match Session::load(transformer, path.trim()) {
+                Ok(session) => {
+                    messages = session.messages;
+                    history = session.tokens;
+                    sampler.set_rng_state(session.rng_state);
+                    n_system = messages.iter().take_while(|m| m.role == "system").count();
+                    println!(
+                        "loaded {} messages ({} tokens)",
+                        messages.len(),
+                        history.len()
+                    );
+                }
+                Err(e) => {
+                    // kv cache 可能已经被部分覆盖
+                    history.clear();
+                    println!("error: {e}");
+                }
+            }
+            continue;
+        }
+        if let Some(arg) = user.strip_prefix("/lora ") {
+            // 换入或卸下适配器不需要重新加载基础模型，但 kv cache 需要重新计算
+            let adapter = match arg.trim() {
+                "off" => None,
+                path if PathBuf::from(path).is_dir() => Some(transformer.read_adapter(path)),
+                path => {
+                    println!("error: {path} is not an adapter directory");
+                    continue;
+                }
+            };
+            transformer.set_adapter(adapter);
+            history.clear();
+            println!("adapter: {}", arg.trim());
+            continue;
+        }
+        if user == "/retry" {
+            // 丢弃最后一个回答，用同样的提示词重新生成
+            if messages.last().is_none_or(|m| m.role != "assistant") {
+                println!("no answer to retry");
+                continue;
+            }
+            messages.pop();
+        } else {
+            messages.push(ChatMessage::new("user", user));
+        }
+
+        // 至少为回答留出四分之一的上下文
+        let prompt = match encode_within(
+            template,
+            tokenizer,
+            &mut messages,
+            n_system,
+            seq_len * 3 / 4,
+        ) {
+            Ok(prompt) if prompt.len() < seq_len => prompt,
+            Ok(prompt) => {
+                println!("message is too long ({} tokens)", prompt.len());
+                messages.pop();
+                continue;
+            }
+            Err(e) => {
+                println!("error: {e}");
+                messages.pop();
+                continue;
+            }
+        };
+        // 上下文填满时保留系统提示词
+        let n_keep = match template.encode(tokenizer, &messages[..n_system], false) {
+            Ok(system) => zip(&system, &prompt).take_while(|(a, b)| a == b).count(),
+            Err(_) => 0,
+        };
+        let (last, tokens) = prompt.split_last().unwrap();
+        let common = history
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count();
+        // 滑动窗口已经丢弃了需要的位置时从头计算
+        let common = match transformer.truncate(common) {
+            Ok(()) => common,
+            Err(_) => {
+                transformer.truncate(0).unwrap();
+                0
+            }
+        };
+        history.truncate(common);
+        if common < tokens.len() {
+            transformer
+                .update(&tokens[common..], common as _, &mut logger)
+                .unwrap();
+            history.extend_from_slice(&tokens[common..]);
+        }
+        let mut pos = tokens.len();
 
         print!("assistant: (pos = {pos}) ");
         std::io::stdout().flush().unwrap();
 
         let mut token = *last;
-        let mut buffer = VecDeque::<char>::new();
-        loop {
-            let logits = transformer.forward(token, pos as _, &mut logger);
+        let mut reply = Vec::<u8>::new();
+        let mut printed = 0;
+        // 回答最多占满一次上下文
+        for _ in 0..seq_len {
+            if pos == seq_len {
+                // 位置不能超过上下文长度（滑动窗口的模型也一样），
+                // 丢弃系统提示词之后一半的内容，平移 kv cache 继续生成
+                let discard = (pos - n_keep) / 2;
+                if discard == 0 {
+                    break;
+                }
+                transformer.shift_cache(n_keep, discard, pos);
+                history.drain(n_keep..n_keep + discard);
+                pos -= discard;
+            }
+            let logits = transformer.forward(token, pos as _, &mut logger).unwrap();
+            history.push(token);
             pos += 1;
 
-            match sampler.sample(logits) {
-                BOS => return,
-                EOS => {
-                    token = EOS;
-                    buffer.extend("</s>".chars());
-                }
-                next => {
-                    let piece = tokenizer.decode(token, next);
-                    token = next;
-                    buffer.extend(piece.chars());
-                }
+            let next = sampler.sample(logits);
+            if next == tokenizer.bos() || next == tokenizer.eos() {
+                break;
             }
+            reply.extend_from_slice(tokenizer.decode(token, next));
+            token = next;
 
+            if let Some(end) = stops
+                .iter()
+                .filter_map(|s| reply.windows(s.len()).position(|w| w == s.as_bytes()))
+                .min()
             {
-                let mut eos = false;
-                let words = buffer.iter().collect::<String>();
-                let words = if let Some((words, _)) = words.split_once("</s") {
-                    eos = true;
-                    words
-                } else {
-                    &words
-                };
-                let words = if let Some((words, _)) = words.split_once("<|") {
-                    eos = true;
-                    words
-                } else {
-                    &words
+                reply.truncate(end);
+                break;
+            }
+            // 末尾可能是结束标记的开头，暂不输出
+            let end = reply.len().saturating_sub(max_stop_len.saturating_sub(1));
+            if end > printed {
+                let valid = match std::str::from_utf8(&reply[printed..end]) {
+                    Ok(s) => s,
+                    Err(e) => std::str::from_utf8(&reply[printed..][..e.valid_up_to()]).unwrap(),
                 };
-                if eos {
-                    print!("{words} [end]");
-                    std::io::stdout().flush().unwrap();
-                    break;
-                } else {
-                    while buffer.len() > 3 {
-                        print!("{}", buffer.pop_front().unwrap());
-                        std::io::stdout().flush().unwrap();
-                    }
-                }
+                print!("{valid}");
+                std::io::stdout().flush().unwrap();
+                printed += valid.len();
             }
         }
-        println!();
+        println!("{} [end]", String::from_utf8_lossy(&reply[printed..]));
+
+        let reply = String::from_utf8_lossy(&reply);
+        messages.push(ChatMessage::new("assistant", reply.trim()));
+    }
+}
+
+/// 编码对话，超过 `limit` 个 token 时丢弃系统提示词之后最早的几轮对话，直到只剩最后一条消息。
+fn encode_within(
+    template: &ChatTemplate,
+    tokenizer: &dyn Tokenizer,
+    messages: &mut Vec<ChatMessage>,
+    n_system: usize,
+    limit: usize,
+) -> Result<Vec<u32>, TemplateError> {
+    loop {
+        let prompt = template.encode(tokenizer, messages, true)?;
+        if prompt.len() <= limit || messages.len() <= n_system + 1 {
+            return Ok(prompt);
+        }
+        // 按轮丢弃，保证剩下的对话仍然从用户消息开始
+        messages.remove(n_system);
+        while messages.len() > n_system + 1 && messages[n_system].role != "user" {
+            messages.remove(n_system);
+        }
     }
 }
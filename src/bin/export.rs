@@ -0,0 +1,53 @@
+use llama2_rs::Transformer;
+use std::{
+    fs::{canonicalize, File},
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+fn main() {
+    struct Args {
+        check_point: PathBuf,
+        output: PathBuf,
+        version: u32,
+    }
+
+    let mut process_args = std::env::args();
+    process_args.next().unwrap();
+    let mut args = Args {
+        check_point: process_args
+            .next()
+            .map(canonicalize)
+            .expect(USAGE_HELP)
+            .unwrap(),
+        output: process_args.next().map(PathBuf::from).expect(USAGE_HELP),
+        version: 0,
+    };
+    loop {
+        match process_args.next() {
+            Some(s) if s == "--version" => {
+                args.version = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
+            None => break,
+            _ => panic!("{USAGE_HELP}"),
+        }
+    }
+
+    let transformer = Transformer::read_checkpoint(&args.check_point);
+    let mut out = BufWriter::new(File::create(&args.output).unwrap());
+    if let Err(e) = transformer
+        .export_bin(args.version, &mut out)
+        .and_then(|()| out.flush())
+    {
+        eprintln!("error: {e}");
+        drop(out);
+        let _ = std::fs::remove_file(&args.output);
+        std::process::exit(1);
+    }
+}
+
+const USAGE_HELP: &str = "\
+Usage: cargo run --bin export <checkpoint> <output> [OPTIONS]
+Options:
+     --version <0|1|2>    llama2.c 的格式版本：0 为旧格式（默认），1 为 fp32，2 为 Q8_0 量化
+";
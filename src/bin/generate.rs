@@ -1,5 +1,5 @@
 use core::panic;
-use llama2_rs::{BpeTokenizer, Sampler, Tokenizer, Transformer, BOS};
+use llama2_rs::{read_tokenizer, KvCache, KvDtype, Sampler, Speculative, Tokenizer, Transformer};
 use std::{
     fs::canonicalize,
     io::Write,
@@ -19,6 +19,13 @@ fn main() {
         steps: usize,
         prompt: String,
         rng_seed: u64,
+        kv_dtype: KvDtype,
+        seq_len: Option<usize>,
+        lora: Option<PathBuf>,
+        prompts: Option<PathBuf>,
+        batch_size: usize,
+        draft: Option<PathBuf>,
+        draft_len: usize,
     }
 
     let mut process_args = std::env::args();
@@ -35,6 +42,13 @@ fn main() {
         steps: 256,
         prompt: String::new(),
         rng_seed: 0,
+        kv_dtype: KvDtype::F32,
+        seq_len: None,
+        lora: None,
+        prompts: None,
+        batch_size: 8,
+        draft: None,
+        draft_len: 4,
     };
     loop {
         match process_args.next() {
@@ -53,9 +67,30 @@ fn main() {
             Some(s) if s == "--prompt" => {
                 args.prompt = process_args.next().expect(USAGE_HELP);
             }
+            Some(s) if s == "--seq-len" => {
+                args.seq_len = Some(process_args.next().expect(USAGE_HELP).parse().unwrap());
+            }
+            Some(s) if s == "--kv-dtype" => {
+                args.kv_dtype = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
+            Some(s) if s == "--lora" => {
+                args.lora = process_args.next().map(PathBuf::from);
+            }
             Some(s) if s == "--rng-seed" => {
                 args.rng_seed = process_args.next().expect(USAGE_HELP).parse().unwrap();
             }
+            Some(s) if s == "--prompts" => {
+                args.prompts = process_args.next().map(PathBuf::from);
+            }
+            Some(s) if s == "--batch-size" => {
+                args.batch_size = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
+            Some(s) if s == "--draft" => {
+                args.draft = process_args.next().map(PathBuf::from);
+            }
+            Some(s) if s == "--draft-len" => {
+                args.draft_len = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
             None => break,
             _ => panic!("{USAGE_HELP}"),
         }
@@ -81,7 +116,44 @@ fn main() {
     }
 
     let mut transformer = Transformer::read_checkpoint(&args.check_point);
-    let tokenizer = BpeTokenizer::new(&args.tokenizer_path, transformer.vocab_size());
+    transformer.set_kv_dtype(args.kv_dtype);
+    if let Some(seq_len) = args.seq_len {
+        transformer.set_seq_len(seq_len);
+    }
+    if let Some(lora) = args.lora {
+        let adapter = transformer.read_adapter(lora);
+        transformer.set_adapter(Some(adapter));
+    }
+    let tokenizer = read_tokenizer(&args.tokenizer_path, transformer.vocab_size());
+
+    if let Some(path) = args.prompts {
+        let prompts = std::fs::read_to_string(path).unwrap();
+        let prompts = prompts
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        let samplers = (0..prompts.len())
+            .map(|i| {
+                Sampler::new(
+                    transformer.vocab_size(),
+                    args.temperature,
+                    args.top_p,
+                    args.rng_seed + i as u64,
+                )
+            })
+            .collect();
+        generate_batch(
+            &transformer,
+            &*tokenizer,
+            samplers,
+            &prompts,
+            args.steps,
+            args.batch_size.max(1),
+        );
+        return;
+    }
+
     let mut sampler = Sampler::new(
         transformer.vocab_size(),
         args.temperature,
@@ -89,9 +161,29 @@ fn main() {
         args.rng_seed,
     );
 
+    if let Some(draft) = args.draft {
+        let mut draft = Transformer::read_checkpoint(draft);
+        assert_eq!(
+            draft.vocab_size(),
+            transformer.vocab_size(),
+            "draft model must share the vocabulary"
+        );
+        draft.set_kv_dtype(args.kv_dtype);
+        draft.set_seq_len(transformer.seq_len());
+        generate_speculative(
+            &mut transformer,
+            Speculative::new(draft, args.draft_len.max(1)),
+            &*tokenizer,
+            &mut sampler,
+            args.prompt,
+            args.steps,
+        );
+        return;
+    }
+
     generate(
         &mut transformer,
-        &tokenizer,
+        &*tokenizer,
         &mut sampler,
         args.prompt,
         args.steps,
@@ -107,11 +199,18 @@ Options:
      --steps <int>
      --prompt <string>
      --rng-seed <int>
+     --prompts <file>      每行一个提示词，批量生成并逐行输出 json
+     --batch-size <int>    批量生成时同时推理的序列数，默认 8
+     --draft <checkpoint>  投机解码的草稿模型，与目标模型使用同一个词表
+     --draft-len <int>     草稿模型每次提出的 token 数，默认 4
+     --kv-dtype <f32|f16|int8>
+     --seq-len <int>
+     --lora <dir>          PEFT 格式的 LoRA 适配器目录
 ";
 
 fn generate(
     transformer: &mut Transformer,
-    tokenizer: &impl Tokenizer,
+    tokenizer: &dyn Tokenizer,
     sampler: &mut Sampler,
     prompt: String,
     steps: usize,
@@ -124,7 +223,7 @@ fn generate(
     let start = Instant::now();
 
     // 一次性输入提示词的所有 token
-    transformer.update(tokens, 0, &mut logger);
+    transformer.update(tokens, 0, &mut logger).unwrap();
     // 一个一个输入提示词的 token 但不计算 output
     // for (i, &t) in tokens.iter().enumerate() {
     //     transformer.update(&[t], i as _, &mut logger);
@@ -141,15 +240,17 @@ fn generate(
     let mut pos = tokens.len();
     let mut token = *last;
     while pos < steps {
-        let logits = transformer.forward(token, pos as _, &mut logger);
+        let logits = transformer.forward(token, pos as _, &mut logger).unwrap();
         let next = sampler.sample(logits);
         pos += 1;
 
-        if next == BOS {
+        if next == tokenizer.bos() || next == tokenizer.eos() {
             break;
         }
 
-        print!("{}", tokenizer.decode(token, next));
+        std::io::stdout()
+            .write_all(tokenizer.decode(token, next))
+            .unwrap();
         std::io::stdout().flush().unwrap();
 
         token = next;
@@ -163,3 +264,167 @@ fn generate(
         pos as f64 / (end - start).as_secs_f64()
     );
 }
+
+/// 投机解码生成：草稿模型每次提出几个 token，目标模型一次验证。
+fn generate_speculative(
+    transformer: &mut Transformer,
+    mut speculative: Speculative,
+    tokenizer: &dyn Tokenizer,
+    sampler: &mut Sampler,
+    prompt: String,
+    steps: usize,
+) {
+    let prompt = prompt.trim();
+    let prompt_tokens = tokenizer.encode(prompt, false, false);
+    let (last, tokens) = prompt_tokens.split_last().unwrap();
+    let steps = steps.min(transformer.seq_len());
+
+    let mut logger = ();
+    let start = Instant::now();
+
+    speculative
+        .prefill(transformer, tokens, &mut logger)
+        .unwrap();
+
+    print!("{prompt}");
+
+    let mid = Instant::now();
+
+    let mut pos = tokens.len();
+    let mut token = *last;
+    'generate: while pos < steps {
+        let tokens = speculative
+            .step(transformer, sampler, token, pos, &mut logger)
+            .unwrap();
+        for next in tokens {
+            pos += 1;
+
+            if next == tokenizer.bos() || next == tokenizer.eos() {
+                break 'generate;
+            }
+
+            std::io::stdout()
+                .write_all(tokenizer.decode(token, next))
+                .unwrap();
+
+            token = next;
+            if pos >= steps {
+                break 'generate;
+            }
+        }
+        std::io::stdout().flush().unwrap();
+    }
+
+    let end = Instant::now();
+    println!();
+    println!("init time: {:?}", mid - start);
+    println!(
+        "achieved tok/s: {}",
+        pos as f64 / (end - start).as_secs_f64()
+    );
+    println!("acceptance rate: {}", speculative.acceptance_rate());
+}
+
+/// 批量生成：最多 `batch_size` 个序列同时推理，一个序列结束后立即补入下一个提示词。
+///
+/// 新加入的序列输入整个提示词，其他序列每次输入一个 token，在同一次 [`Transformer::forward_batch`] 中完成。
+/// 按提示词的顺序输出 `{"prompt", "completion"}` 的 json 行。
+fn generate_batch(
+    transformer: &Transformer,
+    tokenizer: &dyn Tokenizer,
+    samplers: Vec<Sampler>,
+    prompts: &[&str],
+    steps: usize,
+    batch_size: usize,
+) {
+    struct Slot {
+        index: usize,
+        cache: KvCache,
+        sampler: Sampler,
+        /// 下一次输入的 token。
+        input: Vec<u32>,
+        pos: usize,
+        /// 字节 token 可能只是一个字符的一部分，结束时再转换为字符串。
+        completion: Vec<u8>,
+    }
+
+    let steps = steps.min(transformer.seq_len());
+    let vocab_size = transformer.vocab_size();
+    let mut logger = ();
+    let start = Instant::now();
+
+    let mut queue = samplers.into_iter().enumerate();
+    let mut slots = Vec::<Slot>::with_capacity(batch_size);
+    let mut caches = Vec::<KvCache>::new();
+    let mut results = vec![None; prompts.len()];
+    let mut printed = 0;
+    let mut n_tokens = 0;
+    loop {
+        // 补入新的提示词
+        while slots.len() < batch_size {
+            let Some((index, sampler)) = queue.next() else {
+                break;
+            };
+            let input = tokenizer.encode(prompts[index], false, false);
+            if input.is_empty() || input.len() > steps {
+                results[index] = Some(String::new());
+                continue;
+            }
+            slots.push(Slot {
+                index,
+                cache: caches.pop().unwrap_or_else(|| transformer.new_cache()),
+                sampler,
+                input,
+                pos: 0,
+                completion: Vec::new(),
+            });
+        }
+        if slots.is_empty() {
+            break;
+        }
+
+        let mut batch = slots
+            .iter_mut()
+            .map(|slot| (&mut slot.cache, &*slot.input, slot.pos as _))
+            .collect::<Vec<_>>();
+        let mut logits = transformer.forward_batch(&mut batch, &mut logger).unwrap();
+        n_tokens += slots.iter().map(|slot| slot.input.len()).sum::<usize>();
+
+        for (slot, logits) in slots.iter_mut().zip(logits.chunks_mut(vocab_size)) {
+            let token = *slot.input.last().unwrap();
+            let next = slot.sampler.sample(logits);
+            slot.pos += slot.input.len();
+            slot.input.clear();
+            if next != tokenizer.bos() && next != tokenizer.eos() {
+                slot.completion
+                    .extend_from_slice(tokenizer.decode(token, next));
+                if slot.pos < steps {
+                    slot.input.push(next);
+                }
+            }
+        }
+        // 结束的序列让出位置
+        let mut i = 0;
+        while i < slots.len() {
+            if slots[i].input.is_empty() {
+                let slot = slots.swap_remove(i);
+                results[slot.index] = Some(String::from_utf8_lossy(&slot.completion).into_owned());
+                caches.push(slot.cache);
+            } else {
+                i += 1;
+            }
+        }
+
+        while let Some(Some(completion)) = results.get(printed) {
+            let line = serde_json::json!({ "prompt": prompts[printed], "completion": completion });
+            println!("{line}");
+            printed += 1;
+        }
+    }
+    std::io::stdout().flush().unwrap();
+
+    eprintln!(
+        "achieved tok/s: {}",
+        n_tokens as f64 / start.elapsed().as_secs_f64()
+    );
+}
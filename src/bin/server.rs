@@ -0,0 +1,931 @@
+use core::panic;
+use llama2_rs::{
+    read_tokenizer, ChatMessage, ChatTemplate, KvDtype, PrefixCache, Sampler, Tokenizer,
+    Transformer,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    fs::canonicalize,
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+fn main() {
+    struct Args {
+        check_point: PathBuf,
+        tokenizer_path: PathBuf,
+        host: String,
+        port: u16,
+        model_name: Option<String>,
+        template: Option<String>,
+        prefix_cache_mb: usize,
+        kv_dtype: KvDtype,
+        seq_len: Option<usize>,
+    }
+
+    let mut process_args = std::env::args();
+    process_args.next().unwrap();
+    let mut args = Args {
+        check_point: process_args
+            .next()
+            .map(canonicalize)
+            .expect(USAGE_HELP)
+            .unwrap(),
+        tokenizer_path: canonicalize("tokenizer.bin").unwrap_or_default(),
+        host: "127.0.0.1".to_string(),
+        port: 8080,
+        model_name: None,
+        template: None,
+        prefix_cache_mb: 256,
+        kv_dtype: KvDtype::F32,
+        seq_len: None,
+    };
+    loop {
+        match process_args.next() {
+            Some(s) if s == "--tokenizer-path" => {
+                args.tokenizer_path = process_args.next().map(PathBuf::from).expect(USAGE_HELP);
+            }
+            Some(s) if s == "--host" => {
+                args.host = process_args.next().expect(USAGE_HELP);
+            }
+            Some(s) if s == "--port" => {
+                args.port = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
+            Some(s) if s == "--model-name" => {
+                args.model_name = process_args.next();
+            }
+            Some(s) if s == "--prefix-cache-mb" => {
+                args.prefix_cache_mb = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
+            Some(s) if s == "--seq-len" => {
+                args.seq_len = Some(process_args.next().expect(USAGE_HELP).parse().unwrap());
+            }
+            Some(s) if s == "--kv-dtype" => {
+                args.kv_dtype = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
+            Some(s) if s == "--template" => {
+                args.template = process_args.next();
+            }
+            None => break,
+            _ => panic!("{USAGE_HELP}"),
+        }
+    }
+
+    let mut transformer = Transformer::read_checkpoint(&args.check_point);
+    transformer.set_kv_dtype(args.kv_dtype);
+    if let Some(seq_len) = args.seq_len {
+        transformer.set_seq_len(seq_len);
+    }
+    let tokenizer = read_tokenizer(&args.tokenizer_path, transformer.vocab_size());
+    let model = args.model_name.unwrap_or_else(|| {
+        args.check_point
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    });
+    let template = match args.template {
+        None => ChatTemplate::for_model(&args.check_point),
+        Some(name) => ChatTemplate::load(name),
+    };
+    let mut state = State {
+        transformer,
+        tokenizer,
+        template,
+        prefix_cache: PrefixCache::new(args.prefix_cache_mb << 20),
+        model,
+        next_id: 0,
+    };
+
+    let server = Server::http((args.host.as_str(), args.port)).unwrap();
+    println!(
+        "serving {} on http://{}:{}",
+        state.model, args.host, args.port
+    );
+    // 只有一个模型实例，请求依次处理
+    for request in server.incoming_requests() {
+        state.handle(request);
+    }
+}
+
+const USAGE_HELP: &str = "\
+Usage: cargo run --bin server <checkpoint> [OPTIONS]
+Options:
+     --tokenizer-path <string>
+     --host <string>
+     --port <int>
+     --model-name <string>
+     --template <llama2|chatml|zephyr|alpaca|string>
+     --prefix-cache-mb <int>
+     --kv-dtype <f32|f16|int8>
+     --seq-len <int>
+";
+
+struct State {
+    transformer: Transformer,
+    tokenizer: Box<dyn Tokenizer>,
+    template: ChatTemplate,
+    prefix_cache: PrefixCache,
+    model: String,
+    next_id: u64,
+}
+
+#[derive(Deserialize)]
+struct CompletionRequest {
+    prompt: Prompt,
+    #[serde(flatten)]
+    params: Params,
+}
+
+#[derive(Deserialize)]
+struct ChatRequest {
+    messages: Vec<ChatMessage>,
+    #[serde(flatten)]
+    params: Params,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Prompt {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct Params {
+    max_tokens: Option<usize>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    stop: Option<Prompt>,
+    seed: Option<u64>,
+    n: Option<usize>,
+    #[serde(default)]
+    stream: bool,
+}
+
+/// 以 openai 的格式返回的请求错误。
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn invalid(message: impl Into<String>) -> Self {
+        Self {
+            status: 400,
+            message: message.into(),
+        }
+    }
+
+    fn response(&self) -> Response<std::io::Cursor<Vec<u8>>> {
+        let kind = match self.status {
+            404 => "not_found_error",
+            _ => "invalid_request_error",
+        };
+        json_response(
+            self.status,
+            &json!({ "error": { "message": self.message, "type": kind, "param": null, "code": null } }),
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Endpoint {
+    Completions,
+    ChatCompletions,
+}
+
+/// 校验后的请求。
+struct Job {
+    prompts: Vec<Vec<u32>>,
+    max_tokens: usize,
+    sampler: Sampler,
+    stops: Vec<String>,
+    stream: bool,
+}
+
+/// 一次生成的结果。
+struct Generation {
+    text: String,
+    finish_reason: &'static str,
+    prompt_tokens: usize,
+    /// 从前缀缓存复用的提示词 token 数。
+    cached_tokens: usize,
+    completion_tokens: usize,
+}
+
+impl State {
+    fn handle(&mut self, mut request: Request) {
+        let endpoint = match (request.method(), request.url()) {
+            (Method::Post, "/v1/completions") => Endpoint::Completions,
+            (Method::Post, "/v1/chat/completions") => Endpoint::ChatCompletions,
+            (Method::Get, "/v1/models") => {
+                let body = json!({
+                    "object": "list",
+                    "data": [{ "id": self.model, "object": "model", "created": 0, "owned_by": "llama2-rs" }],
+                });
+                let _ = request.respond(json_response(200, &body));
+                return;
+            }
+            (method, url @ ("/v1/completions" | "/v1/chat/completions" | "/v1/models")) => {
+                let allow = if url == "/v1/models" { "GET" } else { "POST" };
+                let err = ApiError {
+                    status: 405,
+                    message: format!("method {method} is not allowed for {url}"),
+                };
+                let allow = Header::from_bytes("Allow", allow).unwrap();
+                let _ = request.respond(err.response().with_header(allow));
+                return;
+            }
+            (_, url) => {
+                let err = ApiError {
+                    status: 404,
+                    message: format!("unknown endpoint {url}"),
+                };
+                let _ = request.respond(err.response());
+                return;
+            }
+        };
+
+        let mut body = String::new();
+        if request.as_reader().read_to_string(&mut body).is_err() {
+            let _ = request.respond(ApiError::invalid("request body is not utf-8").response());
+            return;
+        }
+        if let Err(e) = self.serve(endpoint, &body, request) {
+            eprintln!("{}", e.message);
+        }
+    }
+
+    fn serve(&mut self, endpoint: Endpoint, body: &str, request: Request) -> Result<(), ApiError> {
+        let job = match self.parse(endpoint, body) {
+            Ok(job) => job,
+            Err(e) => {
+                let _ = request.respond(e.response());
+                return Err(e);
+            }
+        };
+        let Job {
+            prompts,
+            max_tokens,
+            mut sampler,
+            stops,
+            stream,
+        } = job;
+
+        self.next_id += 1;
+        let id = match endpoint {
+            Endpoint::Completions => format!("cmpl-{}", self.next_id),
+            Endpoint::ChatCompletions => format!("chatcmpl-{}", self.next_id),
+        };
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let model = self.model.clone();
+
+        if stream {
+            // 对话的第一个分块带上角色，最后一个分块只有 `finish_reason`
+            let chunk = |index: usize, delta: Value, finish_reason: Option<&str>| match endpoint {
+                Endpoint::Completions => json!({
+                    "id": id, "object": "text_completion", "created": created, "model": model,
+                    "choices": [{ "index": index, "text": delta["content"].as_str().unwrap_or_default(), "logprobs": null, "finish_reason": finish_reason }],
+                }),
+                Endpoint::ChatCompletions => json!({
+                    "id": id, "object": "chat.completion.chunk", "created": created, "model": model,
+                    "choices": [{ "index": index, "delta": delta, "finish_reason": finish_reason }],
+                }),
+            };
+            // 服务器发送事件，每产生一段文本就作为一个分块写出并刷新，客户端断开时停止生成
+            let mut writer = request.into_writer();
+            let mut connected = writer
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                    Content-Type: text/event-stream\r\n\
+                    Cache-Control: no-cache\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n",
+                )
+                .is_ok();
+            let mut send = |event: &str| -> bool {
+                let event = format!("data: {event}\n\n");
+                write!(writer, "{:x}\r\n{event}\r\n", event.len()).is_ok() && writer.flush().is_ok()
+            };
+            for (index, prompt) in prompts.iter().enumerate() {
+                if let Endpoint::ChatCompletions = endpoint {
+                    let delta = json!({ "role": "assistant", "content": "" });
+                    connected = connected && send(&chunk(index, delta, None).to_string());
+                }
+                if !connected {
+                    break;
+                }
+                let generation =
+                    self.generate(prompt, max_tokens, &mut sampler, &stops, &mut |text| {
+                        let delta = json!({ "content": text });
+                        connected = send(&chunk(index, delta, None).to_string());
+                        connected
+                    });
+                if connected {
+                    let event = chunk(index, json!({}), Some(generation.finish_reason));
+                    connected = send(&event.to_string());
+                }
+            }
+            if connected && send("[DONE]") {
+                // 结束分块传输，连接可以继续处理下一个请求
+                let _ = writer.write_all(b"0\r\n\r\n").and_then(|_| writer.flush());
+            }
+        } else {
+            let mut choices = Vec::new();
+            let mut usage = (0, 0, 0);
+            for (index, prompt) in prompts.iter().enumerate() {
+                let generation =
+                    self.generate(prompt, max_tokens, &mut sampler, &stops, &mut |_| true);
+                usage.0 += generation.prompt_tokens;
+                usage.1 += generation.completion_tokens;
+                usage.2 += generation.cached_tokens;
+                choices.push(match endpoint {
+                    Endpoint::Completions => json!({
+                        "index": index, "text": generation.text, "logprobs": null,
+                        "finish_reason": generation.finish_reason,
+                    }),
+                    Endpoint::ChatCompletions => json!({
+                        "index": index,
+                        "message": { "role": "assistant", "content": generation.text },
+                        "finish_reason": generation.finish_reason,
+                    }),
+                });
+            }
+            let object = match endpoint {
+                Endpoint::Completions => "text_completion",
+                Endpoint::ChatCompletions => "chat.completion",
+            };
+            let body = json!({
+                "id": id, "object": object, "created": created, "model": model,
+                "choices": choices,
+                "usage": {
+                    "prompt_tokens": usage.0, "completion_tokens": usage.1, "total_tokens": usage.0 + usage.1,
+                    "prompt_tokens_details": { "cached_tokens": usage.2 },
+                },
+            });
+            let _ = request.respond(json_response(200, &body));
+        }
+        Ok(())
+    }
+
+    /// 解析并校验请求。
+    fn parse(&self, endpoint: Endpoint, body: &str) -> Result<Job, ApiError> {
+        let invalid_body =
+            |e: serde_json::Error| ApiError::invalid(format!("invalid request body: {e}"));
+        let (prompts, params, mut stops) = match endpoint {
+            Endpoint::Completions => {
+                let req = serde_json::from_str::<CompletionRequest>(body).map_err(invalid_body)?;
+                let prompts = match req.prompt {
+                    Prompt::One(p) => vec![p],
+                    Prompt::Many(p) if !p.is_empty() => p,
+                    Prompt::Many(_) => return Err(ApiError::invalid("prompt must not be empty")),
+                };
+                let prompts = prompts
+                    .iter()
+                    .map(|p| self.tokenizer.encode(p, true, false))
+                    .collect();
+                (prompts, req.params, vec![])
+            }
+            Endpoint::ChatCompletions => {
+                let req = serde_json::from_str::<ChatRequest>(body).map_err(invalid_body)?;
+                if req.messages.is_empty() {
+                    return Err(ApiError::invalid("messages must not be empty"));
+                }
+                let prompt = self
+                    .template
+                    .encode(&*self.tokenizer, &req.messages, true)
+                    .map_err(|e| ApiError::invalid(e.to_string()))?;
+                (vec![prompt], req.params, self.template.stops().to_vec())
+            }
+        };
+
+        let (max_tokens, sampler) = self.check_params(endpoint, &params)?;
+        stops.extend(match params.stop {
+            Some(Prompt::One(s)) => vec![s],
+            Some(Prompt::Many(s)) if s.len() <= 4 => s,
+            Some(Prompt::Many(_)) => return Err(ApiError::invalid("at most 4 stop sequences")),
+            None => vec![],
+        });
+        stops.retain(|s| !s.is_empty());
+
+        if let Some(p) = prompts
+            .iter()
+            .find(|p| p.len() >= self.transformer.seq_len())
+        {
+            return Err(ApiError::invalid(format!(
+                "prompt has {} tokens, the context length is {}",
+                p.len(),
+                self.transformer.seq_len()
+            )));
+        }
+        Ok(Job {
+            prompts,
+            max_tokens,
+            sampler,
+            stops,
+            stream: params.stream,
+        })
+    }
+
+    /// 把 openai 的采样参数映射到 [`Sampler`]。
+    fn check_params(
+        &self,
+        endpoint: Endpoint,
+        params: &Params,
+    ) -> Result<(usize, Sampler), ApiError> {
+        let temperature = params.temperature.unwrap_or(1.);
+        if !(0.0..=2.0).contains(&temperature) {
+            return Err(ApiError::invalid("temperature must be between 0 and 2"));
+        }
+        let top_p = params.top_p.unwrap_or(1.);
+        if !(top_p > 0. && top_p <= 1.) {
+            return Err(ApiError::invalid("top_p must be in (0, 1]"));
+        }
+        if params.n.is_some_and(|n| n != 1) {
+            return Err(ApiError::invalid("only n = 1 is supported"));
+        }
+        let max_tokens = match (params.max_tokens, endpoint) {
+            (Some(0), _) => return Err(ApiError::invalid("max_tokens must be at least 1")),
+            (Some(n), _) => n,
+            (None, Endpoint::Completions) => 16,
+            (None, Endpoint::ChatCompletions) => usize::MAX,
+        };
+        let seed = params.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64
+        });
+        let sampler = Sampler::new(
+            self.transformer.vocab_size(),
+            temperature,
+            top_p,
+            rng_state(seed),
+        );
+        Ok((max_tokens, sampler))
+    }
+
+    /// 从 `prompt` 开始生成，每得到一段可以输出的文本就调用 `emit`，`emit` 返回 `false` 时停止。
+    fn generate(
+        &mut self,
+        prompt: &[u32],
+        max_tokens: usize,
+        sampler: &mut Sampler,
+        stops: &[String],
+        emit: &mut dyn FnMut(&str) -> bool,
+    ) -> Generation {
+        let mut logger = ();
+        let (last, tokens) = prompt.split_last().unwrap();
+        // 提示词的长度已经在 `parse` 中检查过，位置不会超出上下文
+        let cached_tokens = if tokens.is_empty() {
+            0
+        } else {
+            self.transformer
+                .prefill(tokens, &mut self.prefix_cache, &mut logger)
+                .unwrap()
+        };
+
+        let mut text = String::new();
+        let mut matcher = StopMatcher::new(stops);
+        let mut pos = tokens.len();
+        let mut token = *last;
+        let mut completion_tokens = 0;
+        let mut stopped = false;
+        let finish_reason = loop {
+            if completion_tokens == max_tokens || pos >= self.transformer.seq_len() {
+                break "length";
+            }
+            let logits = self
+                .transformer
+                .forward(token, pos as _, &mut logger)
+                .unwrap();
+            let next = sampler.sample(logits);
+            pos += 1;
+            if next == self.tokenizer.bos() || next == self.tokenizer.eos() {
+                break "stop";
+            }
+            completion_tokens += 1;
+
+            let piece;
+            (piece, stopped) = matcher.push(self.tokenizer.decode(token, next));
+            token = next;
+            if !piece.is_empty() {
+                text.push_str(&piece);
+                if !emit(&piece) {
+                    break "stop";
+                }
+            }
+            if stopped {
+                break "stop";
+            }
+        };
+        // 没有遇到停止字符串时，扣留的文本也是回答的一部分
+        let rest = matcher.finish();
+        if !stopped && !rest.is_empty() {
+            emit(&rest);
+            text.push_str(&rest);
+        }
+        Generation {
+            text,
+            finish_reason,
+            prompt_tokens: prompt.len(),
+            cached_tokens,
+            completion_tokens,
+        }
+    }
+}
+
+/// 检查生成的文本中是否出现停止字符串，并扣留可能是停止字符串开头的部分。
+struct StopMatcher<'a> {
+    stops: &'a [String],
+    pending: Vec<u8>,
+}
+
+impl<'a> StopMatcher<'a> {
+    fn new(stops: &'a [String]) -> Self {
+        Self {
+            stops,
+            pending: Vec::new(),
+        }
+    }
+
+    /// 返回可以输出的文本，以及是否遇到了停止字符串。
+    fn push(&mut self, bytes: &[u8]) -> (String, bool) {
+        self.pending.extend_from_slice(bytes);
+        // 字节 token 可能只是字符的一部分，只处理完整的字符
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => {
+                let lossy = String::from_utf8_lossy(&self.pending).into_owned();
+                self.pending = lossy.into_bytes();
+                self.pending.len()
+            }
+        };
+        let text = std::str::from_utf8(&self.pending[..valid]).unwrap();
+
+        if let Some(i) = self
+            .stops
+            .iter()
+            .filter_map(|s| text.find(s.as_str()))
+            .min()
+        {
+            let ans = text[..i].to_string();
+            self.pending.clear();
+            return (ans, true);
+        }
+        let hold = self
+            .stops
+            .iter()
+            .flat_map(|s| {
+                (1..s.len())
+                    .filter(|&n| s.is_char_boundary(n))
+                    .map(|n| &s[..n])
+            })
+            .filter(|prefix| text.ends_with(prefix))
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        let ans = text[..text.len() - hold].to_string();
+        self.pending.drain(..ans.len());
+        (ans, false)
+    }
+
+    /// 取出扣留的文本。
+    fn finish(&mut self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut self.pending)).into_owned()
+    }
+}
+
+fn json_response(status: u16, body: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+/// 用 splitmix64 把请求的 seed 打散为采样器的随机数状态。
+///
+/// 采样器的 xorshift 在状态为 0 时只输出 0，直接使用 seed 0 会退化为总是选第一个候选。
+fn rng_state(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)).max(1)
+}
+
+#[test]
+fn test_stop_matcher() {
+    let stops = ["</s>".to_string(), "\n\nUser:".to_string()];
+    let mut matcher = StopMatcher::new(&stops);
+    // 可能是停止字符串开头的部分被扣留，确定不是之后再输出
+    assert_eq!(matcher.push(b"Hello\n"), ("Hello".into(), false));
+    assert_eq!(matcher.push(b"world<"), ("\nworld".into(), false));
+    assert_eq!(matcher.push(b"/"), ("".into(), false));
+    // 停止字符串拆分在两个 token 中
+    assert_eq!(matcher.push(b"s> bye"), ("".into(), true));
+
+    // 字节 token 组成的字符完整之后再输出
+    let mut matcher = StopMatcher::new(&stops);
+    assert_eq!(matcher.push(&"你".as_bytes()[..2]), ("".into(), false));
+    assert_eq!(matcher.push(&"你".as_bytes()[2..]), ("你".into(), false));
+    assert_eq!(matcher.push(b"\n\nUs"), ("".into(), false));
+    assert_eq!(matcher.finish(), "\n\nUs");
+}
+
+/// 测试用的分词器：每个字节一个 token，序号为字节加 3。
+#[cfg(test)]
+struct ByteTokenizer(Vec<String>);
+
+#[cfg(test)]
+impl ByteTokenizer {
+    fn new() -> Self {
+        let mut pieces = vec!["<unk>".to_string(), "<s>".to_string(), "</s>".to_string()];
+        pieces.extend((0..=255u8).map(|b| format!("<0x{b:02X}>")));
+        Self(pieces)
+    }
+}
+
+#[cfg(test)]
+impl Tokenizer for ByteTokenizer {
+    fn vocab_size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn max_token_len(&self) -> usize {
+        1
+    }
+
+    fn bos(&self) -> u32 {
+        1
+    }
+
+    fn eos(&self) -> u32 {
+        2
+    }
+
+    fn piece(&self, token: u32) -> &str {
+        &self.0[token as usize]
+    }
+
+    fn score(&self, _: u32) -> f32 {
+        0.
+    }
+
+    fn encode(&self, text: &str, bos: bool, eos: bool) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        if bos {
+            tokens.push(1);
+        }
+        tokens.extend(text.bytes().map(|b| b as u32 + 3));
+        if eos {
+            tokens.push(2);
+        }
+        tokens
+    }
+
+    fn decode(&self, _: u32, next: u32) -> &[u8] {
+        const BYTES: [u8; 256] = {
+            let mut bytes = [0; 256];
+            let mut i = 0;
+            while i < 256 {
+                bytes[i] = i as u8;
+                i += 1;
+            }
+            bytes
+        };
+        match next {
+            3.. => &BYTES[next as usize - 3..][..1],
+            _ => &[],
+        }
+    }
+}
+
+/// 用随机权重的 llama2.c 模型和字节分词器构造服务状态。
+#[cfg(test)]
+fn test_state(name: &str) -> State {
+    let (dim, hidden_dim, n_layers, n_heads, seq_len) = (16usize, 32, 1, 2, 64);
+    let vocab_size = 259;
+    let n_weights = vocab_size * dim
+        + n_layers * (2 * dim + 4 * dim * dim + 3 * dim * hidden_dim)
+        + dim
+        + seq_len * dim / n_heads;
+    let mut bytes = [
+        dim, hidden_dim, n_layers, n_heads, n_heads, vocab_size, seq_len,
+    ]
+    .iter()
+    .flat_map(|&n| (n as i32).to_le_bytes())
+    .collect::<Vec<_>>();
+    let mut seed = 0x2545f491u32;
+    for _ in 0..n_weights {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        let x = seed as f32 / u32::MAX as f32 - 0.5;
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    let path = std::env::temp_dir().join(format!("llama2-rs-{name}-{}.bin", std::process::id()));
+    std::fs::write(&path, bytes).unwrap();
+    let transformer = Transformer::read_checkpoint(&path);
+    std::fs::remove_file(&path).unwrap();
+    State {
+        transformer,
+        tokenizer: Box::new(ByteTokenizer::new()),
+        template: ChatTemplate::load("chatml"),
+        prefix_cache: PrefixCache::new(1 << 20),
+        model: "tiny".to_string(),
+        next_id: 0,
+    }
+}
+
+#[test]
+fn test_parse() {
+    let state = test_state("parse");
+    let invalid = [
+        (Endpoint::Completions, r#"{}"#),
+        (Endpoint::Completions, r#"{"prompt": []}"#),
+        (
+            Endpoint::Completions,
+            r#"{"prompt": "a", "temperature": 3}"#,
+        ),
+        (Endpoint::Completions, r#"{"prompt": "a", "top_p": 0}"#),
+        (Endpoint::Completions, r#"{"prompt": "a", "n": 2}"#),
+        (Endpoint::Completions, r#"{"prompt": "a", "max_tokens": 0}"#),
+        (
+            Endpoint::Completions,
+            r#"{"prompt": "a", "stop": ["1", "2", "3", "4", "5"]}"#,
+        ),
+        (Endpoint::ChatCompletions, r#"{"messages": []}"#),
+    ];
+    for (endpoint, body) in invalid {
+        let err = state.parse(endpoint, body).err().expect(body);
+        assert_eq!(err.status, 400, "{body}");
+    }
+    // 提示词填满上下文时没有位置生成
+    let long = format!(r#"{{"prompt": "{}"}}"#, "a".repeat(64));
+    assert!(state.parse(Endpoint::Completions, &long).is_err());
+
+    let job = state
+        .parse(
+            Endpoint::ChatCompletions,
+            r#"{"messages": [{"role": "user", "content": "hi"}], "stop": "x", "seed": 0}"#,
+        )
+        .ok()
+        .unwrap();
+    assert_eq!(job.max_tokens, usize::MAX);
+    assert_eq!(job.stops.last().unwrap(), "x");
+    assert!(job.stops.len() > 1);
+
+    // 指定 seed 的采样可以复现，seed 0 也是随机采样
+    let sample = |seed: u64| {
+        let body = format!(r#"{{"prompt": "a", "temperature": 1, "seed": {seed}}}"#);
+        let mut job = state.parse(Endpoint::Completions, &body).ok().unwrap();
+        let logits = (0..259).map(|i| (i % 7) as f32 * 0.1).collect::<Vec<_>>();
+        (0..16)
+            .map(|_| job.sampler.sample(&mut logits.clone()))
+            .collect::<Vec<_>>()
+    };
+    let zero = sample(0);
+    assert_eq!(zero, sample(0));
+    assert!(zero.iter().any(|&t| t != zero[0]), "{zero:?}");
+    assert_ne!(zero, sample(1));
+}
+
+#[test]
+fn test_handle() {
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpStream,
+    };
+
+    let mut state = test_state("handle");
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let addr = server.server_addr().to_ip().unwrap();
+
+    // 返回状态码和响应体，分块传输的响应体拼接成一个字符串
+    let request = move |method: &str, url: &str, body: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{method} {url} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let status = line.split(' ').nth(1).unwrap().parse::<u16>().unwrap();
+        let (mut len, mut chunked) = (0, false);
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            let header = line.trim_end().to_ascii_lowercase();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(n) = header.strip_prefix("content-length: ") {
+                len = n.parse().unwrap();
+            }
+            chunked |= header == "transfer-encoding: chunked";
+        }
+        let mut body = Vec::new();
+        if chunked {
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                let len = usize::from_str_radix(line.trim_end(), 16).unwrap();
+                let mut chunk = vec![0; len + 2];
+                reader.read_exact(&mut chunk).unwrap();
+                if len == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..len]);
+            }
+        } else {
+            body.resize(len, 0);
+            reader.read_exact(&mut body).unwrap();
+        }
+        (status, String::from_utf8(body).unwrap())
+    };
+    let client = std::thread::spawn(move || {
+        [
+            request("GET", "/v1/models", ""),
+            request("GET", "/v1/completions", ""),
+            request("POST", "/v1/nothing", "{}"),
+            request("POST", "/v1/completions", r#"{"prompt": "a", "top_p": 2}"#),
+            request(
+                "POST",
+                "/v1/completions",
+                r#"{"prompt": "ab", "max_tokens": 5, "temperature": 0}"#,
+            ),
+            request(
+                "POST",
+                "/v1/chat/completions",
+                r#"{"messages": [{"role": "user", "content": "hi"}], "max_tokens": 3, "stream": true}"#,
+            ),
+        ]
+    });
+    for _ in 0..6 {
+        state.handle(server.recv().unwrap());
+    }
+    let responses = client.join().unwrap();
+
+    let json = |body: &str| serde_json::from_str::<Value>(body).unwrap();
+    assert_eq!(responses[0].0, 200);
+    assert_eq!(json(&responses[0].1)["data"][0]["id"], "tiny");
+    assert_eq!(responses[1].0, 405);
+    assert_eq!(responses[2].0, 404);
+    assert_eq!(responses[3].0, 400);
+    assert_eq!(
+        json(&responses[3].1)["error"]["type"],
+        "invalid_request_error"
+    );
+
+    let (status, body) = &responses[4];
+    assert_eq!(*status, 200);
+    let body = json(body);
+    assert_eq!(body["object"], "text_completion");
+    assert_eq!(body["usage"]["prompt_tokens"], 3);
+    let completion_tokens = body["usage"]["completion_tokens"].as_u64().unwrap();
+    let choice = &body["choices"][0];
+    match choice["finish_reason"].as_str().unwrap() {
+        "length" => assert_eq!(completion_tokens, 5),
+        "stop" => assert!(completion_tokens < 5),
+        reason => panic!("unexpected finish_reason {reason}"),
+    }
+    // 每个 token 一个字节，不完整的字符替换为一个 `�`
+    assert!(choice["text"].as_str().unwrap().chars().count() <= completion_tokens as usize);
+
+    // 流式对话：第一个分块带角色，最后一个分块只有 finish_reason，之后是 [DONE]
+    let (status, body) = &responses[5];
+    assert_eq!(*status, 200);
+    let events = body
+        .split("\n\n")
+        .filter(|e| !e.is_empty())
+        .map(|e| e.strip_prefix("data: ").unwrap())
+        .collect::<Vec<_>>();
+    let (done, chunks) = events.split_last().unwrap();
+    assert_eq!(*done, "[DONE]");
+    let chunks = chunks.iter().map(|c| json(c)).collect::<Vec<_>>();
+    assert!(chunks.len() >= 2);
+    let (first, last) = (
+        &chunks[0]["choices"][0],
+        &chunks.last().unwrap()["choices"][0],
+    );
+    assert_eq!(
+        first["delta"],
+        json!({ "role": "assistant", "content": "" })
+    );
+    assert_eq!(last["delta"], json!({}));
+    assert!(last["finish_reason"].is_string());
+    for chunk in &chunks[1..chunks.len() - 1] {
+        let choice = &chunk["choices"][0];
+        assert!(choice["delta"].get("role").is_none());
+        assert!(choice["finish_reason"].is_null());
+    }
+}
@@ -0,0 +1,194 @@
+use core::panic;
+use llama2_rs::{read_tokenizer, BpeTokenizer, LongestPrefix, Normalizer, Tokenizer};
+use std::{ffi::OsStr, io::Read, path::PathBuf};
+
+fn main() {
+    #[derive(PartialEq)]
+    enum Command {
+        Encode,
+        Decode,
+        Vocab,
+    }
+
+    struct Args {
+        tokenizer_path: PathBuf,
+        command: Command,
+        longest_prefix: bool,
+        vocab_size: usize,
+        input: Option<PathBuf>,
+        seq_len: Option<usize>,
+        bos: bool,
+        eos: bool,
+        nfkc: bool,
+        collapse_whitespace: bool,
+        no_dummy_prefix: bool,
+    }
+
+    let mut process_args = std::env::args();
+    process_args.next().unwrap();
+    let mut args = Args {
+        tokenizer_path: process_args.next().map(PathBuf::from).expect(USAGE_HELP),
+        command: Command::Encode,
+        longest_prefix: false,
+        vocab_size: 32000,
+        input: None,
+        seq_len: None,
+        bos: false,
+        eos: false,
+        nfkc: false,
+        collapse_whitespace: false,
+        no_dummy_prefix: false,
+    };
+    loop {
+        match process_args.next() {
+            Some(s) if s == "encode" => args.command = Command::Encode,
+            Some(s) if s == "decode" => args.command = Command::Decode,
+            Some(s) if s == "vocab" => args.command = Command::Vocab,
+            Some(s) if s == "--longest-prefix" => args.longest_prefix = true,
+            Some(s) if s == "--vocab-size" => {
+                args.vocab_size = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
+            Some(s) if s == "--input" => {
+                args.input = process_args.next().map(PathBuf::from);
+            }
+            Some(s) if s == "--seq-len" => {
+                args.seq_len = Some(process_args.next().expect(USAGE_HELP).parse().unwrap());
+            }
+            Some(s) if s == "--bos" => args.bos = true,
+            Some(s) if s == "--eos" => args.eos = true,
+            Some(s) if s == "--nfkc" => args.nfkc = true,
+            Some(s) if s == "--collapse-whitespace" => args.collapse_whitespace = true,
+            Some(s) if s == "--no-dummy-prefix" => args.no_dummy_prefix = true,
+            None => break,
+            _ => panic!("{USAGE_HELP}"),
+        }
+    }
+
+    // 命令行选项在词表自带的规范化配置上追加
+    let normalizer = |n: &Normalizer| Normalizer {
+        nfkc: n.nfkc || args.nfkc,
+        collapse_whitespace: n.collapse_whitespace || args.collapse_whitespace,
+        add_dummy_prefix: n.add_dummy_prefix && !args.no_dummy_prefix,
+        space_replacement: n.space_replacement,
+    };
+    let tokenizer: Box<dyn Tokenizer> = if args.longest_prefix {
+        let tokenizer = LongestPrefix::new(&args.tokenizer_path);
+        let normalizer = normalizer(tokenizer.normalizer());
+        Box::new(tokenizer.with_normalizer(normalizer))
+    } else if args.tokenizer_path.extension() == Some(OsStr::new("bin")) {
+        let tokenizer = BpeTokenizer::new(&args.tokenizer_path, args.vocab_size);
+        let normalizer = normalizer(tokenizer.normalizer());
+        Box::new(tokenizer.with_normalizer(normalizer))
+    } else {
+        read_tokenizer(&args.tokenizer_path, args.vocab_size)
+    };
+
+    if args.command == Command::Vocab {
+        vocab(&*tokenizer);
+        return;
+    }
+
+    let input = match args.input {
+        Some(path) => std::fs::read_to_string(path).unwrap(),
+        None => {
+            let mut buf = String::new();
+            std::io::stdin().read_to_string(&mut buf).unwrap();
+            buf
+        }
+    };
+    match args.command {
+        Command::Encode => encode(&*tokenizer, &input, args.bos, args.eos, args.seq_len),
+        Command::Decode => decode(&*tokenizer, &input),
+        Command::Vocab => unreachable!(),
+    }
+}
+
+const USAGE_HELP: &str = "\
+Usage: cargo run --bin tokenize <tokenizer> [COMMAND] [OPTIONS]
+Commands:
+     encode (default)
+     decode
+     vocab
+Options:
+     --longest-prefix
+     --vocab-size <int>
+     --input <string>
+     --seq-len <int>
+     --bos
+     --eos
+     --nfkc
+     --collapse-whitespace
+     --no-dummy-prefix
+";
+
+fn encode(tokenizer: &dyn Tokenizer, text: &str, bos: bool, eos: bool, seq_len: Option<usize>) {
+    // 与 generate 一致，去掉首尾空白后再编码
+    let tokens = tokenizer.encode(text.trim(), bos, eos);
+    for (i, &token) in tokens.iter().enumerate() {
+        println!(
+            "{i:>6}: {token:>6} {:>12.4} {:?}",
+            tokenizer.score(token),
+            tokenizer.piece(token),
+        );
+    }
+    println!();
+    println!("chars : {}", text.trim().chars().count());
+    println!("bytes : {}", text.trim().len());
+    match seq_len {
+        Some(seq_len) => {
+            println!(
+                "tokens: {} / {seq_len} ({:.1}%)",
+                tokens.len(),
+                tokens.len() as f64 / seq_len as f64 * 100.
+            );
+            if tokens.len() > seq_len {
+                println!(
+                    "warning: prompt exceeds seq_len by {}",
+                    tokens.len() - seq_len
+                );
+            }
+        }
+        None => println!("tokens: {}", tokens.len()),
+    }
+}
+
+fn decode(tokenizer: &dyn Tokenizer, input: &str) {
+    let ids = input
+        .split(|c: char| c.is_whitespace() || matches!(c, ',' | '[' | ']'))
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse()
+                .unwrap_or_else(|_| panic!("\"{s}\" is not a token id"))
+        })
+        .collect::<Vec<_>>();
+
+    // 字节 token 可能只是字符的一部分，先拼接字节再统一转换
+    let mut text = Vec::<u8>::new();
+    let mut token = tokenizer.bos();
+    for next in ids {
+        if next as usize >= tokenizer.vocab_size() {
+            panic!(
+                "token {next} out of vocab (size {})",
+                tokenizer.vocab_size()
+            );
+        }
+        if next != tokenizer.bos() {
+            text.extend_from_slice(tokenizer.decode(token, next));
+        }
+        token = next;
+    }
+    println!("{}", String::from_utf8_lossy(&text));
+}
+
+fn vocab(tokenizer: &dyn Tokenizer) {
+    println!("vocab_size   : {}", tokenizer.vocab_size());
+    println!("max_token_len: {}", tokenizer.max_token_len());
+    println!();
+    for token in 0..tokenizer.vocab_size() as _ {
+        println!(
+            "{token:>6} {:>12.4} {:?}",
+            tokenizer.score(token),
+            tokenizer.piece(token),
+        );
+    }
+}
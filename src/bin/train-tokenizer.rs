@@ -0,0 +1,70 @@
+use core::panic;
+use llama2_rs::{BpeTokenizer, BpeTrainer, Tokenizer};
+use std::{fs::File, io::BufWriter, path::PathBuf, time::Instant};
+
+fn main() {
+    struct Args {
+        corpus: Vec<PathBuf>,
+        vocab_size: usize,
+        output: PathBuf,
+    }
+
+    let mut process_args = std::env::args();
+    process_args.next().unwrap();
+    let mut args = Args {
+        corpus: Vec::new(),
+        vocab_size: 32000,
+        output: PathBuf::from("tokenizer.bin"),
+    };
+    loop {
+        match process_args.next() {
+            Some(s) if s == "--vocab-size" => {
+                args.vocab_size = process_args.next().expect(USAGE_HELP).parse().unwrap();
+            }
+            Some(s) if s == "--output" => {
+                args.output = process_args.next().map(PathBuf::from).expect(USAGE_HELP);
+            }
+            Some(s) if s.starts_with("--") => panic!("{USAGE_HELP}"),
+            Some(s) => args.corpus.push(PathBuf::from(s)),
+            None => break,
+        }
+    }
+    if args.corpus.is_empty() {
+        panic!("{USAGE_HELP}");
+    }
+
+    let mut corpus = String::new();
+    for path in &args.corpus {
+        corpus.push_str(&std::fs::read_to_string(path).unwrap());
+        corpus.push('\n');
+    }
+
+    let start = Instant::now();
+    let trainer = BpeTrainer::train(&corpus, args.vocab_size);
+    println!(
+        "trained {} pieces from {} bytes in {:?}",
+        trainer.pieces().len(),
+        corpus.len(),
+        start.elapsed()
+    );
+
+    trainer
+        .write(&mut BufWriter::new(File::create(&args.output).unwrap()))
+        .unwrap();
+
+    // 重新加载以确认输出文件可用
+    let tokenizer = BpeTokenizer::new(&args.output, trainer.pieces().len());
+    println!(
+        "saved to {}: vocab_size = {}, max_token_len = {}",
+        args.output.display(),
+        tokenizer.vocab_size(),
+        tokenizer.max_token_len()
+    );
+}
+
+const USAGE_HELP: &str = "\
+Usage: cargo run --bin train-tokenizer <corpus>... [OPTIONS]
+Options:
+     --vocab-size <int>
+     --output <string>
+";
@@ -0,0 +1,929 @@
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// 模板解析或渲染时的错误，包括模板中 `raise_exception` 抛出的错误。
+#[derive(Clone, Debug, PartialEq)]
+pub struct TemplateError(pub String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+type Result<T> = std::result::Result<T, TemplateError>;
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        TemplateError(format!($($arg)*))
+    };
+}
+
+/// huggingface 对话模板使用的 jinja 子集。
+///
+/// 与 huggingface 一样开启 `trim_blocks` 和 `lstrip_blocks`，支持：
+///
+/// - `{{ }}`、`{% %}`、`{# #}` 以及 `-` 空白控制；
+/// - `if`/`elif`/`else`、`for`（含 `loop` 变量）和 `set` 语句；
+/// - 字面量、列表、下标、切片、属性、过滤器、测试、算术、比较和逻辑运算、条件表达式；
+/// - 常用的字符串方法、过滤器和 `raise_exception` 函数。
+pub(crate) struct Template(Vec<Node>);
+
+enum Node {
+    Text(String),
+    Expr(Expr),
+    If(Vec<(Expr, Vec<Node>)>, Vec<Node>),
+    For(String, Expr, Vec<Node>),
+    Set(String, Expr),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    List(Vec<Expr>),
+    Var(String),
+    Attr(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
+    Call(Box<Expr>, Vec<Expr>),
+    Filter(Box<Expr>, String, Vec<Expr>),
+    Test(Box<Expr>, String, bool),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+}
+
+impl Template {
+    pub fn new(source: &str) -> Result<Self> {
+        let mut segments = split(source)?.into_iter();
+        let (nodes, end) = parse_block(&mut segments, &[])?;
+        match end {
+            None => Ok(Self(nodes)),
+            Some(stmt) => Err(error!("unexpected {{% {stmt} %}}")),
+        }
+    }
+
+    /// 使用 `context` 中的变量渲染模板，`context` 必须是对象。
+    pub fn render(&self, context: Value) -> Result<String> {
+        let Value::Object(globals) = context else {
+            return Err(error!("template context must be an object"));
+        };
+        let mut scopes = vec![globals];
+        let mut output = String::new();
+        render_nodes(&self.0, &mut scopes, &mut output)?;
+        Ok(output)
+    }
+}
+
+enum Segment {
+    Text(String),
+    Expr(String),
+    Stmt(String),
+}
+
+/// 把模板源码切分为文本、表达式和语句，同时处理空白控制。
+fn split(source: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = source;
+    // 上一个标签以 `-` 结束，去掉后面文本开头的所有空白
+    let mut strip_next = false;
+    // 上一个标签是语句或注释，去掉后面紧跟的一个换行
+    let mut trim_newline = false;
+    loop {
+        let tag = ["{{", "{%", "{#"]
+            .iter()
+            .filter_map(|open| rest.find(open))
+            .min();
+        let mut text = &rest[..tag.unwrap_or(rest.len())];
+
+        if let Some(i) = tag {
+            let open = &rest[i..i + 2];
+            if rest[i + 2..].starts_with('-') {
+                text = text.trim_end();
+            } else if open != "{{" && !rest[i + 2..].starts_with('+') {
+                // lstrip_blocks：标签所在行前面只有空白时去掉这些空白
+                let line_start = match text.rfind('\n') {
+                    Some(j) => Some(j + 1),
+                    None if rest.len() == source.len() => Some(0),
+                    None => None,
+                };
+                if let Some(j) = line_start {
+                    if text[j..].chars().all(|c| c == ' ' || c == '\t') {
+                        text = &text[..j];
+                    }
+                }
+            }
+        }
+        if strip_next {
+            text = text.trim_start();
+        } else if trim_newline {
+            text = text
+                .strip_prefix("\r\n")
+                .or_else(|| text.strip_prefix('\n'))
+                .unwrap_or(text);
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text.to_string()));
+        }
+
+        let Some(i) = tag else {
+            break;
+        };
+        let open = &rest[i..i + 2];
+        let close = match open {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let body = &rest[i + 2..];
+        let body = body
+            .strip_prefix('-')
+            .or_else(|| body.strip_prefix('+'))
+            .unwrap_or(body);
+        let end = body
+            .find(close)
+            .ok_or_else(|| error!("unclosed {open} in template"))?;
+        let mut inner = &body[..end];
+        strip_next = inner.ends_with('-');
+        trim_newline = open != "{{" && !inner.ends_with('+');
+        if strip_next || inner.ends_with('+') {
+            inner = &inner[..inner.len() - 1];
+        }
+        match open {
+            "{{" => segments.push(Segment::Expr(inner.trim().to_string())),
+            "{%" => segments.push(Segment::Stmt(inner.trim().to_string())),
+            _ => {}
+        }
+        rest = &body[end + 2..];
+    }
+    Ok(segments)
+}
+
+/// 解析语句块，直到遇到 `ends` 中的某个语句，返回块内的节点和结束语句。
+fn parse_block(
+    segments: &mut impl Iterator<Item = Segment>,
+    ends: &[&str],
+) -> Result<(Vec<Node>, Option<String>)> {
+    let mut nodes = Vec::new();
+    while let Some(segment) = segments.next() {
+        match segment {
+            Segment::Text(text) => nodes.push(Node::Text(text)),
+            Segment::Expr(expr) => nodes.push(Node::Expr(parse_expr(&expr)?)),
+            Segment::Stmt(stmt) => {
+                let (keyword, rest) = stmt.split_once(char::is_whitespace).unwrap_or((&stmt, ""));
+                if ends.contains(&keyword) {
+                    return Ok((nodes, Some(stmt)));
+                }
+                match keyword {
+                    "if" => {
+                        let mut branches = Vec::new();
+                        let mut cond = parse_expr(rest)?;
+                        let otherwise = loop {
+                            let (body, end) = parse_block(segments, &["elif", "else", "endif"])?;
+                            branches.push((cond, body));
+                            let end = end.ok_or_else(|| error!("missing {{% endif %}}"))?;
+                            match end.split_once(char::is_whitespace) {
+                                Some(("elif", expr)) => cond = parse_expr(expr)?,
+                                _ if end == "else" => {
+                                    let (body, end) = parse_block(segments, &["endif"])?;
+                                    end.ok_or_else(|| error!("missing {{% endif %}}"))?;
+                                    break body;
+                                }
+                                _ => break Vec::new(),
+                            }
+                        };
+                        nodes.push(Node::If(branches, otherwise));
+                    }
+                    "for" => {
+                        let (var, iter) = rest
+                            .split_once(" in ")
+                            .ok_or_else(|| error!("invalid for statement: {stmt}"))?;
+                        let (body, end) = parse_block(segments, &["endfor"])?;
+                        end.ok_or_else(|| error!("missing {{% endfor %}}"))?;
+                        nodes.push(Node::For(var.trim().to_string(), parse_expr(iter)?, body));
+                    }
+                    "set" => {
+                        let (var, value) = rest
+                            .split_once('=')
+                            .ok_or_else(|| error!("invalid set statement: {stmt}"))?;
+                        nodes.push(Node::Set(var.trim().to_string(), parse_expr(value)?));
+                    }
+                    _ => return Err(error!("unsupported statement: {stmt}")),
+                }
+            }
+        }
+    }
+    Ok((nodes, None))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Str(String),
+    Int(i64),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 20] = [
+    "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "~", "(", ")", "[", "]", ".", ",",
+    ":", "|",
+];
+
+fn lex(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            let mut end = i;
+            while let Some(&(j, c)) = chars.peek() {
+                if !c.is_ascii_digit() {
+                    break;
+                }
+                end = j + 1;
+                chars.next();
+            }
+            tokens.push(Token::Int(source[i..end].parse().unwrap()));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = i;
+            while let Some(&(j, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = j + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Name(source[i..end].to_string()));
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, q)) if q == c => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => s.push('\n'),
+                        Some((_, 't')) => s.push('\t'),
+                        Some((_, 'r')) => s.push('\r'),
+                        Some((_, e)) => s.push(e),
+                        None => return Err(error!("unterminated string in {source}")),
+                    },
+                    Some((_, e)) => s.push(e),
+                    None => return Err(error!("unterminated string in {source}")),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| source[i..].starts_with(*op))
+                .ok_or_else(|| error!("unexpected character {c:?} in {source}"))?;
+            tokens.push(Token::Op(op));
+            for _ in 0..op.len() {
+                chars.next();
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_expr(source: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: lex(source)?,
+        pos: 0,
+    };
+    let expr = parser.expr()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(expr),
+        Some(token) => Err(error!("unexpected {token:?} in {source}")),
+    }
+}
+
+/// 按优先级从低到高递归下降解析表达式。
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token.ok_or_else(|| error!("unexpected end of expression"))
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        let eat = matches!(self.peek(), Some(Token::Op(o)) if *o == op);
+        self.pos += eat as usize;
+        eat
+    }
+
+    fn eat_name(&mut self, name: &str) -> bool {
+        let eat = matches!(self.peek(), Some(Token::Name(n)) if n == name);
+        self.pos += eat as usize;
+        eat
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if self.eat_op(op) {
+            Ok(())
+        } else {
+            Err(error!("expected `{op}`, found {:?}", self.peek()))
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::Name(name) => Ok(name),
+            token => Err(error!("expected a name, found {token:?}")),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let expr = self.or()?;
+        if self.eat_name("if") {
+            let cond = self.or()?;
+            let otherwise = if self.eat_name("else") {
+                Some(Box::new(self.expr()?))
+            } else {
+                None
+            };
+            Ok(Expr::Cond(Box::new(cond), Box::new(expr), otherwise))
+        } else {
+            Ok(expr)
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat_name("or") {
+            expr = Expr::Binary("or", Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.not()?;
+        while self.eat_name("and") {
+            expr = Expr::Binary("and", Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat_name("not") {
+            Ok(Expr::Not(Box::new(self.not()?)))
+        } else {
+            self.compare()
+        }
+    }
+
+    fn compare(&mut self) -> Result<Expr> {
+        let mut expr = self.concat()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(op)) if ["==", "!=", "<", ">", "<=", ">="].contains(op) => *op,
+                Some(Token::Name(n)) if n == "in" => "in",
+                Some(Token::Name(n)) if n == "not" => {
+                    if self.tokens.get(self.pos + 1) != Some(&Token::Name("in".into())) {
+                        return Ok(expr);
+                    }
+                    self.pos += 1;
+                    "not in"
+                }
+                Some(Token::Name(n)) if n == "is" => {
+                    self.pos += 1;
+                    let negated = self.eat_name("not");
+                    expr = Expr::Test(Box::new(expr), self.name()?, negated);
+                    continue;
+                }
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.concat()?));
+        }
+    }
+
+    fn concat(&mut self) -> Result<Expr> {
+        let mut expr = self.add()?;
+        while self.eat_op("~") {
+            expr = Expr::Binary("~", Box::new(expr), Box::new(self.add()?));
+        }
+        Ok(expr)
+    }
+
+    fn add(&mut self) -> Result<Expr> {
+        let mut expr = self.mul()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(op)) if ["+", "-"].contains(op) => *op,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.mul()?));
+        }
+    }
+
+    fn mul(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(op)) if ["*", "/", "%"].contains(op) => *op,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat_op("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else {
+            self.postfix()
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat_op(".") {
+                expr = Expr::Attr(Box::new(expr), self.name()?);
+            } else if self.eat_op("[") {
+                let start = if matches!(self.peek(), Some(Token::Op(":"))) {
+                    None
+                } else {
+                    Some(Box::new(self.expr()?))
+                };
+                if self.eat_op(":") {
+                    let end = if matches!(self.peek(), Some(Token::Op("]"))) {
+                        None
+                    } else {
+                        Some(Box::new(self.expr()?))
+                    };
+                    expr = Expr::Slice(Box::new(expr), start, end);
+                } else {
+                    let index = start.ok_or_else(|| error!("empty index"))?;
+                    expr = Expr::Index(Box::new(expr), index);
+                }
+                self.expect_op("]")?;
+            } else if self.eat_op("(") {
+                expr = Expr::Call(Box::new(expr), self.args(")")?);
+            } else if self.eat_op("|") {
+                let name = self.name()?;
+                let args = if self.eat_op("(") {
+                    self.args(")")?
+                } else {
+                    Vec::new()
+                };
+                expr = Expr::Filter(Box::new(expr), name, args);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    /// 解析逗号分隔的表达式直到 `close`。
+    fn args(&mut self, close: &str) -> Result<Vec<Expr>> {
+        let mut args = Vec::new();
+        while !self.eat_op(close) {
+            args.push(self.expr()?);
+            if !self.eat_op(",") {
+                self.expect_op(close)?;
+                break;
+            }
+        }
+        Ok(args)
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next()? {
+            Token::Str(mut s) => {
+                // 相邻的字符串字面量自动拼接
+                while let Some(Token::Str(next)) = self.peek() {
+                    s.push_str(next);
+                    self.pos += 1;
+                }
+                Ok(Expr::Literal(Value::String(s)))
+            }
+            Token::Int(i) => Ok(Expr::Literal(i.into())),
+            Token::Name(name) => Ok(match name.as_str() {
+                "true" | "True" => Expr::Literal(true.into()),
+                "false" | "False" => Expr::Literal(false.into()),
+                "none" | "None" => Expr::Literal(Value::Null),
+                _ => Expr::Var(name),
+            }),
+            Token::Op("(") => {
+                let expr = self.expr()?;
+                self.expect_op(")")?;
+                Ok(expr)
+            }
+            Token::Op("[") => Ok(Expr::List(self.args("]")?)),
+            token => Err(error!("unexpected {token:?}")),
+        }
+    }
+}
+
+fn render_nodes(
+    nodes: &[Node],
+    scopes: &mut Vec<Map<String, Value>>,
+    output: &mut String,
+) -> Result<()> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Expr(expr) => output.push_str(&display(&eval(expr, scopes)?)),
+            Node::If(branches, otherwise) => {
+                let mut body = otherwise;
+                for (cond, branch) in branches {
+                    if truthy(&eval(cond, scopes)?) {
+                        body = branch;
+                        break;
+                    }
+                }
+                render_nodes(body, scopes, output)?;
+            }
+            Node::For(var, iter, body) => {
+                let items = match eval(iter, scopes)? {
+                    Value::Array(items) => items,
+                    Value::Object(map) => map.into_iter().map(|(k, _)| k.into()).collect(),
+                    Value::String(s) => s.chars().map(|c| c.to_string().into()).collect(),
+                    Value::Null => Vec::new(),
+                    value => return Err(error!("{value} is not iterable")),
+                };
+                let length = items.len();
+                for (i, item) in items.into_iter().enumerate() {
+                    let mut scope = Map::new();
+                    scope.insert(var.clone(), item);
+                    scope.insert(
+                        "loop".into(),
+                        serde_json::json!({
+                            "index": i + 1,
+                            "index0": i,
+                            "revindex": length - i,
+                            "revindex0": length - i - 1,
+                            "first": i == 0,
+                            "last": i + 1 == length,
+                            "length": length,
+                        }),
+                    );
+                    scopes.push(scope);
+                    let result = render_nodes(body, scopes, output);
+                    scopes.pop();
+                    result?;
+                }
+            }
+            Node::Set(var, value) => {
+                let value = eval(value, scopes)?;
+                scopes.last_mut().unwrap().insert(var.clone(), value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 求值变量、属性或下标，未定义时返回 `None`。
+fn lookup(expr: &Expr, scopes: &[Map<String, Value>]) -> Result<Option<Value>> {
+    match expr {
+        Expr::Var(name) => Ok(scopes.iter().rev().find_map(|s| s.get(name)).cloned()),
+        Expr::Attr(obj, name) => Ok(lookup(obj, scopes)?.and_then(|obj| match obj {
+            Value::Object(mut map) => map.remove(name),
+            _ => None,
+        })),
+        Expr::Index(obj, index) => {
+            let Some(obj) = lookup(obj, scopes)? else {
+                return Ok(None);
+            };
+            Ok(match (obj, eval(index, scopes)?) {
+                (Value::Object(mut map), Value::String(key)) => map.remove(&key),
+                (Value::Array(mut items), Value::Number(i)) => {
+                    let len = items.len() as i64;
+                    match i.as_i64().map(|i| if i < 0 { i + len } else { i }) {
+                        Some(i) if (0..len).contains(&i) => Some(items.swap_remove(i as usize)),
+                        _ => None,
+                    }
+                }
+                (Value::String(s), Value::Number(i)) => {
+                    let chars = s.chars().collect::<Vec<_>>();
+                    let len = chars.len() as i64;
+                    match i.as_i64().map(|i| if i < 0 { i + len } else { i }) {
+                        Some(i) if (0..len).contains(&i) => {
+                            Some(chars[i as usize].to_string().into())
+                        }
+                        _ => None,
+                    }
+                }
+                (obj, index) => return Err(error!("cannot index {obj} with {index}")),
+            })
+        }
+        expr => eval(expr, scopes).map(Some),
+    }
+}
+
+fn eval(expr: &Expr, scopes: &[Map<String, Value>]) -> Result<Value> {
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::List(items) => items.iter().map(|e| eval(e, scopes)).collect(),
+        Expr::Var(_) | Expr::Attr(..) | Expr::Index(..) => {
+            Ok(lookup(expr, scopes)?.unwrap_or(Value::Null))
+        }
+        Expr::Slice(obj, start, end) => {
+            let obj = eval(obj, scopes)?;
+            let bound = |e: &Option<Box<Expr>>, len: usize, default: usize| -> Result<usize> {
+                let Some(e) = e else {
+                    return Ok(default);
+                };
+                let i = eval(e, scopes)?
+                    .as_i64()
+                    .ok_or_else(|| error!("slice index must be an integer"))?;
+                let i = if i < 0 { i + len as i64 } else { i };
+                Ok(i.clamp(0, len as i64) as usize)
+            };
+            match obj {
+                Value::Array(items) => {
+                    let len = items.len();
+                    let (start, end) = (bound(start, len, 0)?, bound(end, len, len)?);
+                    Ok(Value::Array(items[start..end.max(start)].to_vec()))
+                }
+                Value::String(s) => {
+                    let chars = s.chars().collect::<Vec<_>>();
+                    let len = chars.len();
+                    let (start, end) = (bound(start, len, 0)?, bound(end, len, len)?);
+                    Ok(chars[start..end.max(start)]
+                        .iter()
+                        .collect::<String>()
+                        .into())
+                }
+                obj => Err(error!("cannot slice {obj}")),
+            }
+        }
+        Expr::Call(callee, args) => {
+            let args = args
+                .iter()
+                .map(|a| eval(a, scopes))
+                .collect::<Result<Vec<_>>>()?;
+            match &**callee {
+                Expr::Var(name) if name == "raise_exception" => {
+                    Err(TemplateError(args.first().map(display).unwrap_or_default()))
+                }
+                Expr::Attr(obj, method) => call_method(eval(obj, scopes)?, method, &args),
+                callee => Err(error!("unsupported function call {callee:?}")),
+            }
+        }
+        Expr::Filter(value, name, args) => {
+            let value = eval(value, scopes)?;
+            let args = args
+                .iter()
+                .map(|a| eval(a, scopes))
+                .collect::<Result<Vec<_>>>()?;
+            filter(value, name, &args)
+        }
+        Expr::Test(value, name, negated) => {
+            let value = lookup(value, scopes)?;
+            let result = match (name.as_str(), &value) {
+                ("defined", v) => v.is_some(),
+                ("undefined", v) => v.is_none(),
+                ("none", v) => matches!(v, Some(Value::Null)),
+                ("string", v) => matches!(v, Some(Value::String(_))),
+                ("number", v) => matches!(v, Some(Value::Number(_))),
+                ("boolean", v) => matches!(v, Some(Value::Bool(_))),
+                ("mapping", v) => matches!(v, Some(Value::Object(_))),
+                ("sequence" | "iterable", v) => {
+                    matches!(
+                        v,
+                        Some(Value::Array(_) | Value::String(_) | Value::Object(_))
+                    )
+                }
+                (test, _) => return Err(error!("unsupported test `{test}`")),
+            };
+            Ok((result != *negated).into())
+        }
+        Expr::Not(value) => Ok((!truthy(&eval(value, scopes)?)).into()),
+        Expr::Neg(value) => match eval(value, scopes)? {
+            Value::Number(n) if n.is_i64() => Ok((-n.as_i64().unwrap()).into()),
+            Value::Number(n) => Ok((-n.as_f64().unwrap()).into()),
+            value => Err(error!("cannot negate {value}")),
+        },
+        Expr::Binary("and", l, r) => {
+            let l = eval(l, scopes)?;
+            if truthy(&l) {
+                eval(r, scopes)
+            } else {
+                Ok(l)
+            }
+        }
+        Expr::Binary("or", l, r) => {
+            let l = eval(l, scopes)?;
+            if truthy(&l) {
+                Ok(l)
+            } else {
+                eval(r, scopes)
+            }
+        }
+        Expr::Binary(op, l, r) => binary(op, eval(l, scopes)?, eval(r, scopes)?),
+        Expr::Cond(cond, then, otherwise) => {
+            if truthy(&eval(cond, scopes)?) {
+                eval(then, scopes)
+            } else if let Some(otherwise) = otherwise {
+                eval(otherwise, scopes)
+            } else {
+                Ok(Value::Null)
+            }
+        }
+    }
+}
+
+fn binary(op: &str, l: Value, r: Value) -> Result<Value> {
+    use Value::{Array, Number, String as Str};
+    Ok(match (op, l, r) {
+        ("~", l, r) => (display(&l) + &display(&r)).into(),
+        ("==", l, r) => (equal(&l, &r)).into(),
+        ("!=", l, r) => (!equal(&l, &r)).into(),
+        ("in", l, r) => contains(&r, &l)?.into(),
+        ("not in", l, r) => (!contains(&r, &l)?).into(),
+        ("+", Str(l), Str(r)) => (l + &r).into(),
+        ("+", Array(mut l), Array(r)) => {
+            l.extend(r);
+            Array(l)
+        }
+        (op, Str(l), Str(r)) if ["<", ">", "<=", ">="].contains(&op) => {
+            compare(op, l.cmp(&r)).into()
+        }
+        (op, Number(l), Number(r)) => match (l.as_i64(), r.as_i64()) {
+            (Some(l), Some(r)) => match op {
+                "+" => (l + r).into(),
+                "-" => (l - r).into(),
+                "*" => (l * r).into(),
+                "/" if r != 0 => (l as f64 / r as f64).into(),
+                "%" if r != 0 => l.rem_euclid(r).into(),
+                "/" | "%" => return Err(error!("division by zero")),
+                op => compare(op, l.cmp(&r)).into(),
+            },
+            _ => {
+                let (l, r) = (l.as_f64().unwrap(), r.as_f64().unwrap());
+                match op {
+                    "+" => (l + r).into(),
+                    "-" => (l - r).into(),
+                    "*" => (l * r).into(),
+                    "/" => (l / r).into(),
+                    "%" => (l % r).into(),
+                    op => compare(op, l.partial_cmp(&r).unwrap()).into(),
+                }
+            }
+        },
+        (op, l, r) => return Err(error!("unsupported operation {l} {op} {r}")),
+    })
+}
+
+fn compare(op: &str, ord: std::cmp::Ordering) -> bool {
+    match op {
+        "<" => ord.is_lt(),
+        ">" => ord.is_gt(),
+        "<=" => ord.is_le(),
+        _ => ord.is_ge(),
+    }
+}
+
+fn equal(l: &Value, r: &Value) -> bool {
+    match (l, r) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64() == r.as_f64(),
+        (l, r) => l == r,
+    }
+}
+
+fn contains(container: &Value, item: &Value) -> Result<bool> {
+    match (container, item) {
+        (Value::String(s), Value::String(sub)) => Ok(s.contains(sub.as_str())),
+        (Value::Array(items), item) => Ok(items.iter().any(|i| equal(i, item))),
+        (Value::Object(map), Value::String(key)) => Ok(map.contains_key(key)),
+        (container, item) => Err(error!("cannot test whether {item} in {container}")),
+    }
+}
+
+fn call_method(obj: Value, method: &str, args: &[Value]) -> Result<Value> {
+    let arg = |i: usize| args.get(i).and_then(Value::as_str);
+    // 与 python 一样，没有参数时去掉空白，否则去掉参数中的字符
+    let strip = |c: char| arg(0).map_or(c.is_whitespace(), |a| a.contains(c));
+    match (&obj, method) {
+        (Value::String(s), "strip") => Ok(s.trim_matches(strip).into()),
+        (Value::String(s), "lstrip") => Ok(s.trim_start_matches(strip).into()),
+        (Value::String(s), "rstrip") => Ok(s.trim_end_matches(strip).into()),
+        (Value::String(s), "upper") => Ok(s.to_uppercase().into()),
+        (Value::String(s), "lower") => Ok(s.to_lowercase().into()),
+        (Value::String(s), "startswith") => Ok(arg(0).is_some_and(|p| s.starts_with(p)).into()),
+        (Value::String(s), "endswith") => Ok(arg(0).is_some_and(|p| s.ends_with(p)).into()),
+        (Value::String(s), "split") => Ok(match arg(0) {
+            Some(sep) => s.split(sep).map(Value::from).collect(),
+            None => s.split_whitespace().map(Value::from).collect(),
+        }),
+        (Value::String(s), "replace") => match (arg(0), arg(1)) {
+            (Some(from), Some(to)) => Ok(s.replace(from, to).into()),
+            _ => Err(error!("replace takes two strings")),
+        },
+        (Value::Object(map), "get") => Ok(arg(0)
+            .and_then(|key| map.get(key))
+            .or(args.get(1))
+            .cloned()
+            .unwrap_or(Value::Null)),
+        (Value::Object(map), "items") => Ok(map
+            .iter()
+            .map(|(k, v)| Value::Array(vec![k.clone().into(), v.clone()]))
+            .collect()),
+        (Value::Object(map), "keys") => Ok(map.keys().cloned().map(Value::from).collect()),
+        (Value::Object(map), "values") => Ok(map.values().cloned().collect()),
+        (obj, method) => Err(error!("unsupported method {method} on {obj}")),
+    }
+}
+
+fn filter(value: Value, name: &str, args: &[Value]) -> Result<Value> {
+    match (name, &value) {
+        ("trim", Value::String(s)) => Ok(s.trim().into()),
+        ("upper", Value::String(s)) => Ok(s.to_uppercase().into()),
+        ("lower", Value::String(s)) => Ok(s.to_lowercase().into()),
+        ("length" | "count", Value::String(s)) => Ok(s.chars().count().into()),
+        ("length" | "count", Value::Array(items)) => Ok(items.len().into()),
+        ("length" | "count", Value::Object(map)) => Ok(map.len().into()),
+        ("string", value) => Ok(display(value).into()),
+        ("tojson", value) => Ok(value.to_string().into()),
+        ("first", Value::Array(items)) => Ok(items.first().cloned().unwrap_or(Value::Null)),
+        ("last", Value::Array(items)) => Ok(items.last().cloned().unwrap_or(Value::Null)),
+        ("join", Value::Array(items)) => {
+            let sep = args.first().map(display).unwrap_or_default();
+            Ok(items
+                .iter()
+                .map(display)
+                .collect::<Vec<_>>()
+                .join(&sep)
+                .into())
+        }
+        ("default" | "d", Value::Null) => Ok(args.first().cloned().unwrap_or_default()),
+        ("default" | "d", _) => Ok(value),
+        (name, value) => Err(error!("unsupported filter {name} on {value}")),
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Bool(true) => "True".into(),
+        Value::Bool(false) => "False".into(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[test]
+fn test_render() {
+    let source = r#"
+{%- for message in messages %}
+    {%- if message['role'] == 'system' %}
+{{ '<<SYS>>' ~ message.content | trim ~ '<</SYS>>' }}
+    {% elif loop.index0 % 2 == 1 and message.role != 'user' %}
+        {{- raise_exception('roles must alternate') }}
+    {%- else %}
+[{{ loop.index }}/{{ loop.length }}] {{ message['content'][:3] + ('!' if loop.last else '') }}
+    {% endif %}
+{%- endfor %}
+{%- if add_generation_prompt is defined and add_generation_prompt %}{{ '> ' }}{% endif %}"#;
+    let template = Template::new(source).unwrap();
+
+    let render = |messages: Value| {
+        template.render(serde_json::json!({ "messages": messages, "add_generation_prompt": true }))
+    };
+    let messages = serde_json::json!([
+        { "role": "system", "content": " be nice " },
+        { "role": "user", "content": "hello" },
+        { "role": "system", "content": "bye" },
+    ]);
+    assert_eq!(
+        render(messages).unwrap(),
+        "<<SYS>>be nice<</SYS>>\n[2/3] hel\n<<SYS>>bye<</SYS>>\n> "
+    );
+
+    let messages = serde_json::json!([
+        { "role": "user", "content": "hi" },
+        { "role": "assistant", "content": "hey" },
+    ]);
+    assert_eq!(
+        render(messages),
+        Err(TemplateError("roles must alternate".into()))
+    );
+}
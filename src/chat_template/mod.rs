@@ -0,0 +1,309 @@
+﻿mod jinja;
+
+use crate::tokenizer::{utok, Tokenizer};
+use jinja::Template;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+pub use jinja::TemplateError;
+
+/// 对话中的一条消息，`role` 通常为 `system`、`user` 或 `assistant`。
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    #[inline]
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+}
+
+/// 把对话消息格式化为模型训练时使用的提示词。
+///
+/// 模板是 huggingface `tokenizer_config.json` 中 `chat_template` 使用的 jinja 子集，
+/// 内置的 llama2、chatml、zephyr 和 alpaca 模板也以同样的方式实现。
+pub struct ChatTemplate {
+    template: Template,
+    bos_token: String,
+    eos_token: String,
+    /// 渲染结果不以 `bos_token` 开头时，编码时是否在开头添加 bos。
+    add_bos: bool,
+    /// 出现这些文本时认为回答结束。
+    stops: Vec<String>,
+}
+
+const LLAMA2: &str = r#"
+{%- if messages[0]['role'] == 'system' %}
+    {%- set loop_messages = messages[1:] %}
+    {%- set system_message = '<<SYS>>\n' + messages[0]['content'].strip() + '\n<</SYS>>\n\n' %}
+{%- else %}
+    {%- set loop_messages = messages %}
+    {%- set system_message = '' %}
+{%- endif %}
+{%- for message in loop_messages %}
+    {%- if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}
+        {{- raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}
+    {%- endif %}
+    {%- if message['role'] == 'user' %}
+        {%- set content = system_message + message['content'].strip() if loop.first else message['content'].strip() %}
+        {{- bos_token + '[INST] ' + content + ' [/INST]' }}
+    {%- else %}
+        {{- ' ' + message['content'].strip() + ' ' + eos_token }}
+    {%- endif %}
+{%- endfor %}"#;
+
+const CHATML: &str = r#"
+{%- for message in messages %}
+    {{- '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>\n' }}
+{%- endfor %}
+{%- if add_generation_prompt %}
+    {{- '<|im_start|>assistant\n' }}
+{%- endif %}"#;
+
+const ZEPHYR: &str = r#"
+{%- for message in messages %}
+    {{- '<|' + message['role'] + '|>\n' + message['content'] | trim + eos_token + '\n' }}
+{%- endfor %}
+{%- if add_generation_prompt %}
+    {{- '<|assistant|>\n' }}
+{%- endif %}"#;
+
+const ALPACA: &str = r#"
+{%- for message in messages %}
+    {%- if message['role'] == 'system' %}
+        {{- message['content'] | trim + '\n\n' }}
+    {%- elif message['role'] == 'user' %}
+        {{- '### Instruction:\n' + message['content'] | trim + '\n\n' }}
+    {%- elif message['role'] == 'assistant' %}
+        {{- '### Response:\n' + message['content'] | trim + eos_token + '\n\n' }}
+    {%- else %}
+        {{- raise_exception('Unknown role: ' + message['role']) }}
+    {%- endif %}
+{%- endfor %}
+{%- if add_generation_prompt %}
+    {{- '### Response:\n' }}
+{%- endif %}"#;
+
+impl ChatTemplate {
+    /// 使用 jinja 模板，`bos_token` 和 `eos_token` 是模板中同名变量的值，编码时替换为对应的 token。
+    pub fn new(
+        source: &str,
+        bos_token: impl Into<String>,
+        eos_token: impl Into<String>,
+    ) -> Result<Self, TemplateError> {
+        let eos_token = eos_token.into();
+        Ok(Self {
+            template: Template::new(source)?,
+            bos_token: bos_token.into(),
+            stops: vec![eos_token.clone()],
+            eos_token,
+            add_bos: true,
+        })
+    }
+
+    fn builtin(source: &str, add_bos: bool, stops: &[&str]) -> Self {
+        Self {
+            template: Template::new(source).unwrap(),
+            bos_token: "<s>".into(),
+            eos_token: "</s>".into(),
+            add_bos,
+            stops: stops.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// llama2-chat 的 `[INST]` 格式。
+    pub fn llama2() -> Self {
+        Self::builtin(LLAMA2, true, &["[INST]"])
+    }
+
+    /// `<|im_start|>` 和 `<|im_end|>` 包围每条消息的 chatml 格式。
+    pub fn chatml() -> Self {
+        Self::builtin(CHATML, false, &["<|im_end|>", "<|im_start|>"])
+    }
+
+    /// zephyr 和 TinyLlama-Chat 的 `<|user|>` 格式。
+    pub fn zephyr() -> Self {
+        Self::builtin(ZEPHYR, true, &["</s>", "<|"])
+    }
+
+    /// alpaca 的 `### Instruction:` 格式。
+    pub fn alpaca() -> Self {
+        Self::builtin(ALPACA, true, &["### Instruction:", "### Response:"])
+    }
+
+    /// 按名字选择内置模板。
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "llama2" => Some(Self::llama2()),
+            "chatml" => Some(Self::chatml()),
+            "zephyr" => Some(Self::zephyr()),
+            "alpaca" => Some(Self::alpaca()),
+            _ => None,
+        }
+    }
+
+    /// 加载内置模板的名字、`tokenizer_config.json` 或 jinja 模板文件。
+    pub fn load(name: impl AsRef<str>) -> Self {
+        let name = name.as_ref();
+        if let Some(template) = Self::by_name(name) {
+            template
+        } else if name.ends_with(".json") {
+            Self::from_tokenizer_config(name).expect("no chat_template in tokenizer config")
+        } else {
+            let source = std::fs::read_to_string(name).unwrap();
+            Self::new(&source, "<s>", "</s>")
+                .unwrap_or_else(|e| panic!("invalid chat template: {e}"))
+        }
+    }
+
+    /// 读取 huggingface `tokenizer_config.json` 中的 `chat_template`，没有模板时返回 `None`。
+    pub fn from_tokenizer_config(path: impl AsRef<Path>) -> Option<Self> {
+        let config = std::fs::read(path).unwrap();
+        let config = serde_json::from_slice::<Value>(&config).unwrap();
+        // 特殊 token 可能是字符串，也可能是带 `content` 字段的对象
+        let token = |key: &str| match &config[key] {
+            Value::String(s) => s.clone(),
+            value => value["content"].as_str().unwrap_or_default().to_string(),
+        };
+        let source = match &config["chat_template"] {
+            Value::String(s) => s.as_str(),
+            // 多个具名模板时使用 `default`
+            Value::Array(templates) => templates
+                .iter()
+                .find(|t| t["name"] == "default")
+                .or(templates.first())?["template"]
+                .as_str()?,
+            _ => return None,
+        };
+        let mut template = Self::new(source, token("bos_token"), token("eos_token"))
+            .unwrap_or_else(|e| panic!("invalid chat_template: {e}"));
+        template.add_bos = config["add_bos_token"].as_bool().unwrap_or(true);
+        Some(template)
+    }
+
+    /// 为模型选择模板：模型所在目录的 `tokenizer_config.json` 中有 `chat_template` 时使用它，否则使用 zephyr 模板。
+    pub fn for_model(checkpoint: impl AsRef<Path>) -> Self {
+        let checkpoint = checkpoint.as_ref();
+        let dir = if checkpoint.is_dir() {
+            checkpoint
+        } else {
+            checkpoint.parent().unwrap_or(Path::new("."))
+        };
+        let config = dir.join("tokenizer_config.json");
+        config
+            .is_file()
+            .then(|| Self::from_tokenizer_config(config))
+            .flatten()
+            .unwrap_or_else(Self::zephyr)
+    }
+
+    /// 出现这些文本时认为回答结束。
+    #[inline]
+    pub fn stops(&self) -> &[String] {
+        &self.stops
+    }
+
+    /// 渲染对话，`add_generation_prompt` 时在末尾添加助手回答的开头。
+    pub fn render(
+        &self,
+        messages: &[ChatMessage],
+        add_generation_prompt: bool,
+    ) -> Result<String, TemplateError> {
+        let messages = messages
+            .iter()
+            .map(|m| serde_json::json!({ "role": m.role, "content": m.content }))
+            .collect::<Vec<_>>();
+        self.template.render(serde_json::json!({
+            "messages": messages,
+            "add_generation_prompt": add_generation_prompt,
+            "bos_token": self.bos_token,
+            "eos_token": self.eos_token,
+        }))
+    }
+
+    /// 渲染并编码对话，文本中的 `bos_token` 和 `eos_token` 编码为分词器的控制 token。
+    pub fn encode(
+        &self,
+        tokenizer: &dyn Tokenizer,
+        messages: &[ChatMessage],
+        add_generation_prompt: bool,
+    ) -> Result<Vec<utok>, TemplateError> {
+        let text = self.render(messages, add_generation_prompt)?;
+        // 词表中有完整的特殊 token 时（例如 llama3 的 `<|eot_id|>`）直接使用，否则使用分词器的 bos/eos
+        let special = |token: &str, default: utok| match &*tokenizer.encode(token, false, false) {
+            &[id] => id,
+            _ => default,
+        };
+        let mut specials = Vec::new();
+        if !self.bos_token.is_empty() {
+            specials.push((
+                self.bos_token.as_str(),
+                special(&self.bos_token, tokenizer.bos()),
+            ));
+        }
+        if !self.eos_token.is_empty() {
+            specials.push((
+                self.eos_token.as_str(),
+                special(&self.eos_token, tokenizer.eos()),
+            ));
+        }
+
+        let mut tokens = Vec::new();
+        if self.add_bos && (self.bos_token.is_empty() || !text.starts_with(&self.bos_token)) {
+            tokens.push(tokenizer.bos());
+        }
+        let mut rest = text.as_str();
+        while !rest.is_empty() {
+            let next = specials
+                .iter()
+                .filter_map(|&(s, id)| rest.find(s).map(|i| (i, s.len(), id)))
+                .min_by_key(|&(i, len, _)| (i, std::cmp::Reverse(len)));
+            let (end, len, id) =
+                next.map_or((rest.len(), 0, None), |(i, len, id)| (i, len, Some(id)));
+            if end > 0 {
+                tokens.extend(tokenizer.encode(&rest[..end], false, false));
+            }
+            tokens.extend(id);
+            rest = &rest[end + len..];
+        }
+        Ok(tokens)
+    }
+}
+
+#[test]
+fn test_builtin() {
+    let messages = [
+        ChatMessage::new("system", "Be brief."),
+        ChatMessage::new("user", "Hi"),
+        ChatMessage::new("assistant", "Hello!"),
+        ChatMessage::new("user", "Who are you?"),
+    ];
+    assert_eq!(
+        ChatTemplate::llama2().render(&messages, true).unwrap(),
+        "<s>[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s><s>[INST] Who are you? [/INST]"
+    );
+    assert_eq!(
+        ChatTemplate::chatml()
+            .render(&messages[1..2], true)
+            .unwrap(),
+        "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+    );
+    assert_eq!(
+        ChatTemplate::zephyr().render(&messages[..2], true).unwrap(),
+        "<|system|>\nBe brief.</s>\n<|user|>\nHi</s>\n<|assistant|>\n"
+    );
+    assert_eq!(
+        ChatTemplate::alpaca()
+            .render(&messages[1..3], false)
+            .unwrap(),
+        "### Instruction:\nHi\n\n### Response:\nHello!</s>\n\n"
+    );
+    assert!(ChatTemplate::llama2().render(&messages[2..], true).is_err());
+}
@@ -8,7 +8,7 @@ macro_rules! slice {
 
 pub(crate) use slice;
 
-pub(crate) fn rmsnorm(o: &mut [f32], x: &[f32], weight: &[f32]) {
+pub(crate) fn rmsnorm(o: &mut [f32], x: &[f32], weight: &[f32], eps: f32) {
     let n = weight.len();
     let lines = x.len() / n;
 
@@ -18,29 +18,61 @@ pub(crate) fn rmsnorm(o: &mut [f32], x: &[f32], weight: &[f32]) {
     for i in 0..lines {
         let o = &mut slice!(o; n; [i]);
         let x = &slice!(x; n; [i]);
-        let ss = rmsnorm_reduce(x);
+        let ss = rmsnorm_reduce(x, eps);
         zip(o, zip(x, weight)).for_each(|(o, (x, w))| *o = w * (ss * x));
     }
 }
 
-pub(crate) fn rmsnorm_inplace(x: &mut [f32], weight: &[f32]) {
+/// gemma 的 rmsnorm，权重保存为与 1 的差：`o = x / rms(x) * (1 + w)`。
+pub(crate) fn rmsnorm_unit_offset(o: &mut [f32], x: &[f32], weight: &[f32], eps: f32) {
     let n = weight.len();
     let lines = x.len() / n;
 
+    debug_assert_eq!(o.len(), x.len());
     debug_assert_eq!(x.len() % n, 0);
 
     for i in 0..lines {
-        let x = &mut slice!(x; n; [i]);
-        let ss = rmsnorm_reduce(x);
-        zip(x, weight).for_each(|(x, w)| *x *= w * ss);
+        let o = &mut slice!(o; n; [i]);
+        let x = &slice!(x; n; [i]);
+        let ss = rmsnorm_reduce(x, eps);
+        zip(o, zip(x, weight)).for_each(|(o, (x, w))| *o = (1. + w) * (ss * x));
+    }
+}
+
+/// `o = (x - mean(x)) / sqrt(var(x) + eps) * w + b`。
+pub(crate) fn layernorm(o: &mut [f32], x: &[f32], weight: &[f32], bias: Option<&[f32]>, eps: f32) {
+    let n = weight.len();
+    let lines = x.len() / n;
+
+    debug_assert_eq!(o.len(), x.len());
+    debug_assert_eq!(x.len() % n, 0);
+
+    for i in 0..lines {
+        let o = &mut slice!(o; n; [i]);
+        let x = &slice!(x; n; [i]);
+        let mean = x.iter().sum::<f32>() / n as f32;
+        let var = x.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / n as f32;
+        let ss = (var + eps).powf(-0.5);
+        zip(&mut *o, zip(x, weight)).for_each(|(o, (x, w))| *o = w * (ss * (x - mean)));
+    }
+    add_bias(o, bias);
+}
+
+/// 每行加上偏置，`bias` 为 `None` 时不做任何事。
+pub(crate) fn add_bias(x: &mut [f32], bias: Option<&[f32]>) {
+    if let Some(bias) = bias {
+        debug_assert_eq!(x.len() % bias.len(), 0);
+        for x in x.chunks_exact_mut(bias.len()) {
+            zip(x, bias).for_each(|(x, b)| *x += b);
+        }
     }
 }
 
 #[inline]
-fn rmsnorm_reduce(x: &[f32]) -> f32 {
+fn rmsnorm_reduce(x: &[f32], eps: f32) -> f32 {
     // (Σx^2 / n + δ)^(-1/2)
     let y = x.iter().map(|x| x * x).sum::<f32>();
-    (y / (x.len() as f32) + 1e-5).powf(-0.5)
+    (y / (x.len() as f32) + eps).powf(-0.5)
 }
 
 /// c := α. a: <mxk> . b: <kxn> + β. c: <mxn>
@@ -101,3 +133,10 @@ pub(crate) fn softmax(x: &mut [f32]) {
 pub(crate) fn sigmoid(x: f32) -> f32 {
     1. / (1. + (-x).exp())
 }
+
+/// tanh 近似的 gelu。
+#[inline]
+pub(crate) fn gelu(x: f32) -> f32 {
+    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    0.5 * x * (1. + (SQRT_2_OVER_PI * (x + 0.044715 * x * x * x)).tanh())
+}
@@ -1,12 +1,20 @@
 ﻿mod arguments;
+mod chat_template;
 mod kernel;
 mod log;
 mod sampler;
+mod session;
+mod speculative;
 mod tokenizer;
 mod transformer;
 
-pub use arguments::{Arguments, SafeTensors};
+pub use arguments::{Activation, Architecture, Arguments, Lora, Norm, RopeScaling, SafeTensors};
+pub use chat_template::{ChatMessage, ChatTemplate, TemplateError};
 pub use log::{FsLogger, Logger};
 pub use sampler::Sampler;
-pub use tokenizer::{BpeTokenizer, LongestPrefix, Tokenizer, BOS, EOS};
-pub use transformer::Transformer;
+pub use session::Session;
+pub use speculative::Speculative;
+pub use tokenizer::{
+    read_tokenizer, BpeTokenizer, BpeTrainer, LongestPrefix, Normalizer, Tiktoken, Tokenizer,
+};
+pub use transformer::{KvCache, KvDtype, PositionError, PrefixCache, Transformer};
@@ -1,4 +1,5 @@
 ﻿use super::{kernel::softmax, tokenizer::utok};
+use std::iter::zip;
 
 pub struct Sampler {
     temperature: f32,
@@ -17,6 +18,17 @@ impl Sampler {
         }
     }
 
+    /// 随机数发生器的当前状态，保存后用 [`Sampler::set_rng_state`] 恢复可以得到相同的采样序列。
+    #[inline]
+    pub fn rng_state(&self) -> u64 {
+        self.rng_state
+    }
+
+    #[inline]
+    pub fn set_rng_state(&mut self, rng_state: u64) {
+        self.rng_state = rng_state;
+    }
+
     pub fn sample(&mut self, logits: &mut [f32]) -> utok {
         if self.temperature == 0.0 {
             logits
@@ -40,6 +52,62 @@ impl Sampler {
         }
     }
 
+    /// 把 `logits` 原地转换为 [`Sampler::sample`] 实际采样的概率分布：温度为 0 时是最大值处的独热分布，
+    /// 否则按温度做 softmax，再只保留 top-p 的部分并重新归一化。
+    pub fn probabilities(&mut self, logits: &mut [f32]) {
+        if self.temperature == 0.0 {
+            let (max, _) = logits
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+                .unwrap();
+            logits.fill(0.);
+            logits[max] = 1.;
+        } else {
+            for logit in logits.iter_mut() {
+                *logit /= self.temperature;
+            }
+            softmax(logits);
+            if (0.0..=1.0).contains(&self.top_p) {
+                let cumulative_prob = nucleus(logits, self.top_p, &mut self.probindex);
+                logits.fill(0.);
+                for prob in self.probindex.iter() {
+                    logits[prob.index as usize] = prob.prob / cumulative_prob;
+                }
+            }
+        }
+    }
+
+    /// 从 [`Sampler::probabilities`] 得到的分布中采样。
+    #[inline]
+    pub fn sample_probabilities(&mut self, probs: &[f32]) -> utok {
+        let coin = self.random_f32();
+        sample_mult(probs, coin)
+    }
+
+    /// 投机解码的拒绝采样：草稿模型以分布 `q` 提出 `token`，目标模型的分布是 `p`，
+    /// 两者都由 [`Sampler::probabilities`] 得到。
+    ///
+    /// 以 `min(1, p / q)` 的概率接受 `token` 并返回 `None`；拒绝时从 `max(0, p - q)` 归一化的分布中重新采样，
+    /// 这样得到的 token 与直接从 `p` 采样同分布。`p` 会被修改。
+    pub fn verify(&mut self, p: &mut [f32], q: &[f32], token: utok) -> Option<utok> {
+        let (p_, q_) = (p[token as usize], q[token as usize]);
+        if p_ >= q_ || self.random_f32() * q_ < p_ {
+            return None;
+        }
+        let mut sum = 0.;
+        for (p, q) in zip(&mut *p, q) {
+            *p = (*p - q).max(0.);
+            sum += *p;
+        }
+        // 舍入误差使 p 和 q 几乎相同时，剩余的分布是空的
+        if sum <= 0. {
+            return None;
+        }
+        let coin = self.random_f32();
+        Some(sample_mult(p, coin * sum))
+    }
+
     #[inline]
     fn random_f32(&mut self) -> f32 {
         (self.random_u32() >> 8) as f32 / 16777216.0
@@ -50,7 +118,7 @@ impl Sampler {
         self.rng_state ^= self.rng_state >> 12;
         self.rng_state ^= self.rng_state << 25;
         self.rng_state ^= self.rng_state >> 27;
-        ((self.rng_state * 0x2545F4914F6CDD1Du64) >> 32) as _
+        (self.rng_state.wrapping_mul(0x2545F4914F6CDD1D) >> 32) as _
     }
 }
 
@@ -66,6 +134,21 @@ fn sample_mult(logits: &[f32], coin: f32) -> utok {
 }
 
 fn sample_top_p(logits: &[f32], top_p: f32, probindex: &mut Vec<ProbIndex>, coin: f32) -> utok {
+    let cumulative_prob = nucleus(logits, top_p, probindex);
+
+    let r = coin * cumulative_prob;
+    let mut cdf = 0.;
+    for prob in probindex.iter() {
+        cdf += prob.prob;
+        if cdf > r {
+            return prob.index;
+        }
+    }
+    probindex.last().unwrap().index
+}
+
+/// 按概率从大到小把累积概率刚超过 `top_p` 的 token 放入 `probindex`，返回它们的累积概率。
+fn nucleus(logits: &[f32], top_p: f32, probindex: &mut Vec<ProbIndex>) -> f32 {
     probindex.clear();
 
     let cutoff = (1. - top_p) / (logits.len() - 1) as f32;
@@ -87,16 +170,7 @@ fn sample_top_p(logits: &[f32], top_p: f32, probindex: &mut Vec<ProbIndex>, coin
             break;
         }
     }
-
-    let r = coin * cumulative_prob;
-    let mut cdf = 0.;
-    for prob in probindex.iter() {
-        cdf += prob.prob;
-        if cdf > r {
-            return prob.index;
-        }
-    }
-    probindex.last().unwrap().index
+    cumulative_prob
 }
 
 #[derive(Clone, Default)]
@@ -104,3 +178,21 @@ struct ProbIndex {
     prob: f32,
     index: utok,
 }
+
+#[test]
+fn test_verify_distribution() {
+    // 草稿分布 q 与目标分布 p 不同，接受或重新采样得到的 token 仍然服从 p
+    let p = [0.5, 0.3, 0.2, 0.];
+    let q = [0.1, 0.2, 0.3, 0.4];
+    let mut sampler = Sampler::new(4, 1., 1., 42);
+    let mut counts = [0; 4];
+    let n = 100_000;
+    for _ in 0..n {
+        let draft = sampler.sample_probabilities(&q);
+        let token = sampler.verify(&mut p.clone(), &q, draft).unwrap_or(draft);
+        counts[token as usize] += 1;
+    }
+    for (count, p) in zip(counts, p) {
+        assert!((count as f32 / n as f32 - p).abs() < 1e-2, "{counts:?}");
+    }
+}
@@ -0,0 +1,137 @@
+﻿use crate::{tokenizer::utok, ChatMessage, Sampler, Transformer};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8; 4] = b"llss";
+const VERSION: u32 = 1;
+
+/// 可以保存到文件的对话，恢复后不需要重新计算已有的 token。
+///
+/// 文件依次是魔数、版本、随机数状态、消息记录（json）、token 和 kv cache，整数均为小端序。
+pub struct Session {
+    pub messages: Vec<ChatMessage>,
+    /// 已经存入 kv cache 的 token。
+    pub tokens: Vec<utok>,
+    /// 采样器的随机数状态。
+    pub rng_state: u64,
+}
+
+impl Session {
+    /// 记录当前的对话状态，`tokens` 的长度即 kv cache 的长度。
+    pub fn new(messages: Vec<ChatMessage>, tokens: Vec<utok>, sampler: &Sampler) -> Self {
+        Self {
+            messages,
+            tokens,
+            rng_state: sampler.rng_state(),
+        }
+    }
+
+    /// 保存会话和 `transformer` 中对应的 kv cache。
+    pub fn save(&self, transformer: &Transformer, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&self.rng_state.to_le_bytes())?;
+
+        let messages = serde_json::to_vec(&self.messages)?;
+        w.write_all(&(messages.len() as u32).to_le_bytes())?;
+        w.write_all(&messages)?;
+
+        w.write_all(&(self.tokens.len() as u32).to_le_bytes())?;
+        for token in &self.tokens {
+            w.write_all(&token.to_le_bytes())?;
+        }
+        transformer.write_cache(self.tokens.len(), &mut w)?;
+        w.flush()
+    }
+
+    /// 加载会话，kv cache 写入 `transformer`。
+    pub fn load(transformer: &mut Transformer, path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u32(&mut r)? != VERSION {
+            return Err(invalid_data("not a session file"));
+        }
+        let mut rng_state = [0u8; 8];
+        r.read_exact(&mut rng_state)?;
+
+        let mut messages = vec![0u8; read_u32(&mut r)? as usize];
+        r.read_exact(&mut messages)?;
+        let messages = serde_json::from_slice(&messages)?;
+
+        let tokens = (0..read_u32(&mut r)?)
+            .map(|_| read_u32(&mut r))
+            .collect::<io::Result<Vec<_>>>()?;
+        if transformer.read_cache(&mut r)? != tokens.len() {
+            return Err(invalid_data("kv cache does not match the tokens"));
+        }
+        Ok(Self {
+            messages,
+            tokens,
+            rng_state: u64::from_le_bytes(rng_state),
+        })
+    }
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[inline]
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[test]
+fn test_save_load() {
+    use crate::arguments::TinyModel;
+
+    let model = TinyModel::new(serde_json::json!({}), 0x5e55);
+    let mut transformer = model.load("session-model");
+    let mut sampler = Sampler::new(transformer.vocab_size(), 1., 0.9, 42);
+    let mut tokens = vec![1, 5, 9, 13];
+    transformer.update(&tokens, 0, &mut ()).unwrap();
+    // 采样几个 token 推进随机数状态，`next` 是还没有存入 kv cache 的 token
+    let mut next = 17;
+    for _ in 0..3 {
+        let logits = transformer
+            .forward(next, tokens.len() as _, &mut ())
+            .unwrap();
+        tokens.push(next);
+        next = sampler.sample(logits);
+    }
+
+    let messages = vec![
+        ChatMessage::new("system", "be brief"),
+        ChatMessage::new("user", "hi"),
+    ];
+    let session = Session::new(messages.clone(), tokens.clone(), &sampler);
+    let path = std::env::temp_dir().join(format!("llama2-rs-session-{}", std::process::id()));
+    session.save(&transformer, &path).unwrap();
+
+    let mut loaded = model.load("session-loaded");
+    let restored = Session::load(&mut loaded, &path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(restored.messages, messages);
+    assert_eq!(restored.tokens, tokens);
+    assert_eq!(restored.rng_state, sampler.rng_state());
+    assert_eq!(loaded.cache_len(), tokens.len());
+
+    // 恢复的 kv cache 与原来的相同，继续推理得到相同的 logits 和采样结果
+    let mut restored_sampler = Sampler::new(loaded.vocab_size(), 1., 0.9, 0);
+    restored_sampler.set_rng_state(restored.rng_state);
+    let pos = tokens.len() as _;
+    let expected = transformer.forward(next, pos, &mut ()).unwrap().to_vec();
+    let actual = loaded.forward(next, pos, &mut ()).unwrap().to_vec();
+    assert_eq!(actual, expected);
+    assert_eq!(
+        restored_sampler.sample(&mut actual.clone()),
+        sampler.sample(&mut expected.clone())
+    );
+}
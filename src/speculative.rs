@@ -0,0 +1,166 @@
+﻿use crate::{log::Logger, tokenizer::utok, PositionError, Sampler, Transformer};
+use std::iter::zip;
+
+/// 投机解码：小的草稿模型逐个提出 `draft_len` 个 token，目标模型一次推理验证所有草稿，
+/// 按 [`Sampler::verify`] 拒绝采样，输出的 token 与只用目标模型采样同分布。
+///
+/// 验证之后两个模型的 kv cache 都用 [`Transformer::truncate`] 回退到接受的位置。
+pub struct Speculative {
+    draft: Transformer,
+    draft_len: usize,
+    /// 已经接受但还没有输入草稿模型的 token。
+    pending: Vec<utok>,
+    proposed: usize,
+    accepted: usize,
+}
+
+impl Speculative {
+    /// 草稿模型需要与目标模型使用同一个词表，上下文长度不小于目标模型。
+    pub fn new(draft: Transformer, draft_len: usize) -> Self {
+        assert!(draft_len > 0);
+        Self {
+            draft,
+            draft_len,
+            pending: Vec::new(),
+            proposed: 0,
+            accepted: 0,
+        }
+    }
+
+    /// 被目标模型接受的草稿 token 的比例。
+    pub fn acceptance_rate(&self) -> f32 {
+        self.accepted as f32 / self.proposed.max(1) as f32
+    }
+
+    /// 从位置 0 向目标模型和草稿模型输入 `tokens`，通常是提示词除了最后一个 token 的部分。
+    pub fn prefill(
+        &mut self,
+        target: &mut Transformer,
+        tokens: &[utok],
+        logger: &mut impl Logger,
+    ) -> Result<(), PositionError> {
+        self.pending.clear();
+        if !tokens.is_empty() {
+            target.update(tokens, 0, logger)?;
+            self.draft.update(tokens, 0, logger)?;
+        }
+        Ok(())
+    }
+
+    /// 两个模型的 kv cache 中已经有 `pos` 之前的 token，从 `pos` 输入 `token`，
+    /// 返回接受的草稿 token 和最后一个由目标模型采样的 token，至少一个、至多 `draft_len + 1` 个。
+    ///
+    /// 下一步从 `pos` 加返回的长度开始输入返回的最后一个 token，草稿不会超出目标模型的上下文长度。
+    /// 任何一个模型不能从 `pos` 继续推理时报错。
+    pub fn step(
+        &mut self,
+        target: &mut Transformer,
+        sampler: &mut Sampler,
+        token: utok,
+        pos: usize,
+        logger: &mut impl Logger,
+    ) -> Result<Vec<utok>, PositionError> {
+        let vocab_size = target.vocab_size();
+        assert_eq!(self.draft.vocab_size(), vocab_size);
+        assert!(self.draft.seq_len() >= target.seq_len());
+        assert!(pos < target.seq_len());
+        let k = self.draft_len.min(target.seq_len() - pos - 1);
+
+        // 草稿模型逐个提出 k 个 token，记录每个 token 的采样分布
+        let mut drafts = Vec::with_capacity(k);
+        let mut q = vec![0.; k * vocab_size];
+        if k > 0 {
+            self.pending.push(token);
+            let begin = pos + 1 - self.pending.len();
+            let logits = self.draft.forward_all(&self.pending, begin as _, logger)?;
+            q[..vocab_size].copy_from_slice(&logits[logits.len() - vocab_size..]);
+            for i in 0..k {
+                let (q, rest) = q[i * vocab_size..].split_at_mut(vocab_size);
+                sampler.probabilities(q);
+                let next = sampler.sample_probabilities(q);
+                drafts.push(next);
+                if i + 1 < k {
+                    let logits = self.draft.forward(next, (pos + 1 + i) as _, logger)?;
+                    rest[..vocab_size].copy_from_slice(logits);
+                }
+            }
+        }
+
+        // 目标模型一次计算所有草稿位置的分布，依次验证
+        let mut inputs = vec![token];
+        inputs.extend_from_slice(&drafts);
+        let mut p = target.forward_all(&inputs, pos as _, logger)?;
+        let mut tokens = Vec::with_capacity(k + 1);
+        let mut accepted = 0;
+        for ((p, q), &draft) in zip(p.chunks_mut(vocab_size), q.chunks(vocab_size)).zip(&drafts) {
+            sampler.probabilities(p);
+            match sampler.verify(p, q, draft) {
+                None => {
+                    tokens.push(draft);
+                    accepted += 1;
+                }
+                Some(next) => {
+                    tokens.push(next);
+                    break;
+                }
+            }
+        }
+        // 所有草稿都被接受时，从最后一个位置的分布中再采样一个 token
+        if accepted == k {
+            let p = &mut p[k * vocab_size..];
+            sampler.probabilities(p);
+            tokens.push(sampler.sample_probabilities(p));
+        }
+        self.proposed += k;
+        self.accepted += accepted;
+
+        // 目标模型丢弃被拒绝的草稿；草稿模型输入了 `token` 和前 k - 1 个草稿，全部接受时还缺最后一个草稿
+        target.truncate(pos + accepted + 1)?;
+        self.pending.clear();
+        if k == 0 {
+            self.pending.push(token);
+        } else if accepted == k {
+            self.pending.push(drafts[k - 1]);
+        } else {
+            self.draft.truncate(pos + accepted + 1)?;
+        }
+        Ok(tokens)
+    }
+}
+
+#[test]
+fn test_greedy_matches_target() {
+    use crate::arguments::TinyModel;
+
+    // 温度为 0 时投机解码的输出与只用目标模型贪心解码相同，一直生成到填满上下文
+    let model = TinyModel::new(serde_json::json!({}), 0x7a6e);
+    let mut target = model.load("speculative-target");
+    let mut sampler = Sampler::new(target.vocab_size(), 0., 0.9, 0);
+    let mut expected = vec![1];
+    for pos in 0..target.seq_len() {
+        let logits = target.forward(expected[pos], pos as _, &mut ()).unwrap();
+        expected.push(sampler.sample(logits));
+    }
+
+    // 草稿模型与目标模型相同时全部接受，不同时有草稿被拒绝
+    for (name, seed) in [("same", 0x7a6e), ("other", 0xd4af)] {
+        let draft = TinyModel::new(serde_json::json!({}), seed);
+        let draft = draft.load(&format!("speculative-{name}"));
+        let mut speculative = Speculative::new(draft, 4);
+        let mut tokens = vec![1];
+        while tokens.len() < expected.len() {
+            let pos = tokens.len() - 1;
+            let next = speculative
+                .step(&mut target, &mut sampler, tokens[pos], pos, &mut ())
+                .unwrap();
+            tokens.extend(next);
+        }
+        assert_eq!(tokens, expected);
+        let rate = speculative.acceptance_rate();
+        if seed == 0x7a6e {
+            assert_eq!(rate, 1.);
+        } else {
+            assert!(rate < 1., "acceptance rate: {rate}");
+        }
+    }
+}
@@ -1,4 +1,4 @@
-﻿use super::{utok, Tokenizer, BOS, EOS};
+﻿use super::{utok, Normalizer, Tokenizer, BOS, EOS};
 use memmap2::Mmap;
 use std::{fs::File, path::Path};
 
@@ -11,6 +11,7 @@ pub struct BpeTokenizer {
     /// 保存根据 token 字符串字典序排序的序号，用于从 token 字符串查询序号。
     sorted_indices: Vec<utok>,
     byte_pieces: [u8; 256],
+    normalizer: Normalizer,
 }
 
 impl BpeTokenizer {
@@ -34,6 +35,7 @@ impl BpeTokenizer {
             words_offset,
             sorted_indices,
             byte_pieces: [0; 256],
+            normalizer: Normalizer::default(),
         };
         for i in 0..=255u8 {
             ans.byte_pieces[i as usize] = i;
@@ -41,10 +43,17 @@ impl BpeTokenizer {
         ans
     }
 
-    #[allow(dead_code)]
     #[inline]
-    pub fn max_token_len(&self) -> usize {
-        (unsafe { *self.mmap.as_ptr().cast::<u32>() }) as _
+    pub fn normalizer(&self) -> &Normalizer {
+        &self.normalizer
+    }
+
+    /// 替换编码前的规范化配置。
+    ///
+    /// `tokenizer.bin` 中的词已经用空格表示，`space_replacement` 不起作用。
+    #[inline]
+    pub fn with_normalizer(self, normalizer: Normalizer) -> Self {
+        Self { normalizer, ..self }
     }
 
     #[inline]
@@ -62,6 +71,36 @@ impl BpeTokenizer {
 }
 
 impl Tokenizer for BpeTokenizer {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.words_offset.len()
+    }
+
+    #[inline]
+    fn max_token_len(&self) -> usize {
+        (unsafe { self.mmap.as_ptr().cast::<u32>().read_unaligned() }) as _
+    }
+
+    #[inline]
+    fn bos(&self) -> utok {
+        BOS
+    }
+
+    #[inline]
+    fn eos(&self) -> utok {
+        EOS
+    }
+
+    #[inline]
+    fn piece(&self, token: utok) -> &str {
+        self.map_str(token)
+    }
+
+    #[inline]
+    fn score(&self, token: utok) -> f32 {
+        file::map(&self.mmap, self.words_offset[token as usize]).1
+    }
+
     fn encode(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
         #[inline(always)]
         const fn byte_index(b: u8) -> utok {
@@ -72,20 +111,21 @@ impl Tokenizer for BpeTokenizer {
         if bos {
             tokens.push(BOS);
         }
-        if !text.is_empty() {
-            tokens.push(self.find_token(" ").unwrap())
-        }
 
-        text.chars().map(|c| c.to_string()).for_each(|c| {
-            if let Some(index) = self.find_token(&c) {
-                tokens.extend([index]);
-            } else {
-                tokens.extend(c.bytes().map(byte_index));
-            }
-        });
+        self.normalizer
+            .normalize(text)
+            .chars()
+            .map(|c| c.to_string())
+            .for_each(|c| {
+                if let Some(index) = self.find_token(&c) {
+                    tokens.extend([index]);
+                } else {
+                    tokens.extend(c.bytes().map(byte_index));
+                }
+            });
 
         loop {
-            let mut best_score = std::f32::NEG_INFINITY;
+            let mut best_score = f32::NEG_INFINITY;
             let mut replacement = None;
             for (i, pair) in tokens.windows(2).enumerate() {
                 let pair = format!("{}{}", self.map_str(pair[0]), self.map_str(pair[1]));
@@ -115,21 +155,20 @@ impl Tokenizer for BpeTokenizer {
         tokens
     }
 
-    fn decode(&self, token: utok, next: utok) -> &str {
+    fn decode(&self, token: utok, next: utok) -> &[u8] {
         let piece = self.map_str(next);
         if let Some(byte) = piece.strip_prefix("<0x").and_then(|s| s.strip_suffix('>')) {
             let byte = u8::from_str_radix(byte, 16).unwrap();
-            let byte = &self.byte_pieces[byte as usize..][..1];
-            unsafe { std::str::from_utf8_unchecked(byte) }
-        } else if token == BOS && piece.starts_with(' ') {
-            &piece[1..]
+            &self.byte_pieces[byte as usize..][..1]
+        } else if token == BOS && self.normalizer.add_dummy_prefix && piece.starts_with(' ') {
+            &piece.as_bytes()[1..]
         } else {
-            piece
+            piece.as_bytes()
         }
     }
 }
 
-mod file {
+pub(super) mod file {
     //! 文件结构：
     //!
     //! ```plain_text
@@ -144,6 +183,7 @@ mod file {
     //! ```
 
     use memmap2::Mmap;
+    use std::io::Write;
 
     #[repr(C)]
     struct TokenHeader {
@@ -170,4 +210,29 @@ mod file {
             header.score,
         )
     }
+
+    /// 依次读出文件中的所有对象。
+    pub fn items(mmap: &Mmap) -> impl Iterator<Item = (&str, f32)> {
+        let mut offset = std::mem::size_of::<u32>();
+        std::iter::from_fn(move || {
+            if offset + std::mem::size_of::<TokenHeader>() > mmap.len() {
+                return None;
+            }
+            let item = map(mmap, offset);
+            offset += item_len(mmap, offset);
+            Some(item)
+        })
+    }
+
+    /// 按文件结构写出词表。
+    pub fn write(pieces: &[(String, f32)], stream: &mut dyn Write) -> std::io::Result<()> {
+        let max_token_len = pieces.iter().map(|(p, _)| p.len()).max().unwrap_or(0);
+        stream.write_all(&(max_token_len as u32).to_le_bytes())?;
+        for (piece, score) in pieces {
+            stream.write_all(&score.to_le_bytes())?;
+            stream.write_all(&(piece.len() as u32).to_le_bytes())?;
+            stream.write_all(piece.as_bytes())?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,199 @@
+﻿use super::{bpe32000::file, utok};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    io::Write,
+};
+
+/// 保留的控制 token 和 256 个字节 token 的数量。
+const RESERVED: usize = 3 + 256;
+/// 训练时保留的字符覆盖率，覆盖不到的罕见字符用字节回退表示。
+const CHARACTER_COVERAGE: f64 = 0.9995;
+
+/// 从语料中训练 bpe 词表，产生的词表结构与 `tokenizer.bin` 一致：
+///
+/// - `0`: `<unk>`，`1`: BOS，`2`: EOS；
+/// - `3..259`: 字节回退 token `<0x00>`..`<0xFF>`；
+/// - 之后是覆盖语料的单个字符，以及按合并顺序排列的合并结果。
+///
+/// 除保留 token 外，每个 token 的分数为其序号的相反数，因此越早学到的合并优先级越高。
+pub struct BpeTrainer {
+    pieces: Vec<(String, f32)>,
+}
+
+impl BpeTrainer {
+    pub fn train(corpus: &str, vocab_size: usize) -> Self {
+        assert!(
+            vocab_size > RESERVED,
+            "vocab_size must be greater than {RESERVED}"
+        );
+
+        let mut pieces = Vec::with_capacity(vocab_size);
+        pieces.push(("<unk>".to_string(), 0.));
+        pieces.push(("\n<s>\n".to_string(), 0.));
+        pieces.push(("\n</s>\n".to_string(), 0.));
+        for b in 0..=255u8 {
+            pieces.push((format!("<0x{b:02X}>"), 0.));
+        }
+
+        // 与 `BpeTokenizer::encode` 一致，每个词都以空格开头，合并不会跨越词的边界。
+        let mut word_count = HashMap::<String, usize>::new();
+        for line in corpus.lines() {
+            for word in line.split(' ').filter(|w| !w.is_empty()) {
+                *word_count.entry(format!(" {word}")).or_default() += 1;
+            }
+        }
+        let mut words = word_count.into_iter().collect::<Vec<_>>();
+        words.sort_unstable();
+
+        // 按频率选出覆盖语料的字符。
+        let mut char_count = HashMap::<char, usize>::new();
+        for (word, count) in &words {
+            for c in word.chars() {
+                *char_count.entry(c).or_default() += count;
+            }
+        }
+        let mut chars = char_count.into_iter().collect::<Vec<_>>();
+        chars.sort_unstable_by_key(|&(c, n)| (Reverse(n), c));
+        let total = chars.iter().map(|(_, n)| n).sum::<usize>() as f64;
+
+        let mut vocab = HashMap::<String, utok>::new();
+        let mut covered = 0;
+        for (c, n) in chars {
+            if pieces.len() == vocab_size
+                || (covered as f64 >= total * CHARACTER_COVERAGE && c != ' ')
+            {
+                continue;
+            }
+            covered += n;
+            vocab.insert(c.to_string(), pieces.len() as _);
+            pieces.push((c.to_string(), 0.));
+        }
+        assert!(vocab.contains_key(" "), "vocab_size too small");
+
+        // 把每个词切分成字符 token，覆盖不到的字符用字节 token 表示，字节 token 不参与合并。
+        const BARRIER: utok = utok::MAX;
+        let mut symbols = words
+            .iter()
+            .map(|(word, _)| {
+                word.chars()
+                    .map(|c| *vocab.get(c.to_string().as_str()).unwrap_or(&BARRIER))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut pair_count = HashMap::<(utok, utok), isize>::new();
+        let mut pair_words = HashMap::<(utok, utok), HashSet<usize>>::new();
+        for (i, word) in symbols.iter().enumerate() {
+            for pair in word.windows(2) {
+                if pair[0] != BARRIER && pair[1] != BARRIER {
+                    *pair_count.entry((pair[0], pair[1])).or_default() += words[i].1 as isize;
+                    pair_words.entry((pair[0], pair[1])).or_default().insert(i);
+                }
+            }
+        }
+        let mut heap = pair_count
+            .iter()
+            .map(|(&pair, &n)| (n, Reverse(pair)))
+            .collect::<BinaryHeap<_>>();
+
+        while pieces.len() < vocab_size {
+            // 堆中可能有过期的计数，弹出直到找到与当前计数一致的项。
+            let Some((n, Reverse(pair))) = heap.pop() else {
+                break;
+            };
+            if pair_count.get(&pair) != Some(&n) {
+                continue;
+            }
+            if n <= 0 {
+                break;
+            }
+
+            let merged = format!("{}{}", pieces[pair.0 as usize].0, pieces[pair.1 as usize].0);
+            let index = *vocab.entry(merged.clone()).or_insert_with(|| {
+                pieces.push((merged, 0.));
+                (pieces.len() - 1) as _
+            });
+
+            let mut changed = HashSet::new();
+            for i in pair_words.remove(&pair).unwrap_or_default() {
+                let count = words[i].1 as isize;
+                let word = &mut symbols[i];
+                for p in word.windows(2) {
+                    if p[0] != BARRIER && p[1] != BARRIER {
+                        *pair_count.get_mut(&(p[0], p[1])).unwrap() -= count;
+                        changed.insert((p[0], p[1]));
+                    }
+                }
+                let mut j = 0;
+                while j + 1 < word.len() {
+                    if (word[j], word[j + 1]) == pair {
+                        word[j] = index;
+                        word.remove(j + 1);
+                    }
+                    j += 1;
+                }
+                for p in word.windows(2) {
+                    if p[0] != BARRIER && p[1] != BARRIER {
+                        *pair_count.entry((p[0], p[1])).or_default() += count;
+                        pair_words.entry((p[0], p[1])).or_default().insert(i);
+                        changed.insert((p[0], p[1]));
+                    }
+                }
+            }
+            for pair in changed {
+                heap.push((pair_count[&pair], Reverse(pair)));
+            }
+        }
+
+        for (i, (_, score)) in pieces.iter_mut().enumerate().skip(RESERVED) {
+            *score = -(i as f32);
+        }
+        Self { pieces }
+    }
+
+    #[inline]
+    pub fn pieces(&self) -> &[(String, f32)] {
+        &self.pieces
+    }
+
+    /// 按 `tokenizer.bin` 的文件结构写出词表。
+    #[inline]
+    pub fn write(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        file::write(&self.pieces, stream)
+    }
+}
+
+#[test]
+fn test_train() {
+    use super::{BpeTokenizer, Tokenizer, BOS};
+
+    let corpus = "\
+Once upon a time, there was a little girl named Lily.
+She loved to play outside in the sunshine.
+One day, she saw a big, red ball in the park.
+Lily and her friend played with the ball all day long.
+小猫在公园里玩球。";
+    let trainer = BpeTrainer::train(corpus, 400);
+    assert_eq!(trainer.pieces().len(), 400);
+
+    // 目录在测试结束时删除
+    let dir = crate::arguments::TempDir::new("bpe-train");
+    let path = dir.join("tokenizer.bin");
+    trainer
+        .write(&mut std::fs::File::create(&path).unwrap())
+        .unwrap();
+    let tokenizer = BpeTokenizer::new(&path, trainer.pieces().len());
+
+    for text in ["Lily played with the red ball.", "小狗在公园里玩"] {
+        let tokens = tokenizer.encode(text, true, false);
+
+        let mut decoded = Vec::new();
+        for pair in tokens.windows(2) {
+            decoded.extend_from_slice(tokenizer.decode(pair[0], pair[1]));
+        }
+        assert_eq!(tokens[0], BOS);
+        assert_eq!(String::from_utf8(decoded).unwrap(), text);
+    }
+    assert!(tokenizer.encode("the ball", false, false).len() <= 2);
+}
@@ -1,60 +1,197 @@
-﻿use super::{utok, Tokenizer, BOS, EOS};
+use super::{bpe32000::file, utok, Normalizer, Tokenizer, _UNKNOWN, BOS, EOS};
 use memmap2::Mmap;
 use patricia_tree::PatriciaMap;
-use std::{fs::File, path::Path};
+use serde::Deserialize;
+use std::{ffi::OsStr, fs::File, path::Path};
 
+/// 贪心最长前缀匹配的分词器，不需要合并分数，适合快速统计 token 数量。
 pub struct LongestPrefix {
     words: Vec<String>,
     trie: PatriciaMap<utok>,
     max_piece_len: usize,
+    /// 每个字节对应的回退 token，词表中没有时为 `<unk>`。
+    byte_tokens: [utok; 256],
     byte_pieces: [u8; 256],
+    normalizer: Normalizer,
+    bos: utok,
+    eos: utok,
 }
 
 impl LongestPrefix {
+    /// 根据扩展名选择词表格式：
+    ///
+    /// - `.bin`：与 [`BpeTokenizer`](super::BpeTokenizer) 相同的 `tokenizer.bin`；
+    /// - `.json`：huggingface 的 `tokenizer.json`，读取 `model.vocab`、`added_tokens` 和规范化配置；
+    /// - 其他：每行一个词的纯文本词表，行首尾成对的 `"` 会被去掉。
     pub fn new(tokenizer: impl AsRef<Path>) -> Self {
+        let tokenizer = tokenizer.as_ref();
         let mmap = unsafe { Mmap::map(&File::open(tokenizer).unwrap()) }.unwrap();
-        let text = unsafe { std::str::from_utf8_unchecked(&mmap) };
+        match tokenizer.extension().and_then(OsStr::to_str) {
+            Some("bin") => {
+                Self::from_pieces(file::items(&mmap).map(|(piece, _)| piece.to_string()))
+            }
+            Some("json") => Self::from_tokenizer_json(&mmap),
+            _ => {
+                let text = std::str::from_utf8(&mmap).expect("vocab list is not utf-8");
+                Self::from_pieces(text.lines().map(|line| {
+                    line.strip_prefix('"')
+                        .and_then(|l| l.strip_suffix('"'))
+                        .unwrap_or(line)
+                        .to_string()
+                }))
+            }
+        }
+    }
+
+    /// 从按序号排列的词构造分词器，使用默认的规范化配置。
+    #[inline]
+    pub fn from_pieces(pieces: impl IntoIterator<Item = String>) -> Self {
+        Self::build(pieces, Normalizer::default())
+    }
+
+    #[inline]
+    pub fn normalizer(&self) -> &Normalizer {
+        &self.normalizer
+    }
+
+    /// 替换编码前的规范化配置，词表按新配置重新还原空格。
+    #[inline]
+    pub fn with_normalizer(self, normalizer: Normalizer) -> Self {
+        Self::build(self.words, normalizer)
+    }
 
-        let mut words = Vec::new();
+    fn build(pieces: impl IntoIterator<Item = String>, normalizer: Normalizer) -> Self {
+        let words = pieces
+            .into_iter()
+            .map(|piece| normalizer.denormalize_piece(&piece).into_owned())
+            .collect::<Vec<_>>();
         let mut trie = PatriciaMap::new();
         let mut max_piece_len = 0;
-        for (i, line) in text.lines().into_iter().enumerate() {
-            let piece = line.strip_prefix('"').unwrap().strip_suffix('"').unwrap();
+        for (i, piece) in words.iter().enumerate() {
             max_piece_len = max_piece_len.max(piece.len());
-            words.push(piece.to_string());
-            trie.insert(piece, i as _);
+            // 重复的词保留序号最小的一个
+            if !piece.is_empty() && trie.get(piece).is_none() {
+                trie.insert(piece, i as _);
+            }
         }
+        // 按常见的名字查找控制 token，找不到时使用 sentencepiece 的默认序号
+        let find = |names: &[&str], default: utok| {
+            names
+                .iter()
+                .find_map(|&name| trie.get(name).copied())
+                .unwrap_or(default)
+        };
+        let bos = find(
+            &["\n<s>\n", "<s>", "<|begin_of_text|>", "<|startoftext|>"],
+            BOS,
+        );
+        let eos = find(
+            &["\n</s>\n", "</s>", "<|end_of_text|>", "<|endoftext|>"],
+            EOS,
+        );
         let mut ans = Self {
             words,
             trie,
             max_piece_len,
+            byte_tokens: [_UNKNOWN; 256],
             byte_pieces: [0; 256],
+            normalizer,
+            bos,
+            eos,
         };
         for i in 0..=255u8 {
             ans.byte_pieces[i as usize] = i;
+            if let Some(&tok) = ans.trie.get(format!("<0x{i:02X}>")) {
+                ans.byte_tokens[i as usize] = tok;
+            }
         }
         ans
     }
+
+    fn from_tokenizer_json(json: &[u8]) -> Self {
+        #[derive(serde::Deserialize)]
+        struct Model {
+            vocab: std::collections::HashMap<String, utok>,
+        }
+        #[derive(serde::Deserialize)]
+        struct AddedToken {
+            id: utok,
+            content: String,
+        }
+
+        let json = serde_json::from_slice::<serde_json::Value>(json).unwrap();
+        let normalizer = Normalizer::from_tokenizer_json(&json);
+        let model = Model::deserialize(&json["model"]).unwrap();
+        let added_tokens = match &json["added_tokens"] {
+            serde_json::Value::Null => vec![],
+            tokens => Vec::<AddedToken>::deserialize(tokens).unwrap(),
+        };
+
+        let vocab_size = model
+            .vocab
+            .values()
+            .chain(added_tokens.iter().map(|t| &t.id))
+            .max()
+            .map_or(0, |&max| max as usize + 1);
+        let mut pieces = vec![String::new(); vocab_size];
+        for (piece, id) in model.vocab {
+            pieces[id as usize] = piece;
+        }
+        for token in added_tokens {
+            pieces[token.id as usize] = token.content;
+        }
+        Self::build(pieces, normalizer)
+    }
 }
 
 impl Tokenizer for LongestPrefix {
-    fn encode(&self, mut text: &str, bos: bool, eos: bool) -> Vec<utok> {
-        #[inline(always)]
-        const fn byte_index(b: u8) -> utok {
-            b as utok + 3
-        }
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.words.len()
+    }
+
+    #[inline]
+    fn max_token_len(&self) -> usize {
+        self.max_piece_len
+    }
+
+    #[inline]
+    fn bos(&self) -> utok {
+        self.bos
+    }
+
+    #[inline]
+    fn eos(&self) -> utok {
+        self.eos
+    }
 
+    #[inline]
+    fn piece(&self, token: utok) -> &str {
+        &self.words[token as usize]
+    }
+
+    #[inline]
+    fn score(&self, _: utok) -> f32 {
+        0.
+    }
+
+    fn encode(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
         let mut tokens: Vec<u32> = Vec::<utok>::new();
         if bos {
-            tokens.push(BOS);
-        }
-        if !text.is_empty() {
-            tokens.push(*self.trie.get(" ").unwrap())
+            tokens.push(self.bos);
         }
+        // 前缀空格与正文一起匹配，才能匹配到以空格开头的词
+        let text = self.normalizer.normalize(text);
+        let mut text = &*text;
 
         while !text.is_empty() {
             let piece = if text.len() > self.max_piece_len {
-                &text[..self.max_piece_len]
+                // 截断不能落在字符中间
+                let mut end = self.max_piece_len;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                &text[..end]
             } else {
                 text
             };
@@ -64,30 +201,59 @@ impl Tokenizer for LongestPrefix {
             } else {
                 let mut chars = text.chars();
                 let char = chars.next().unwrap();
-                tokens.extend(char.to_string().bytes().map(byte_index));
+                tokens.extend(
+                    char.to_string()
+                        .bytes()
+                        .map(|b| self.byte_tokens[b as usize]),
+                );
                 text = chars.as_str();
             }
         }
 
         if bos {
-            assert_eq!(tokens[0], BOS);
+            assert_eq!(tokens[0], self.bos);
         }
         if eos {
-            tokens.push(EOS);
+            tokens.push(self.eos);
         }
         tokens
     }
 
-    fn decode(&self, token: utok, next: utok) -> &str {
+    fn decode(&self, token: utok, next: utok) -> &[u8] {
         let piece = self.words[next as usize].as_str();
         if let Some(byte) = piece.strip_prefix("<0x").and_then(|s| s.strip_suffix('>')) {
             let byte = u8::from_str_radix(byte, 16).unwrap();
-            let byte = &self.byte_pieces[byte as usize..][..1];
-            unsafe { std::str::from_utf8_unchecked(byte) }
-        } else if token == BOS && piece.starts_with(' ') {
-            &piece[1..]
+            &self.byte_pieces[byte as usize..][..1]
+        } else if token == self.bos && self.normalizer.add_dummy_prefix && piece.starts_with(' ') {
+            &piece.as_bytes()[1..]
         } else {
-            piece
+            piece.as_bytes()
         }
     }
 }
+
+#[test]
+fn test_encode() {
+    let pieces = ["<unk>", "\n<s>\n", "\n</s>\n"]
+        .into_iter()
+        .map(String::from)
+        .chain((0..=255u8).map(|b| format!("<0x{b:02X}>")))
+        .chain([" ", " 你好", "世界", "ab", "abc"].map(String::from))
+        .collect::<Vec<_>>();
+    let tokenizer = LongestPrefix::from_pieces(pieces);
+    assert_eq!(tokenizer.max_token_len(), 7);
+
+    // 最长的词只有 7 字节，截断会落在 "世界" 的字符中间
+    let text = "你好世界abcab中";
+    let tokens = tokenizer.encode(text, true, false);
+    assert_eq!(
+        tokens,
+        [BOS, 260, 261, 263, 262, 3 + 0xE4, 3 + 0xB8, 3 + 0xAD]
+    );
+
+    let mut decoded = Vec::new();
+    for pair in tokens.windows(2) {
+        decoded.extend_from_slice(tokenizer.decode(pair[0], pair[1]));
+    }
+    assert_eq!(String::from_utf8(decoded).unwrap(), text);
+}
@@ -1,18 +1,64 @@
 ﻿mod bpe32000;
+mod bpe_trainer;
 mod longest_prefix;
+mod normalizer;
+mod tiktoken;
 
 /// `utok` for token id.
 #[allow(non_camel_case_types)]
 pub(super) type utok = u32;
 
-pub const _UNKNOWN: utok = 0;
-pub const BOS: utok = 1;
-pub const EOS: utok = 2;
+use std::{ffi::OsStr, path::Path};
+
+/// sentencepiece 词表中控制 token 的序号。
+pub(crate) const _UNKNOWN: utok = 0;
+pub(crate) const BOS: utok = 1;
+pub(crate) const EOS: utok = 2;
 
 pub trait Tokenizer {
+    fn vocab_size(&self) -> usize;
+    fn max_token_len(&self) -> usize;
+    fn bos(&self) -> utok;
+    fn eos(&self) -> utok;
+    /// 词表中序号 `token` 对应的词，sentencepiece 的 `▁` 已还原为空格。
+    fn piece(&self, token: utok) -> &str;
+    /// 序号 `token` 的合并分数，没有分数的词表返回 0。
+    fn score(&self, token: utok) -> f32;
     fn encode(&self, text: &str, bos: bool, eos: bool) -> Vec<utok>;
-    fn decode(&self, token: utok, next: utok) -> &str;
+    /// `token` 之后的 `next` 解码得到的字节串。
+    ///
+    /// 字节 token 只是 utf-8 字符的一部分，调用者需要拼接之后再转换为字符串。
+    fn decode(&self, token: utok, next: utok) -> &[u8];
 }
 
 pub use bpe32000::BpeTokenizer;
+pub use bpe_trainer::BpeTrainer;
 pub use longest_prefix::LongestPrefix;
+pub use normalizer::Normalizer;
+pub use tiktoken::Tiktoken;
+
+/// 根据扩展名加载分词器：
+///
+/// - `.bin`：llama2.c 的 `tokenizer.bin`；
+/// - `.json`：huggingface 的字节级 bpe `tokenizer.json`，按其中的合并规则合并，其他类型的 `tokenizer.json` 报错；
+/// - 其他：tiktoken 格式的词表，例如 llama3 的 `tokenizer.model`。
+///
+/// 其他格式的文件（例如 sentencepiece 的 `tokenizer.model`）报错。
+pub fn read_tokenizer(tokenizer: impl AsRef<Path>, vocab_size: usize) -> Box<dyn Tokenizer> {
+    let tokenizer = tokenizer.as_ref();
+    match tokenizer.extension().and_then(OsStr::to_str) {
+        Some("bin") => Box::new(BpeTokenizer::new(tokenizer, vocab_size)),
+        Some("json") => Box::new(Tiktoken::from_tokenizer_json(tokenizer)),
+        _ => {
+            let content = std::fs::read(tokenizer)
+                .unwrap_or_else(|_| panic!("Could not open tokenizer {}", tokenizer.display()));
+            assert!(
+                Tiktoken::is_tiktoken(&content),
+                "{} is not a tiktoken vocabulary; sentencepiece models are not supported, \
+                 use tokenizer.bin or a byte-level tokenizer.json instead",
+                tokenizer.display()
+            );
+            Box::new(Tiktoken::new(tokenizer))
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use serde_json::Value;
+use std::borrow::Cow;
+use unicode_normalization::UnicodeNormalization;
+
+/// 编码前对文本的规范化，依次执行：
+///
+/// 1. unicode NFKC 规范化；
+/// 2. 把连续的空格合并为一个，与 huggingface 的 `Replace(" {2,}", " ")` 一致，不影响制表符和换行；
+/// 3. 在文本前添加一个空格（sentencepiece 的 dummy prefix）。
+///
+/// 默认值与 llama2 的分词器一致，只添加前缀空格。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Normalizer {
+    pub nfkc: bool,
+    pub collapse_whitespace: bool,
+    pub add_dummy_prefix: bool,
+    /// 词表中代替空格的字符，sentencepiece 使用 `▁`。
+    ///
+    /// 分词器加载词表时把这个字符还原为空格，因此规范化后的文本总是用空格匹配。
+    pub space_replacement: Option<char>,
+}
+
+impl Default for Normalizer {
+    fn default() -> Self {
+        Self {
+            nfkc: false,
+            collapse_whitespace: false,
+            add_dummy_prefix: true,
+            space_replacement: None,
+        }
+    }
+}
+
+impl Normalizer {
+    /// 从 huggingface `tokenizer.json` 的 `normalizer` 和 `pre_tokenizer` 字段解析规范化配置。
+    pub fn from_tokenizer_json(json: &Value) -> Self {
+        let mut ans = Self {
+            nfkc: false,
+            collapse_whitespace: false,
+            add_dummy_prefix: false,
+            space_replacement: None,
+        };
+        ans.parse_normalizer(&json["normalizer"]);
+        ans.parse_pre_tokenizer(&json["pre_tokenizer"]);
+        ans
+    }
+
+    fn parse_normalizer(&mut self, json: &Value) {
+        match json["type"].as_str() {
+            Some("Sequence") => json["normalizers"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .for_each(|n| self.parse_normalizer(n)),
+            Some("NFKC") => self.nfkc = true,
+            Some("Prepend") => self.add_dummy_prefix = true,
+            Some("Replace") => match (&json["pattern"]["String"], &json["pattern"]["Regex"]) {
+                (Value::String(s), _) if s == " " => {
+                    self.space_replacement = json["content"].as_str().and_then(|s| s.chars().next())
+                }
+                (_, Value::String(r)) if r.starts_with(" {2,}") || r == " +" => {
+                    self.collapse_whitespace = true
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn parse_pre_tokenizer(&mut self, json: &Value) {
+        match json["type"].as_str() {
+            Some("Sequence") => json["pretokenizers"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .for_each(|n| self.parse_pre_tokenizer(n)),
+            Some("Metaspace") => {
+                self.space_replacement =
+                    json["replacement"].as_str().and_then(|s| s.chars().next());
+                self.add_dummy_prefix = match json["prepend_scheme"].as_str() {
+                    Some(scheme) => scheme != "never",
+                    None => json["add_prefix_space"].as_bool().unwrap_or(true),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    pub fn normalize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        if self.nfkc {
+            text = Cow::Owned(text.nfkc().collect());
+        }
+        if self.collapse_whitespace {
+            let mut collapsed = String::with_capacity(text.len());
+            for c in text.chars() {
+                if !(c == ' ' && collapsed.ends_with(' ')) {
+                    collapsed.push(c);
+                }
+            }
+            text = Cow::Owned(collapsed);
+        }
+        if self.add_dummy_prefix && !text.is_empty() {
+            text = Cow::Owned(format!(" {text}"));
+        }
+        text
+    }
+
+    /// 把词表中的词还原为文本形式。
+    pub fn denormalize_piece<'a>(&self, piece: &'a str) -> Cow<'a, str> {
+        match self.space_replacement {
+            Some(c) if piece.contains(c) => Cow::Owned(piece.replace(c, " ")),
+            _ => Cow::Borrowed(piece),
+        }
+    }
+}
+
+#[test]
+fn test_normalize() {
+    let llama = Normalizer::default();
+    assert_eq!(llama.normalize("a  b"), " a  b");
+    assert_eq!(llama.normalize(""), "");
+
+    let json = serde_json::json!({
+        "normalizer": {
+            "type": "Sequence",
+            "normalizers": [
+                { "type": "NFKC" },
+                { "type": "Replace", "pattern": { "Regex": " {2,}" }, "content": " " },
+            ]
+        },
+        "pre_tokenizer": {
+            "type": "Metaspace",
+            "replacement": "\u{2581}",
+            "prepend_scheme": "never",
+        },
+    });
+    let normalizer = Normalizer::from_tokenizer_json(&json);
+    assert_eq!(
+        normalizer,
+        Normalizer {
+            nfkc: true,
+            collapse_whitespace: true,
+            add_dummy_prefix: false,
+            space_replacement: Some('\u{2581}'),
+        }
+    );
+    assert_eq!(normalizer.normalize(" ﬁne   ｗｏｒｋ "), " fine work ");
+    assert_eq!(normalizer.normalize("a \n\n  b\t\tc"), "a \n\n b\t\tc");
+    assert_eq!(normalizer.denormalize_piece("\u{2581}work"), " work");
+}
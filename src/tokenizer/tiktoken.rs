@@ -0,0 +1,628 @@
+use super::{utok, Tokenizer};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use fancy_regex::Regex;
+use serde_json::Value;
+use std::{borrow::Cow, collections::HashMap, path::Path};
+use unicode_normalization::UnicodeNormalization;
+
+/// llama3 的预分词正则表达式。
+pub const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// gpt2 的预分词正则表达式，`tokenizer.json` 的 `ByteLevel` 预分词器设置 `use_regex` 时使用。
+pub const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+/// tiktoken 格式的字节级 bpe 分词器。
+///
+/// 词表文件每行为 `base64(piece) rank`，rank 即序号，同时也是合并的优先级（越小越优先）。
+/// 特殊 token 排在普通 token 之后。
+///
+/// 也可以加载 huggingface 的字节级 bpe `tokenizer.json`，按其中的合并规则合并。
+pub struct Tiktoken {
+    /// 每个序号对应的字节串。
+    pieces: Vec<Vec<u8>>,
+    /// 从字节串查询序号。
+    ranks: HashMap<Vec<u8>, utok>,
+    /// huggingface 词表的合并规则：相邻两个 token 合并的优先级（越小越优先）。
+    ///
+    /// tiktoken 词表没有合并规则，以合并结果的序号作为优先级。
+    merges: Option<HashMap<(utok, utok), usize>>,
+    /// 整段文本在词表中时直接输出，不再逐步合并。
+    ignore_merges: bool,
+    /// 预分词之前做 NFC 规范化。
+    nfc: bool,
+    /// 从特殊 token 的文本查询序号。
+    special: HashMap<String, utok>,
+    /// 预分词。
+    pattern: Regex,
+    /// 匹配文本中出现的特殊 token。
+    special_pattern: Option<Regex>,
+    bos: utok,
+    eos: utok,
+}
+
+impl Tiktoken {
+    /// 加载 tiktoken 格式的词表，例如 llama3 的 `tokenizer.model`，使用 llama3 的预分词规则。
+    ///
+    /// 特殊 token 以及 bos、eos 从同一目录的 `tokenizer_config.json` 读取（`added_tokens_decoder`、`bos_token`、`eos_token`），
+    /// 没有这个文件时使用 llama3 的特殊 token。
+    pub fn new(tokenizer: impl AsRef<Path>) -> Self {
+        let tokenizer = tokenizer.as_ref();
+        let config = tokenizer.with_file_name("tokenizer_config.json");
+        let Ok(config) = std::fs::read(&config) else {
+            return Self::llama3(tokenizer);
+        };
+        let config = serde_json::from_slice::<Value>(&config).unwrap();
+        let mut special = config["added_tokens_decoder"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(id, token)| {
+                let id = id
+                    .parse::<utok>()
+                    .expect("invalid id in added_tokens_decoder");
+                let content = token["content"].as_str().expect("invalid added token");
+                (id, content.to_string())
+            })
+            .collect::<Vec<_>>();
+        special.sort_unstable();
+
+        let ans = Self::with_special_tokens(
+            tokenizer,
+            LLAMA3_PATTERN,
+            special.iter().map(|(_, s)| s.clone()),
+            &config_token(&config, "bos_token"),
+            &config_token(&config, "eos_token"),
+        );
+        // 特殊 token 依次编号在普通 token 之后，与配置中的序号不一致说明词表和配置不匹配
+        for (id, s) in &special {
+            assert_eq!(
+                ans.special[s], *id,
+                "special token {s} does not follow the vocabulary"
+            );
+        }
+        ans
+    }
+
+    /// 使用 llama3 的特殊 token。
+    fn llama3(tokenizer: &Path) -> Self {
+        let mut special = vec![
+            "<|begin_of_text|>".to_string(),
+            "<|end_of_text|>".to_string(),
+            "<|reserved_special_token_0|>".to_string(),
+            "<|reserved_special_token_1|>".to_string(),
+            "<|reserved_special_token_2|>".to_string(),
+            "<|reserved_special_token_3|>".to_string(),
+            "<|start_header_id|>".to_string(),
+            "<|end_header_id|>".to_string(),
+            "<|reserved_special_token_4|>".to_string(),
+            "<|eot_id|>".to_string(),
+        ];
+        special.extend((5..251).map(|i| format!("<|reserved_special_token_{i}|>")));
+        Self::with_special_tokens(
+            tokenizer,
+            LLAMA3_PATTERN,
+            special,
+            "<|begin_of_text|>",
+            "<|end_of_text|>",
+        )
+    }
+
+    /// 文件内容是否为 tiktoken 格式：每行为 base64 编码的字节串和一个整数。
+    pub(super) fn is_tiktoken(content: &[u8]) -> bool {
+        let Ok(text) = std::str::from_utf8(content) else {
+            return false;
+        };
+        let mut lines = text.lines().filter(|l| !l.is_empty()).peekable();
+        lines.peek().is_some()
+            && lines.take(16).all(|line| {
+                line.split_once(' ').is_some_and(|(piece, rank)| {
+                    STANDARD.decode(piece).is_ok() && rank.parse::<utok>().is_ok()
+                })
+            })
+    }
+
+    /// 使用指定的预分词规则和特殊 token 加载词表，特殊 token 依次编号在普通 token 之后。
+    pub fn with_special_tokens(
+        tokenizer: impl AsRef<Path>,
+        pattern: &str,
+        special: impl IntoIterator<Item = String>,
+        bos: &str,
+        eos: &str,
+    ) -> Self {
+        let text = std::fs::read_to_string(tokenizer).unwrap();
+
+        let mut ranks = HashMap::new();
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            let (piece, rank) = line.split_once(' ').expect("invalid tiktoken line");
+            let piece = STANDARD
+                .decode(piece)
+                .expect("invalid base64 in tiktoken file");
+            ranks.insert(piece, rank.parse::<utok>().expect("invalid rank"));
+        }
+
+        let n_normal = ranks.values().max().map_or(0, |&r| r as usize + 1);
+        let mut pieces = vec![Vec::new(); n_normal];
+        for (piece, &rank) in &ranks {
+            pieces[rank as usize] = piece.clone();
+        }
+        let special = special
+            .into_iter()
+            .enumerate()
+            .map(|(i, s)| {
+                pieces.push(s.as_bytes().to_vec());
+                (s, (n_normal + i) as utok)
+            })
+            .collect::<HashMap<_, _>>();
+
+        Self {
+            bos: special[bos],
+            eos: special[eos],
+            pieces,
+            ranks,
+            merges: None,
+            ignore_merges: true,
+            nfc: false,
+            special_pattern: special_pattern(&special),
+            special,
+            pattern: Regex::new(pattern).unwrap(),
+        }
+    }
+
+    /// 加载 huggingface 的字节级 bpe `tokenizer.json`，例如 llama3、qwen2 和 gpt2 的词表。
+    ///
+    /// 按 `model.merges` 的顺序合并，预分词规则取自 `pre_tokenizer`，`added_tokens` 作为特殊 token，
+    /// bos、eos 从同一目录的 `tokenizer_config.json` 读取。
+    /// 其他类型的 `tokenizer.json`（例如 sentencepiece 转换得到的词表）报错。
+    pub fn from_tokenizer_json(tokenizer: impl AsRef<Path>) -> Self {
+        let tokenizer = tokenizer.as_ref();
+        let json = std::fs::read(tokenizer)
+            .unwrap_or_else(|_| panic!("Could not open tokenizer {}", tokenizer.display()));
+        let json = serde_json::from_slice::<Value>(&json).unwrap();
+        let model = &json["model"];
+        let affix = |key: &str| model[key].as_str().unwrap_or("").is_empty();
+        let pattern = byte_level_pattern(&json["pre_tokenizer"]);
+        assert!(
+            model["type"] == "BPE"
+                && affix("continuing_subword_prefix")
+                && affix("end_of_word_suffix")
+                && pattern.is_some(),
+            "{} is not a byte-level bpe tokenizer.json; use tokenizer.bin for sentencepiece \
+             vocabularies, or `tokenize --longest-prefix` to count tokens greedily",
+            tokenizer.display()
+        );
+        let nfc = match json["normalizer"]["type"].as_str() {
+            None => false,
+            Some("NFC") => true,
+            Some(ty) => panic!("normalizer {ty} is not supported"),
+        };
+
+        let bytes = unicode_to_byte();
+        let decode = |piece: &str| {
+            piece
+                .chars()
+                .map(|c| bytes.get(&c).copied())
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or_else(|| panic!("{piece:?} is not a byte-level piece"))
+        };
+        let vocab = model["vocab"].as_object().expect("model.vocab is missing");
+        let id = |piece: &str| {
+            vocab
+                .get(piece)
+                .and_then(Value::as_u64)
+                .unwrap_or_else(|| panic!("{piece:?} is not in model.vocab")) as utok
+        };
+        let ranks = vocab
+            .keys()
+            .map(|piece| (decode(piece), id(piece)))
+            .collect::<HashMap<_, _>>();
+        // 新版本的合并规则是两个词的数组，旧版本是空格分隔的字符串
+        let merges = model["merges"]
+            .as_array()
+            .expect("model.merges is missing")
+            .iter()
+            .enumerate()
+            .map(|(i, merge)| {
+                let pair = match merge {
+                    Value::String(s) => s.split_once(' '),
+                    Value::Array(pair) => pair
+                        .first()
+                        .and_then(Value::as_str)
+                        .zip(pair.get(1).and_then(Value::as_str)),
+                    _ => None,
+                };
+                let (left, right) = pair.unwrap_or_else(|| panic!("invalid merge {merge}"));
+                ((id(left), id(right)), i)
+            })
+            .collect::<HashMap<_, _>>();
+        let special = json["added_tokens"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|token| {
+                let content = token["content"].as_str().expect("invalid added token");
+                let id = token["id"].as_u64().expect("invalid added token");
+                (content.to_string(), id as utok)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let len = ranks
+            .values()
+            .chain(special.values())
+            .max()
+            .map_or(0, |&id| id as usize + 1);
+        let mut pieces = vec![Vec::new(); len];
+        for (piece, &id) in &ranks {
+            pieces[id as usize] = piece.clone();
+        }
+        for (content, &id) in &special {
+            pieces[id as usize] = content.as_bytes().to_vec();
+        }
+
+        let config = tokenizer.with_file_name("tokenizer_config.json");
+        let config = std::fs::read(&config).unwrap_or_else(|_| {
+            panic!(
+                "{} is required for bos_token and eos_token",
+                config.display()
+            )
+        });
+        let config = serde_json::from_slice::<Value>(&config).unwrap();
+        let token = |key: &str| {
+            let content = config_token(&config, key);
+            special
+                .get(&content)
+                .or_else(|| ranks.get(&decode(&content)))
+                .copied()
+                .unwrap_or_else(|| panic!("{key} {content} is not in the vocabulary"))
+        };
+
+        Self {
+            bos: token("bos_token"),
+            eos: token("eos_token"),
+            pieces,
+            ranks,
+            merges: Some(merges),
+            ignore_merges: model["ignore_merges"].as_bool().unwrap_or(false),
+            nfc,
+            special_pattern: special_pattern(&special),
+            special,
+            pattern: Regex::new(&pattern.unwrap()).unwrap(),
+        }
+    }
+
+    /// 查询特殊 token 的序号。
+    #[inline]
+    pub fn special_token(&self, name: &str) -> Option<utok> {
+        self.special.get(name).copied()
+    }
+
+    /// 对预分词得到的一段文本做字节级的 bpe 合并。
+    fn encode_chunk(&self, chunk: &[u8], tokens: &mut Vec<utok>) {
+        if self.ignore_merges {
+            if let Some(&rank) = self.ranks.get(chunk) {
+                tokens.push(rank);
+                return;
+            }
+        }
+        // 每个部分的起始位置，最后一项是结尾
+        let mut parts = (0..=chunk.len()).collect::<Vec<_>>();
+        loop {
+            let best = parts
+                .windows(3)
+                .enumerate()
+                .filter_map(|(i, w)| self.merge_rank(chunk, w).map(|r| (r, i)))
+                .min();
+            match best {
+                Some((_, i)) => {
+                    parts.remove(i + 1);
+                }
+                None => break,
+            }
+        }
+        tokens.extend(parts.windows(2).map(|w| self.ranks[&chunk[w[0]..w[1]]]));
+    }
+
+    /// `chunk[w[0]..w[1]]` 和 `chunk[w[1]..w[2]]` 合并的优先级，不能合并时返回 `None`。
+    fn merge_rank(&self, chunk: &[u8], w: &[usize]) -> Option<usize> {
+        match &self.merges {
+            Some(merges) => {
+                let left = self.ranks.get(&chunk[w[0]..w[1]])?;
+                let right = self.ranks.get(&chunk[w[1]..w[2]])?;
+                merges.get(&(*left, *right)).copied()
+            }
+            None => self.ranks.get(&chunk[w[0]..w[2]]).map(|&r| r as _),
+        }
+    }
+
+    fn encode_ordinary(&self, text: &str, tokens: &mut Vec<utok>) {
+        let text = if self.nfc {
+            Cow::Owned(text.nfc().collect())
+        } else {
+            Cow::Borrowed(text)
+        };
+        for chunk in self.pattern.find_iter(&text) {
+            self.encode_chunk(chunk.unwrap().as_str().as_bytes(), tokens);
+        }
+    }
+}
+
+/// 读取 `tokenizer_config.json` 中的 bos 或 eos，可能是字符串，也可能是带 `content` 字段的对象。
+fn config_token(config: &Value, key: &str) -> String {
+    match &config[key] {
+        Value::String(s) => s.clone(),
+        value => value["content"]
+            .as_str()
+            .unwrap_or_else(|| panic!("{key} is missing in tokenizer_config.json"))
+            .to_string(),
+    }
+}
+
+/// 匹配文本中出现的特殊 token，较长的特殊 token 优先匹配。
+fn special_pattern(special: &HashMap<String, utok>) -> Option<Regex> {
+    if special.is_empty() {
+        return None;
+    }
+    let mut names = special.keys().map(|s| s.as_str()).collect::<Vec<_>>();
+    names.sort_unstable_by_key(|s| std::cmp::Reverse(s.len()));
+    let names = names
+        .into_iter()
+        .map(fancy_regex::escape)
+        .collect::<Vec<_>>();
+    Some(Regex::new(&names.join("|")).unwrap())
+}
+
+/// 字节级 `tokenizer.json` 的预分词正则表达式：单独的 `ByteLevel`，或者 `Split` 正则之后接 `ByteLevel`。
+///
+/// 其他预分词器返回 `None`。
+fn byte_level_pattern(pre_tokenizer: &Value) -> Option<String> {
+    // `add_prefix_space` 会在文本前补一个空格，这里不支持
+    let byte_level =
+        |p: &Value| p["type"] == "ByteLevel" && !p["add_prefix_space"].as_bool().unwrap_or(false);
+    let use_regex = |p: &Value| p["use_regex"].as_bool().unwrap_or(true);
+    match pre_tokenizer["type"].as_str()? {
+        "ByteLevel" if byte_level(pre_tokenizer) && use_regex(pre_tokenizer) => {
+            Some(GPT2_PATTERN.to_string())
+        }
+        "Sequence" => match pre_tokenizer["pretokenizers"].as_array()?.as_slice() {
+            [split, last]
+                if split["type"] == "Split"
+                    && split["behavior"] == "Isolated"
+                    && !split["invert"].as_bool().unwrap_or(false)
+                    && byte_level(last)
+                    && !use_regex(last) =>
+            {
+                split["pattern"]["Regex"].as_str().map(str::to_string)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// gpt2 的 `bytes_to_unicode` 的逆映射：字节级词表中的字符还原为字节。
+///
+/// 可显示的字节对应同一码位的字符，其余字节依次对应 `U+0100` 之后的字符。
+fn unicode_to_byte() -> HashMap<char, u8> {
+    let mut n = 0;
+    (0..=255u8)
+        .map(|b| {
+            let c = if matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF) {
+                b as char
+            } else {
+                n += 1;
+                char::from_u32(0xFF + n).unwrap()
+            };
+            (c, b)
+        })
+        .collect()
+}
+
+impl Tokenizer for Tiktoken {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    fn max_token_len(&self) -> usize {
+        self.pieces.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    #[inline]
+    fn bos(&self) -> utok {
+        self.bos
+    }
+
+    #[inline]
+    fn eos(&self) -> utok {
+        self.eos
+    }
+
+    /// 不是完整 utf-8 字符的字节串显示为 `�`。
+    #[inline]
+    fn piece(&self, token: utok) -> &str {
+        std::str::from_utf8(&self.pieces[token as usize]).unwrap_or("\u{FFFD}")
+    }
+
+    #[inline]
+    fn score(&self, token: utok) -> f32 {
+        if (token as usize) < self.ranks.len() {
+            -(token as f32)
+        } else {
+            0.
+        }
+    }
+
+    /// 文本中出现的特殊 token 直接编码为对应的序号。
+    fn encode(&self, text: &str, bos: bool, eos: bool) -> Vec<utok> {
+        let mut tokens = Vec::new();
+        if bos {
+            tokens.push(self.bos);
+        }
+        let mut start = 0;
+        if let Some(special) = &self.special_pattern {
+            for m in special.find_iter(text) {
+                let m = m.unwrap();
+                self.encode_ordinary(&text[start..m.start()], &mut tokens);
+                tokens.push(self.special[m.as_str()]);
+                start = m.end();
+            }
+        }
+        self.encode_ordinary(&text[start..], &mut tokens);
+        if eos {
+            tokens.push(self.eos);
+        }
+        tokens
+    }
+
+    /// 字节级的词表中一个字符可能被拆分到多个 token。
+    #[inline]
+    fn decode(&self, _: utok, next: utok) -> &[u8] {
+        &self.pieces[next as usize]
+    }
+}
+
+#[test]
+fn test_encode() {
+    // 构造一个只有少量 token 的词表，`\u{80}`..`\u{FF}` 表示单个字节
+    let pieces = [
+        "a", "b", "c", " ", "ab", " ab", "!", "\n", "\u{E4}", "\u{BD}", "\u{A0}",
+    ];
+    let dir = crate::arguments::TempDir::new("tiktoken-encode");
+    let path = dir.join("tokenizer.model");
+    let mut text = String::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        let bytes = piece.chars().map(|c| c as u32 as u8).collect::<Vec<_>>();
+        text.push_str(&format!("{} {i}\n", STANDARD.encode(bytes)));
+    }
+    std::fs::write(&path, text).unwrap();
+    let tokenizer = Tiktoken::with_special_tokens(
+        &path,
+        LLAMA3_PATTERN,
+        ["<|bos|>".to_string(), "<|eos|>".to_string()],
+        "<|bos|>",
+        "<|eos|>",
+    );
+
+    assert_eq!(tokenizer.vocab_size(), pieces.len() + 2);
+    assert_eq!((tokenizer.bos(), tokenizer.eos()), (11, 12));
+
+    let text = "abc ab!<|eos|>\n你";
+    let tokens = tokenizer.encode(text, true, false);
+    assert_eq!(tokens, [11, 4, 2, 5, 6, 12, 7, 8, 9, 10]);
+
+    let mut decoded = Vec::new();
+    for pair in tokens.windows(2) {
+        decoded.extend_from_slice(tokenizer.decode(pair[0], pair[1]));
+    }
+    assert_eq!(String::from_utf8(decoded).unwrap(), text);
+}
+
+#[test]
+fn test_special_tokens() {
+    let dir = std::env::temp_dir().join(format!("llama2-rs-tiktoken-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("tokenizer.model");
+    let text = ["a", "b", "ab"]
+        .iter()
+        .enumerate()
+        .map(|(i, piece)| format!("{} {i}\n", STANDARD.encode(piece)))
+        .collect::<String>();
+    std::fs::write(&path, &text).unwrap();
+    std::fs::write(
+        dir.join("tokenizer_config.json"),
+        r#"{
+            "added_tokens_decoder": {
+                "4": { "content": "<|im_end|>", "special": true },
+                "3": { "content": "<|im_start|>", "special": true }
+            },
+            "bos_token": "<|im_start|>",
+            "eos_token": { "content": "<|im_end|>" }
+        }"#,
+    )
+    .unwrap();
+    let tokenizer = Tiktoken::new(&path);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(tokenizer.vocab_size(), 5);
+    assert_eq!((tokenizer.bos(), tokenizer.eos()), (3, 4));
+    assert_eq!(
+        tokenizer.encode("<|im_start|>ab<|im_end|>", false, false),
+        [3, 2, 4]
+    );
+
+    assert!(Tiktoken::is_tiktoken(text.as_bytes()));
+    // sentencepiece 的 `tokenizer.model` 是 protobuf
+    assert!(!Tiktoken::is_tiktoken(
+        b"\n\x0e\n\x05<unk>\x15\0\0\0\0\x18\x02"
+    ));
+    assert!(!Tiktoken::is_tiktoken(b""));
+}
+
+#[test]
+fn test_tokenizer_json() {
+    use serde_json::json;
+
+    let dir = crate::arguments::TempDir::new("tiktoken-json");
+    let path = dir.join("tokenizer.json");
+    // `Ġ` 是空格，`Ã`、`¤` 是 `ä` 的两个字节；`ab` 的序号比 `bc` 小，但 `b c` 先合并
+    let tokenizer = json!({
+        "normalizer": null,
+        "pre_tokenizer": {
+            "type": "Sequence",
+            "pretokenizers": [
+                { "type": "Split", "pattern": { "Regex": LLAMA3_PATTERN }, "behavior": "Isolated", "invert": false },
+                { "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": false }
+            ]
+        },
+        "added_tokens": [
+            { "id": 9, "content": "<|end|>", "special": true },
+            { "id": 10, "content": "<|begin|>", "special": true }
+        ],
+        "model": {
+            "type": "BPE",
+            "vocab": {
+                "a": 0, "b": 1, "c": 2, "\u{120}": 3, "ab": 4, "bc": 5, "\u{120}a": 6,
+                "\u{C3}": 7, "\u{A4}": 8, "abc": 11
+            },
+            "merges": ["b c", "a b", ["\u{120}", "a"]]
+        }
+    });
+    std::fs::write(&path, tokenizer.to_string()).unwrap();
+    std::fs::write(
+        dir.join("tokenizer_config.json"),
+        r#"{ "bos_token": "<|begin|>", "eos_token": { "content": "<|end|>" } }"#,
+    )
+    .unwrap();
+    let tokenizer = Tiktoken::from_tokenizer_json(&path);
+
+    assert_eq!(tokenizer.vocab_size(), 12);
+    assert_eq!((tokenizer.bos(), tokenizer.eos()), (10, 9));
+    assert_eq!(tokenizer.piece(6), " a");
+    // 没有 `ignore_merges` 时 `abc` 也要逐步合并，不会直接得到 11
+    let text = "abc abc<|end|>\u{E4}";
+    let tokens = tokenizer.encode(text, true, false);
+    assert_eq!(tokens, [10, 0, 5, 6, 5, 9, 7, 8]);
+
+    let mut decoded = Vec::new();
+    for pair in tokens.windows(2) {
+        decoded.extend_from_slice(tokenizer.decode(pair[0], pair[1]));
+    }
+    assert_eq!(String::from_utf8(decoded).unwrap(), text);
+}
+
+#[test]
+#[should_panic(expected = "is not a byte-level bpe tokenizer.json")]
+fn test_reject_sentencepiece_json() {
+    let dir = crate::arguments::TempDir::new("tiktoken-sentencepiece");
+    let path = dir.join("tokenizer.json");
+    std::fs::write(
+        &path,
+        r#"{
+            "normalizer": { "type": "Replace", "pattern": { "String": " " }, "content": "\u2581" },
+            "pre_tokenizer": null,
+            "model": { "type": "BPE", "byte_fallback": true, "vocab": { "a": 0 }, "merges": [] }
+        }"#,
+    )
+    .unwrap();
+    Tiktoken::from_tokenizer_json(&path);
+}
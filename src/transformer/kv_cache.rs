@@ -0,0 +1,514 @@
+﻿use half::f16;
+use std::{cell::RefCell, fmt, iter::zip, rc::Rc, str::FromStr};
+
+/// 每个块保存的位置数。
+pub(super) const BLOCK_SIZE: usize = 16;
+
+/// 块表中已经释放的块。
+const EVICTED: usize = usize::MAX;
+
+/// kv cache 的存储类型。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum KvDtype {
+    #[default]
+    F32,
+    F16,
+    /// 每个位置的每个 kv 头共用一个 f32 缩放系数的对称 int8 量化。
+    Int8,
+}
+
+impl FromStr for KvDtype {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(Self::F32),
+            "f16" => Ok(Self::F16),
+            "int8" => Ok(Self::Int8),
+            _ => Err(format!(
+                "unknown kv cache dtype: {s}, expected f32, f16 or int8"
+            )),
+        }
+    }
+}
+
+/// 不能从给定的位置推理或回退：位置越过了 kv cache 中已经写入的位置，或者需要的位置已经滑出注意力窗口。
+#[derive(Clone, Debug, PartialEq)]
+pub struct PositionError(pub String);
+
+impl fmt::Display for PositionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PositionError {}
+
+/// 一个块的数据，布局为 `n_layers x 2 x BLOCK_SIZE x kv_dim`，int8 的缩放系数为 `n_layers x 2 x BLOCK_SIZE x n_kv_heads`。
+#[derive(Clone)]
+enum Block {
+    F32(Box<[f32]>),
+    F16(Box<[f16]>),
+    Int8(Box<[i8]>, Box<[f32]>),
+}
+
+/// kv cache 的块池，所有序列共享。
+///
+/// 每个块保存所有层 [`BLOCK_SIZE`] 个位置的 key 和 value，按 [`KvDtype`] 存储，写入时量化，读出时反量化为 f32。
+/// 块按需分配，引用计数归零后放回空闲列表复用。
+pub(super) struct BlockPool {
+    dtype: KvDtype,
+    n_layers: usize,
+    kv_dim: usize,
+    head_size: usize,
+    blocks: Vec<Block>,
+    ref_counts: Vec<usize>,
+    free: Vec<usize>,
+}
+
+impl BlockPool {
+    pub fn new(n_layers: usize, kv_dim: usize, head_size: usize, dtype: KvDtype) -> Self {
+        Self {
+            dtype,
+            n_layers,
+            kv_dim,
+            head_size,
+            blocks: Vec::new(),
+            ref_counts: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn dtype(&self) -> KvDtype {
+        self.dtype
+    }
+
+    /// 一个块中一层的 key 或 value 的大小：`BLOCK_SIZE x kv_dim`。
+    #[inline]
+    pub fn layer_len(&self) -> usize {
+        BLOCK_SIZE * self.kv_dim
+    }
+
+    fn allocate(&mut self) -> usize {
+        if let Some(id) = self.free.pop() {
+            self.ref_counts[id] = 1;
+            return id;
+        }
+        let len = self.n_layers * 2 * self.layer_len();
+        self.blocks.push(match self.dtype {
+            KvDtype::F32 => Block::F32(vec![0.; len].into()),
+            KvDtype::F16 => Block::F16(vec![f16::ZERO; len].into()),
+            KvDtype::Int8 => {
+                Block::Int8(vec![0; len].into(), vec![0.; len / self.head_size].into())
+            }
+        });
+        self.ref_counts.push(1);
+        self.blocks.len() - 1
+    }
+
+    #[inline]
+    fn retain(&mut self, id: usize) {
+        self.ref_counts[id] += 1;
+    }
+
+    #[inline]
+    fn release(&mut self, id: usize) {
+        self.ref_counts[id] -= 1;
+        if self.ref_counts[id] == 0 {
+            self.free.push(id);
+        }
+    }
+
+    /// 块被多个序列共享时复制一份，返回可以写入的块。
+    fn make_unique(&mut self, id: usize) -> usize {
+        if self.ref_counts[id] == 1 {
+            return id;
+        }
+        let new = self.allocate();
+        self.blocks[new] = self.blocks[id].clone();
+        self.release(id);
+        new
+    }
+
+    /// 第 `layer` 层的 key：`BLOCK_SIZE x kv_dim`，不是 f32 存储时反量化到 `buf`。
+    #[inline]
+    pub fn k<'a>(&'a self, id: usize, layer: usize, buf: &'a mut [f32]) -> &'a [f32] {
+        self.view(id, layer * 2, buf)
+    }
+
+    /// 第 `layer` 层的 value：`BLOCK_SIZE x kv_dim`，不是 f32 存储时反量化到 `buf`。
+    #[inline]
+    pub fn v<'a>(&'a self, id: usize, layer: usize, buf: &'a mut [f32]) -> &'a [f32] {
+        self.view(id, layer * 2 + 1, buf)
+    }
+
+    fn view<'a>(&'a self, id: usize, part: usize, buf: &'a mut [f32]) -> &'a [f32] {
+        let len = self.layer_len();
+        match &self.blocks[id] {
+            Block::F32(data) => &data[part * len..][..len],
+            _ => {
+                self.load(id, part, 0, &mut buf[..len]);
+                buf
+            }
+        }
+    }
+
+    /// 把 `src` 中的若干行量化写入块 `id` 的第 `part` 部分（`layer * 2` 为 key，`layer * 2 + 1` 为 value）的第 `row` 行开始的位置。
+    fn store(&mut self, id: usize, part: usize, row: usize, src: &[f32]) {
+        let base = part * self.layer_len() + row * self.kv_dim;
+        match &mut self.blocks[id] {
+            Block::F32(data) => data[base..][..src.len()].copy_from_slice(src),
+            Block::F16(data) => {
+                zip(&mut data[base..], src).for_each(|(d, s)| *d = f16::from_f32(*s))
+            }
+            Block::Int8(data, scales) => {
+                let head_size = self.head_size;
+                let data = &mut data[base..][..src.len()];
+                let scales = &mut scales[base / head_size..][..src.len() / head_size];
+                for ((d, s), scale) in zip(
+                    zip(data.chunks_mut(head_size), src.chunks(head_size)),
+                    scales,
+                ) {
+                    let max = s.iter().fold(0f32, |max, x| max.max(x.abs()));
+                    *scale = max / 127.;
+                    let inv = if max > 0. { 127. / max } else { 0. };
+                    zip(d, s).for_each(|(d, s)| *d = (s * inv).round() as i8);
+                }
+            }
+        }
+    }
+
+    /// 从块 `id` 的第 `part` 部分的第 `row` 行开始读出若干行到 `dst`。
+    fn load(&self, id: usize, part: usize, row: usize, dst: &mut [f32]) {
+        let base = part * self.layer_len() + row * self.kv_dim;
+        match &self.blocks[id] {
+            Block::F32(data) => dst.copy_from_slice(&data[base..][..dst.len()]),
+            Block::F16(data) => zip(dst, &data[base..]).for_each(|(d, s)| *d = s.to_f32()),
+            Block::Int8(data, scales) => {
+                let head_size = self.head_size;
+                let data = &data[base..][..dst.len()];
+                let scales = &scales[base / head_size..];
+                for ((d, s), scale) in zip(
+                    zip(dst.chunks_mut(head_size), data.chunks(head_size)),
+                    scales,
+                ) {
+                    zip(d, s).for_each(|(d, s)| *d = *s as f32 * scale);
+                }
+            }
+        }
+    }
+}
+
+/// 一个序列的分页 kv cache。
+///
+/// 序列只保存块表，位置 `pos` 在第 `pos / BLOCK_SIZE` 个块中，块从 [`Transformer`](super::Transformer) 的块池按需分配，
+/// 所以只占用实际用到的内存。[`KvCache::fork`] 得到的序列共享已有的块，写入共享的块时先复制。
+///
+/// 滑动窗口注意力的模型不再需要 [`KvCache::start`] 之前的位置，这些位置所在的块放回块池，
+/// 块表像环形缓冲区一样循环使用窗口大小的内存。
+///
+/// 每次推理都写入所有层，所以所有层的有效长度相同，只记录一个 [`KvCache::len`]。
+pub struct KvCache {
+    pool: Rc<RefCell<BlockPool>>,
+    blocks: Vec<usize>,
+    start: usize,
+    len: usize,
+}
+
+impl KvCache {
+    pub(super) fn new(pool: Rc<RefCell<BlockPool>>) -> Self {
+        Self {
+            pool,
+            blocks: Vec::new(),
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// 复制这个序列，新序列与原序列共享所有的块，任何一方写入共享的块时再复制。
+    pub fn fork(&self) -> Self {
+        let mut pool = self.pool.borrow_mut();
+        for &id in self.blocks.iter().filter(|&&id| id != EVICTED) {
+            pool.retain(id);
+        }
+        Self {
+            pool: self.pool.clone(),
+            blocks: self.blocks.clone(),
+            start: self.start,
+            len: self.len,
+        }
+    }
+
+    /// 已经写入的位置数，下一次推理的位置不能超过它。
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 回退到只保留前 `len` 个位置，完全在 `len` 之后的块放回块池，`len` 超过已经写入的位置数时报错。
+    ///
+    /// `len` 在 [`KvCache::start`] 之前时保留的位置都已经滑出窗口，`start` 也回退到 `len`。
+    pub fn truncate(&mut self, len: usize) -> Result<(), PositionError> {
+        if len > self.len {
+            return Err(PositionError(format!(
+                "cannot truncate kv cache of {} positions to {len}",
+                self.len
+            )));
+        }
+        let mut pool = self.pool.borrow_mut();
+        for id in self.blocks.drain(len.div_ceil(BLOCK_SIZE)..) {
+            if id != EVICTED {
+                pool.release(id);
+            }
+        }
+        self.start = self.start.min(len);
+        self.len = len;
+        Ok(())
+    }
+
+    /// 已分配的块可以容纳的位置数。
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.blocks.len() * BLOCK_SIZE
+    }
+
+    /// 第一个保留的位置，之前的位置已经滑出注意力窗口，不再参与注意力计算。
+    #[inline]
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    #[inline]
+    pub(super) fn pool(&self) -> &Rc<RefCell<BlockPool>> {
+        &self.pool
+    }
+
+    /// 覆盖位置 `0..len` 的块，[`KvCache::start`] 所在的块之前的块已经释放。
+    #[inline]
+    pub(super) fn blocks(&self, len: usize) -> &[usize] {
+        &self.blocks[..len.div_ceil(BLOCK_SIZE)]
+    }
+
+    /// 是否保留了从 `pos` 继续推理需要的位置，即窗口大小为 `window` 时 `pos` 之前窗口内的位置。
+    #[inline]
+    pub(super) fn can_resume(&self, pos: usize, window: usize) -> bool {
+        let begin = (pos + 1).saturating_sub(window);
+        begin >= self.start || begin == pos
+    }
+
+    /// 准备从 `pos` 继续推理，丢弃窗口大小为 `window` 时不再需要的位置，释放完全在窗口之前的块。
+    pub(super) fn slide(&mut self, pool: &mut BlockPool, pos: usize, window: usize) {
+        assert!(
+            self.can_resume(pos, window),
+            "positions before {} have left the sliding window",
+            self.start
+        );
+        // 从已经丢弃的位置重新开始，之后的位置都会重新写入
+        self.start = self.start.min(pos);
+
+        let begin = (pos + 1).saturating_sub(window);
+        if begin <= self.start {
+            return;
+        }
+        self.start = begin;
+        let end = (begin / BLOCK_SIZE).min(self.blocks.len());
+        for id in &mut self.blocks[..end] {
+            if *id != EVICTED {
+                pool.release(*id);
+                *id = EVICTED;
+            }
+        }
+    }
+
+    /// 记录 `pos` 之前的位置都已写入，之后的位置作废。
+    #[inline]
+    pub(super) fn set_len(&mut self, pos: usize) {
+        self.len = pos;
+    }
+
+    /// 把 [`KvCache::start`] 调整到 [`Transformer::shift_cache`](super::Transformer::shift_cache) 之后的位置：
+    /// `[keep + discard, ..)` 前移 `discard` 个位置，其中已经丢弃的部分仍然不可用。
+    pub(super) fn shift_start(&mut self, keep: usize, discard: usize) {
+        if self.start > keep {
+            self.start = keep.max(self.start - discard.min(self.start));
+        }
+    }
+
+    /// 准备写入 `pos..pos + len`：分配缺少的块，复制共享的块。
+    pub(super) fn prepare(&mut self, pool: &mut BlockPool, pos: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let end = (pos + len).div_ceil(BLOCK_SIZE);
+        while self.blocks.len() < end {
+            self.blocks.push(pool.allocate());
+        }
+        for id in &mut self.blocks[pos / BLOCK_SIZE..end] {
+            *id = if *id == EVICTED {
+                pool.allocate()
+            } else {
+                pool.make_unique(*id)
+            };
+        }
+    }
+
+    /// 把 `k`、`v`（`len x kv_dim`）写入第 `layer` 层从 `pos` 开始的位置，需要先 [`KvCache::prepare`]。
+    pub(super) fn write(
+        &self,
+        pool: &mut BlockPool,
+        layer: usize,
+        pos: usize,
+        k: &[f32],
+        v: &[f32],
+    ) {
+        let kv_dim = pool.kv_dim;
+        self.for_each_chunk(pos, k.len() / kv_dim, |id, offset, i, n| {
+            let src = i * kv_dim..(i + n) * kv_dim;
+            pool.store(id, layer * 2, offset, &k[src.clone()]);
+            pool.store(id, layer * 2 + 1, offset, &v[src]);
+        });
+    }
+
+    /// 读出第 `layer` 层从 `pos` 开始的 key 和 value 到 `k`、`v`（`len x kv_dim`），已经释放的位置读出为零。
+    pub(super) fn read(
+        &self,
+        pool: &BlockPool,
+        layer: usize,
+        pos: usize,
+        k: &mut [f32],
+        v: &mut [f32],
+    ) {
+        let kv_dim = pool.kv_dim;
+        self.for_each_chunk(pos, k.len() / kv_dim, |id, offset, i, n| {
+            let dst = i * kv_dim..(i + n) * kv_dim;
+            if id == EVICTED {
+                k[dst.clone()].fill(0.);
+                v[dst].fill(0.);
+            } else {
+                pool.load(id, layer * 2, offset, &mut k[dst.clone()]);
+                pool.load(id, layer * 2 + 1, offset, &mut v[dst]);
+            }
+        });
+    }
+
+    /// 把 `pos..pos + len` 按块切分，依次以块、块内偏移、相对 `pos` 的偏移和长度调用 `f`。
+    fn for_each_chunk(
+        &self,
+        pos: usize,
+        len: usize,
+        mut f: impl FnMut(usize, usize, usize, usize),
+    ) {
+        let mut i = 0;
+        while i < len {
+            let offset = (pos + i) % BLOCK_SIZE;
+            let n = (BLOCK_SIZE - offset).min(len - i);
+            f(self.blocks[(pos + i) / BLOCK_SIZE], offset, i, n);
+            i += n;
+        }
+    }
+}
+
+impl Drop for KvCache {
+    fn drop(&mut self) {
+        let mut pool = self.pool.borrow_mut();
+        for &id in self.blocks.iter().filter(|&&id| id != EVICTED) {
+            pool.release(id);
+        }
+    }
+}
+
+#[test]
+fn test_copy_on_write() {
+    let pool = Rc::new(RefCell::new(BlockPool::new(1, 2, 2, KvDtype::F32)));
+    let row = |x: f32| [x, x];
+
+    let mut a = KvCache::new(pool.clone());
+    a.prepare(&mut pool.borrow_mut(), 0, BLOCK_SIZE + 1);
+    for pos in 0..BLOCK_SIZE + 1 {
+        let x = row(pos as _);
+        a.write(&mut pool.borrow_mut(), 0, pos, &x, &x);
+    }
+
+    let mut b = a.fork();
+    assert_eq!(b.blocks, a.blocks);
+    // 写入第二个块只复制第二个块
+    b.prepare(&mut pool.borrow_mut(), BLOCK_SIZE, 1);
+    b.write(&mut pool.borrow_mut(), 0, BLOCK_SIZE, &row(-1.), &row(-1.));
+    assert_eq!(b.blocks[0], a.blocks[0]);
+    assert_ne!(b.blocks[1], a.blocks[1]);
+
+    let (mut k, mut v) = ([0.; 2], [0.; 2]);
+    a.read(&pool.borrow(), 0, BLOCK_SIZE, &mut k, &mut v);
+    assert_eq!(k, row(BLOCK_SIZE as _));
+    b.read(&pool.borrow(), 0, BLOCK_SIZE, &mut k, &mut v);
+    assert_eq!(v, row(-1.));
+
+    drop(a);
+    drop(b);
+    assert_eq!(pool.borrow().free.len(), 3);
+}
+
+#[test]
+fn test_truncate() {
+    let pool = Rc::new(RefCell::new(BlockPool::new(1, 2, 2, KvDtype::F32)));
+
+    let mut a = KvCache::new(pool.clone());
+    a.prepare(&mut pool.borrow_mut(), 0, 2 * BLOCK_SIZE + 1);
+    a.set_len(2 * BLOCK_SIZE + 1);
+    let b = a.fork();
+    // 回退只放回完全在保留长度之后的块，与其他序列共享的块仍然保留
+    a.truncate(BLOCK_SIZE + 1).unwrap();
+    assert_eq!((a.len(), a.blocks.len()), (BLOCK_SIZE + 1, 2));
+    assert!(pool.borrow().free.is_empty());
+    drop(b);
+    assert_eq!(pool.borrow().free.len(), 1);
+    assert!(a.truncate(BLOCK_SIZE + 2).is_err());
+    a.truncate(0).unwrap();
+    assert!(a.is_empty() && a.blocks.is_empty());
+    assert_eq!(pool.borrow().free.len(), 3);
+}
+
+#[test]
+fn test_evict() {
+    let pool = Rc::new(RefCell::new(BlockPool::new(1, 2, 2, KvDtype::F32)));
+    let window = 2 * BLOCK_SIZE;
+
+    // 按滑动窗口丢弃之前的位置，块池中的块循环使用
+    let mut a = KvCache::new(pool.clone());
+    for pos in 0..10 * BLOCK_SIZE {
+        let mut pool = pool.borrow_mut();
+        a.slide(&mut pool, pos, window);
+        a.prepare(&mut pool, pos, 1);
+        a.write(&mut pool, 0, pos, &[pos as _; 2], &[pos as _; 2]);
+    }
+    assert_eq!(a.start(), 10 * BLOCK_SIZE - window);
+    assert_eq!(pool.borrow().blocks.len(), 3);
+    assert!(!a.can_resume(a.start() + window - 2, window));
+    assert!(a.can_resume(0, window));
+
+    let (mut k, mut v) = ([1.; 4], [1.; 4]);
+    a.read(&pool.borrow(), 0, a.start() - 1, &mut k, &mut v);
+    assert_eq!(k, [0., 0., a.start() as _, a.start() as _]);
+
+    // 回退到窗口之前时，保留的位置都不可用，只能从头计算
+    let mut b = a.fork();
+    b.set_len(10 * BLOCK_SIZE);
+    b.truncate(BLOCK_SIZE).unwrap();
+    assert_eq!((b.start(), b.blocks.len()), (BLOCK_SIZE, 1));
+    assert!(!b.can_resume(BLOCK_SIZE, window) && b.can_resume(0, window));
+    b.truncate(0).unwrap();
+    assert_eq!(b.start(), 0);
+    drop(b);
+
+    // 前移之后，移入的已丢弃位置仍然不可用
+    a.shift_start(4, a.start() - 8);
+    assert_eq!(a.start(), 8);
+    a.shift_start(4, 100);
+    assert_eq!(a.start(), 4);
+}
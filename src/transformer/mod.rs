@@ -1,25 +1,45 @@
-﻿mod state;
+﻿mod kv_cache;
+mod prefix_cache;
+mod state;
 
 use super::{
-    kernel::{gemm, rmsnorm, rmsnorm_inplace, sigmoid, slice, softmax},
+    kernel::{
+        add_bias, gelu, gemm, layernorm, rmsnorm, rmsnorm_unit_offset, sigmoid, slice, softmax,
+    },
     tokenizer::utok,
 };
 use crate::{
-    arguments::{AllInOneBin, Arguments, SafeTensors},
+    arguments::{export, Activation, AllInOneBin, Arguments, Lora, Norm, Proj, SafeTensors},
     log::Logger,
 };
-use state::{Layer, RotaryEmbedder, RunState};
-use std::{ffi::OsStr, fs::File, iter::zip, path::Path};
+use kv_cache::{BlockPool, BLOCK_SIZE};
+use state::{RotaryEmbedder, RunState};
+
+pub use kv_cache::{KvCache, KvDtype, PositionError};
+pub use prefix_cache::PrefixCache;
+use std::{
+    cell::RefCell,
+    ffi::OsStr,
+    fs::File,
+    io::{self, Read, Write},
+    iter::zip,
+    path::Path,
+    rc::Rc,
+};
 
 /// `upos` for position id.
 #[allow(non_camel_case_types)]
 pub(super) type upos = u32;
 
 pub struct Transformer {
-    layers: Vec<Layer>,
+    /// kv cache 的块池，`cache` 和 [`Transformer::new_cache`] 创建的序列共享。
+    pool: Rc<RefCell<BlockPool>>,
+    cache: KvCache,
     logits: Vec<f32>,
     embedder: RotaryEmbedder,
     arguments: Box<dyn Arguments>,
+    /// 叠加在基础模型上的 LoRA 适配器。
+    adapter: Option<Lora>,
 }
 
 impl Transformer {
@@ -40,276 +60,1185 @@ impl Transformer {
             Box::new(AllInOneBin::new(file))
         };
 
+        let pool = new_pool(&*arguments, KvDtype::F32);
         Self {
-            layers: vec![Layer::new(&*arguments); arguments.n_layers()],
+            cache: KvCache::new(pool.clone()),
+            pool,
             logits: vec![0.; arguments.vocab_size()],
-            embedder: RotaryEmbedder::new(&*arguments),
+            embedder: RotaryEmbedder::new(&*arguments, arguments.seq_len()),
             arguments,
+            adapter: None,
         }
     }
 
+    /// 读取目录 `dir` 中 PEFT 格式的 LoRA 适配器：`adapter_config.json` 和 `adapter_model.safetensors`。
+    pub fn read_adapter(&self, dir: impl AsRef<Path>) -> Lora {
+        let dir = dir.as_ref();
+        let config = dir.join("adapter_config.json");
+        let config = File::open(&config)
+            .unwrap_or_else(|_| panic!("Could not open adapter config {}", config.display()));
+        let tensors = dir.join("adapter_model.safetensors");
+        let tensors = File::open(&tensors)
+            .unwrap_or_else(|_| panic!("Could not open adapter {}", tensors.display()));
+        Lora::new(config, tensors, &*self.arguments)
+    }
+
+    /// 把模型按 llama2.c 的 `.bin` 格式写出，`version` 为 0（旧格式）、1（fp32）或 2（Q8_0 量化），
+    /// 不包括 LoRA 适配器。模型不是 llama 结构时报错。
+    pub fn export_bin(&self, version: u32, w: &mut impl Write) -> io::Result<()> {
+        export(&*self.arguments, version, w)
+    }
+
+    /// 换入 LoRA 适配器，`None` 恢复基础模型，返回原来的适配器。不需要重新加载基础模型。
+    ///
+    /// 已有的 kv cache 按原来的权重计算，当前序列的 kv cache 被清空，
+    /// [`Transformer::new_cache`] 创建的其他序列需要调用者自己丢弃。
+    pub fn set_adapter(&mut self, adapter: Option<Lora>) -> Option<Lora> {
+        self.cache.truncate(0).unwrap();
+        std::mem::replace(&mut self.adapter, adapter)
+    }
+
+    /// 设置 kv cache 的存储类型，清空已有的 kv cache。
+    ///
+    /// 需要在 [`Transformer::new_cache`] 创建其他序列之前调用。
+    pub fn set_kv_dtype(&mut self, dtype: KvDtype) {
+        assert_eq!(
+            Rc::strong_count(&self.pool),
+            2,
+            "kv cache dtype must be set before creating other caches"
+        );
+        self.pool = new_pool(&*self.arguments, dtype);
+        self.cache = KvCache::new(self.pool.clone());
+    }
+
+    /// 设置推理的上下文长度，默认为模型的训练长度，清空已有的 kv cache。
+    ///
+    /// 超过训练长度时需要模型配置了 rope 缩放，否则超出的位置质量很差。
+    /// 需要在 [`Transformer::new_cache`] 创建其他序列之前调用。
+    pub fn set_seq_len(&mut self, seq_len: usize) {
+        assert_eq!(
+            Rc::strong_count(&self.pool),
+            2,
+            "seq_len must be set before creating other caches"
+        );
+        self.embedder = RotaryEmbedder::new(&*self.arguments, seq_len);
+        self.cache = KvCache::new(self.pool.clone());
+    }
+
+    /// kv cache 是否保留了从 `pos` 继续推理需要的位置，滑动窗口已经丢弃了需要的位置时只能从头计算。
+    #[inline]
+    pub fn can_resume(&self, pos: usize) -> bool {
+        let window = self.arguments.sliding_window().unwrap_or(usize::MAX);
+        pos <= self.cache.len() && self.cache.can_resume(pos, window)
+    }
+
+    /// kv cache 中已经写入的位置数，[`Transformer::update`] 的 `pos` 不能超过它。
+    #[inline]
+    pub fn cache_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// 回退到只保留前 `len` 个位置，之后从 `len` 继续推理，例如重新生成最后一个回答或丢弃被拒绝的草稿。
+    ///
+    /// 传入更小的 `pos` 推理也会丢弃之后的位置，这个方法还会把不再需要的块放回块池。
+    /// `len` 超过已经写入的位置数，或者从 `len` 继续需要的位置已经滑出窗口时报错，kv cache 不变，
+    /// 调用者可以回退到 0 从头计算。
+    pub fn truncate(&mut self, len: usize) -> Result<(), PositionError> {
+        if len <= self.cache.len() && !self.can_resume(len) {
+            return Err(PositionError(format!(
+                "positions before {} have left the sliding window, cannot resume from {len}",
+                self.cache.start()
+            )));
+        }
+        self.cache.truncate(len)
+    }
+
+    /// 复制当前序列的 kv cache，与当前序列共享已有的块，写入时再复制，用于从同一个前缀尝试多个分支。
+    #[inline]
+    pub fn fork_cache(&self) -> KvCache {
+        self.cache.fork()
+    }
+
+    /// 换入 [`Transformer::fork_cache`] 或 [`Transformer::new_cache`] 得到的 kv cache 作为当前序列，返回原来的 kv cache。
+    pub fn replace_cache(&mut self, cache: KvCache) -> KvCache {
+        assert!(
+            Rc::ptr_eq(cache.pool(), &self.pool),
+            "kv cache belongs to another transformer"
+        );
+        std::mem::replace(&mut self.cache, cache)
+    }
+
+    #[inline]
+    pub fn kv_dtype(&self) -> KvDtype {
+        self.pool.borrow().dtype()
+    }
+
     #[inline]
     pub fn vocab_size(&self) -> usize {
         self.arguments.vocab_size()
     }
 
-    pub fn update(&mut self, tokens: &[utok], pos: upos, logger: &mut impl Logger) -> Vec<f32> {
-        let tok_len = tokens.len();
-        let pos = pos as usize;
+    #[inline]
+    pub fn seq_len(&self) -> usize {
+        self.embedder.seq_len()
+    }
+
+    /// 从位置 0 开始输入 `tokens`，从 `cache` 中公共前缀最长的条目恢复 kv cache，只计算剩余的部分，
+    /// 之后把 `tokens` 的 kv cache 存入 `cache`。返回复用的 token 数，`tokens` 超过上下文长度时报错。
+    pub fn prefill(
+        &mut self,
+        tokens: &[utok],
+        cache: &mut PrefixCache,
+        logger: &mut impl Logger,
+    ) -> Result<usize, PositionError> {
+        let kv_dim = self.arguments.kv_dim();
+        let reused = match cache.lookup(tokens) {
+            Some((entry, len)) => {
+                // 恢复的前缀覆盖 `0..len`，之前的 kv cache 可能已经滑出窗口
+                self.cache = KvCache::new(self.pool.clone());
+                let mut pool = self.pool.borrow_mut();
+                self.cache.prepare(&mut pool, 0, len);
+                for (l, (k, v)) in entry.layers.iter().enumerate() {
+                    let (k, v) = (&k[..len * kv_dim], &v[..len * kv_dim]);
+                    self.cache.write(&mut pool, l, 0, k, v);
+                }
+                self.cache.set_len(len);
+                len
+            }
+            None => 0,
+        };
+        if reused < tokens.len() {
+            self.update(&tokens[reused..], reused as _, logger)?;
+            // 滑动窗口丢弃了前面的位置时，kv cache 不完整，不能给其他提示词复用
+            if self.cache.start() > 0 {
+                return Ok(reused);
+            }
+            let len = tokens.len() * kv_dim;
+            cache.insert(tokens, || {
+                let pool = self.pool.borrow();
+                (0..self.arguments.n_layers())
+                    .map(|l| {
+                        let (mut k, mut v) = (vec![0.; len], vec![0.; len]);
+                        self.cache.read(&pool, l, 0, &mut k, &mut v);
+                        (k, v)
+                    })
+                    .collect()
+            });
+        }
+        Ok(reused)
+    }
 
-        let dim = self.arguments.dim();
-        let hidden_dim = self.arguments.hidden_dim();
-        let seq_len = self.arguments.seq_len();
+    /// 丢弃 kv cache 中 `[keep, keep + discard)` 位置的内容，把 `[keep + discard, len)` 前移 `discard` 个位置。
+    ///
+    /// 移动的 key 按新位置重新旋转，不需要重新计算，之后从 `len - discard` 继续推理。
+    /// 滑动窗口已经丢弃的位置移动之后仍然不参与注意力计算。
+    ///
+    /// 推理的位置不能超过 [`Transformer::seq_len`]，滑动窗口的模型也一样：rope 表和注意力的缓冲区按上下文长度分配。
+    /// 更长的序列在填满上下文时用这个方法前移，之后的位置从前移后的长度开始循环使用，
+    /// rope 只与相对位置有关，窗口内的 token 都保留时结果与在原来的位置继续相同。
+    pub fn shift_cache(&mut self, keep: usize, discard: usize, len: usize) {
+        assert!(keep + discard <= len && len <= self.cache.len());
         let kv_dim = self.arguments.kv_dim();
+        let moved = len - keep - discard;
+        let mut pool = self.pool.borrow_mut();
+        self.cache.prepare(&mut pool, keep, moved);
+        let (mut k, mut v) = (vec![0.; moved * kv_dim], vec![0.; moved * kv_dim]);
+        for l in 0..self.arguments.n_layers() {
+            self.cache.read(&pool, l, keep + discard, &mut k, &mut v);
+            for i in 0..moved {
+                self.embedder
+                    .run_inverse(discard, &mut slice!(k; kv_dim; [i]));
+            }
+            self.cache.write(&mut pool, l, keep, &k, &v);
+        }
+        self.cache.shift_start(keep, discard);
+        self.cache.set_len(len - discard);
+    }
 
-        let n_head = self.arguments.n_heads();
-        let kv_mul = n_head / self.arguments.n_kv_heads();
-        let head_size = dim / n_head;
-        let head_div = 1. / (head_size as f32).sqrt();
+    /// 写出前 `len` 个位置的 kv cache：层数、`kv_dim`、`len`，然后按层依次是 key 和 value。
+    pub fn write_cache(&self, len: usize, w: &mut impl Write) -> io::Result<()> {
+        assert!(len <= self.cache.len());
+        let n_layers = self.arguments.n_layers();
+        let kv_dim = self.arguments.kv_dim();
+        for n in [n_layers, kv_dim, len] {
+            w.write_all(&(n as u32).to_le_bytes())?;
+        }
+        let pool = self.pool.borrow();
+        let (mut k, mut v) = (vec![0.; len * kv_dim], vec![0.; len * kv_dim]);
+        for l in 0..n_layers {
+            self.cache.read(&pool, l, 0, &mut k, &mut v);
+            w.write_all(as_bytes(&k))?;
+            w.write_all(as_bytes(&v))?;
+        }
+        Ok(())
+    }
 
-        let mut s = RunState::new(tok_len, dim, hidden_dim, n_head, seq_len);
-        let h = s.hidden.split_at_mut(tok_len * hidden_dim);
+    /// 读取 [`Transformer::write_cache`] 写出的 kv cache，返回其长度，模型结构不同时报错。
+    pub fn read_cache(&mut self, r: &mut impl Read) -> io::Result<usize> {
+        let mut header = [0u8; 12];
+        r.read_exact(&mut header)?;
+        let [n_layers, kv_dim, len] = [0, 1, 2]
+            .map(|i| u32::from_le_bytes(header[i * 4..][..4].try_into().unwrap()) as usize);
+        if n_layers != self.arguments.n_layers() || kv_dim != self.arguments.kv_dim() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("kv cache of {n_layers} layers x {kv_dim} does not match the model"),
+            ));
+        }
+        if len > self.seq_len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "kv cache of {len} tokens exceeds seq_len {}",
+                    self.seq_len()
+                ),
+            ));
+        }
+        self.cache = KvCache::new(self.pool.clone());
+        let mut pool = self.pool.borrow_mut();
+        self.cache.prepare(&mut pool, 0, len);
+        let (mut k, mut v) = (vec![0.; len * kv_dim], vec![0.; len * kv_dim]);
+        for l in 0..n_layers {
+            r.read_exact(as_bytes_mut(&mut k))?;
+            r.read_exact(as_bytes_mut(&mut v))?;
+            self.cache.write(&mut pool, l, 0, &k, &v);
+        }
+        self.cache.set_len(len);
+        Ok(len)
+    }
 
-        let log_prefix = format!("update_pos={pos}");
-        // logger.log(&[&log_prefix, "tokens"], tokens, &[tok_len]);
+    /// 创建一个空的 kv cache，用于 [`Transformer::forward_batch`] 中的一个序列，块在推理时按需分配。
+    pub fn new_cache(&self) -> KvCache {
+        KvCache::new(self.pool.clone())
+    }
+
+    /// 从 `pos` 输入 `tokens`，返回每个 token 的隐藏状态：`tokens.len() x dim`。
+    ///
+    /// `pos` 越过 kv cache 中已经写入的位置、需要的位置已经滑出窗口或者超出上下文长度时报错，kv cache 不变。
+    /// 记录中间结果的调用都已注释掉，`_logger` 不记录任何内容。
+    pub fn update(
+        &mut self,
+        tokens: &[utok],
+        pos: upos,
+        _logger: &mut impl Logger,
+    ) -> Result<Vec<f32>, PositionError> {
+        let mut batch = [(&mut self.cache, tokens, pos as usize)];
+        let adapter = self.adapter.as_ref();
+        run(&*self.arguments, adapter, &self.embedder, &mut batch)
+    }
 
-        for (i, &token) in tokens.iter().enumerate() {
-            slice!(s.x0; dim; [i]).copy_from_slice(self.arguments.token_embedding_table(token));
+    pub fn forward(
+        &mut self,
+        token: utok,
+        pos: upos,
+        logger: &mut impl Logger,
+    ) -> Result<&mut [f32], PositionError> {
+        let mut x = self.update(&[token], pos, logger)?;
+        logits(&*self.arguments, &mut x, &mut self.logits);
+        Ok(&mut self.logits)
+    }
+
+    /// 从 `pos` 输入 `tokens`，返回每个 token 的 logits：`tokens.len() x vocab_size`。
+    ///
+    /// 用于一次推理验证投机解码的草稿模型提出的多个 token。
+    pub fn forward_all(
+        &mut self,
+        tokens: &[utok],
+        pos: upos,
+        logger: &mut impl Logger,
+    ) -> Result<Vec<f32>, PositionError> {
+        let mut x = self.update(tokens, pos, logger)?;
+        let mut logits_ = vec![0.; tokens.len() * self.vocab_size()];
+        logits(&*self.arguments, &mut x, &mut logits_);
+        Ok(logits_)
+    }
+
+    /// 批量推理多个相互独立的序列，每个序列使用自己的 kv cache 从位置 `pos` 输入 `tokens`，
+    /// 可以同时包含预填充和逐个 token 的解码。
+    ///
+    /// 权重矩阵乘对所有序列的 token 一起计算，注意力在每个序列的 kv cache 上分别计算。
+    /// 返回每个序列最后一个 token 的 logits：`batch x vocab_size`，任何一个序列的位置不能推理时报错，所有 kv cache 不变。
+    /// `_logger` 与 [`Transformer::update`] 一样不记录任何内容。
+    pub fn forward_batch(
+        &self,
+        batch: &mut [(&mut KvCache, &[utok], upos)],
+        _logger: &mut impl Logger,
+    ) -> Result<Vec<f32>, PositionError> {
+        let dim = self.arguments.dim();
+        let mut seqs = batch
+            .iter_mut()
+            .map(|(cache, tokens, pos)| {
+                assert!(!tokens.is_empty());
+                (&mut **cache, *tokens, *pos as usize)
+            })
+            .collect::<Vec<_>>();
+        let adapter = self.adapter.as_ref();
+        let x0 = run(&*self.arguments, adapter, &self.embedder, &mut seqs)?;
+
+        // 取出每个序列最后一个 token 的状态
+        let mut x = Vec::with_capacity(batch.len() * dim);
+        let mut row = 0;
+        for (_, tokens, _) in batch.iter() {
+            row += tokens.len();
+            x.extend_from_slice(&slice!(x0; dim; [row - 1]));
         }
-        // logger.log(&[&log_prefix, "embedding"], &s.x0, &[tok_len, dim]);
+        let mut logits_ = vec![0.; batch.len() * self.vocab_size()];
+        logits(&*self.arguments, &mut x, &mut logits_);
+        Ok(logits_)
+    }
+}
 
-        for (l, layer) in self.layers.iter_mut().enumerate() {
-            let Layer { k_cache, v_cache } = layer;
+/// 批量计算多个序列，每个序列是 `(kv cache, tokens, pos)`，返回所有 token 的隐藏状态：`Σtok_len x dim`。
+///
+/// 计算之前检查所有序列的位置，有不能推理的位置时报错，不修改任何 kv cache。
+fn run(
+    arguments: &dyn Arguments,
+    adapter: Option<&Lora>,
+    embedder: &RotaryEmbedder,
+    seqs: &mut [(&mut KvCache, &[utok], usize)],
+) -> Result<Vec<f32>, PositionError> {
+    let seq_len = embedder.seq_len();
+    let window = arguments.sliding_window().unwrap_or(usize::MAX);
+    for (cache, tokens, pos) in seqs.iter() {
+        if *pos + tokens.len() > seq_len {
+            return Err(PositionError(format!(
+                "positions {pos}..{} exceed seq_len {seq_len}",
+                *pos + tokens.len()
+            )));
+        }
+        if *pos > cache.len() {
+            return Err(PositionError(format!(
+                "position {pos} skips past the {} positions in kv cache",
+                cache.len()
+            )));
+        }
+        if !cache.can_resume(*pos, window) {
+            return Err(PositionError(format!(
+                "positions before {} have left the sliding window, cannot resume from {pos}",
+                cache.start()
+            )));
+        }
+    }
 
-            let log_layer = format!("layer={l}");
+    let tok_len = seqs
+        .iter()
+        .map(|(_, tokens, _)| tokens.len())
+        .sum::<usize>();
+    let max_len = seqs
+        .iter()
+        .map(|(_, tokens, _)| tokens.len())
+        .max()
+        .unwrap_or(0);
+
+    let dim = arguments.dim();
+    let q_dim = arguments.q_dim();
+    let kv_dim = arguments.kv_dim();
+
+    let n_head = arguments.n_heads();
+    let kv_mul = n_head / arguments.n_kv_heads();
+    let head_size = arguments.head_size();
+    let head_div = 1. / (head_size as f32).sqrt();
+    let eps = arguments.rms_norm_eps();
+    let arch = arguments.architecture();
+
+    let mut s = RunState::new(arguments, tok_len, max_len, seq_len);
+    // MoE 的路由得分，以及单个专家的输入和输出
+    let n_experts = arguments.n_experts();
+    let mut router = vec![0.; tok_len * n_experts];
+    let mut expert_buf = vec![0.; if n_experts > 0 { 2 * tok_len * dim } else { 0 }];
+
+    // let log_prefix = format!("update_len={tok_len}");
+    // logger.log(&[&log_prefix, "tokens"], tokens, &[tok_len]);
+
+    for (i, &token) in seqs.iter().flat_map(|(_, tokens, _)| *tokens).enumerate() {
+        slice!(s.x0; dim; [i]).copy_from_slice(arguments.token_embedding_table(token));
+    }
+    if arch.embedding_scale != 1. {
+        s.x0.iter_mut().for_each(|x| *x *= arch.embedding_scale);
+    }
+    // logger.log(&[&log_prefix, "embedding"], &s.x0, &[tok_len, dim]);
+
+    // 所有序列的 kv cache 来自同一个块池，先释放滑出窗口的位置，再为新的位置准备好块
+    let shared = seqs[0].0.pool().clone();
+    let mut pool = shared.borrow_mut();
+    for (cache, tokens, pos) in seqs.iter_mut() {
+        assert!(Rc::ptr_eq(cache.pool(), &shared));
+        cache.slide(&mut pool, *pos, window);
+        cache.prepare(&mut pool, *pos, tokens.len());
+    }
+    // 非 f32 存储的块反量化到这里
+    let mut buf = vec![0.; pool.layer_len()];
+
+    for l in 0..arguments.n_layers() {
+        // let log_layer = format!("layer={l}");
+
+        // x1 = norm(x0, rms_att_weight[l]);
+        let (w, b) = (arguments.rms_att_weight(l), arguments.att_norm_bias(l));
+        normalize(arch.norm, &mut s.x1, &s.x0, w, b, eps);
+        // logger.log(
+        //     &[&log_prefix, &log_layer, "input_rmsnorm"],
+        //     &s.x1,
+        //     &[tok_len, dim],
+        // );
+        {
+            let k = dim;
+            let n = tok_len;
+            let b = s.x1.as_ptr();
+            let rsb = 1;
+            let csb = k as _;
+            let alpha = 1.;
+            let beta = 0.;
+            // q = wq[l] * x1;
+            let m = q_dim;
+            let rsa = k as _;
+            let csa = 1;
+            let rsc = 1;
+            let csc = m as _;
+            let a = arguments.wq(l).as_ptr();
+            let c = s.q.as_mut_ptr();
+            unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+            // logger.log(&[&log_prefix, &log_layer, "q"], &s.q, &[tok_len, dim]);
+            // k = wk[l] * x1;
+            let m = kv_dim;
+            let rsa = k as _;
+            let csa = 1;
+            let rsc = 1;
+            let csc = m as _;
+            let a = arguments.wk(l).as_ptr();
+            let c = s.k.as_mut_ptr();
+            unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+            // logger.log(&[&log_prefix, &log_layer, "k"], &s.k, &[tok_len, kv_dim]);
+            // v = wv[l] * x1;
+            let a = arguments.wv(l).as_ptr();
+            let c = s.v.as_mut_ptr();
+            unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+            // logger.log(&[&log_prefix, &log_layer, "v"], &s.v, &[tok_len, kv_dim]);
+        }
+        // q += lora_q(x1); k += lora_k(x1); v += lora_v(x1);
+        low_rank(adapter, l, Proj::Q, &s.x1, dim, &mut s.q, q_dim, tok_len);
+        low_rank(adapter, l, Proj::K, &s.x1, dim, &mut s.k, kv_dim, tok_len);
+        low_rank(adapter, l, Proj::V, &s.x1, dim, &mut s.v, kv_dim, tok_len);
+        // q += bq[l]; k += bk[l]; v += bv[l];
+        add_bias(&mut s.q, arguments.bq(l));
+        add_bias(&mut s.k, arguments.bk(l));
+        add_bias(&mut s.v, arguments.bv(l));
+
+        // 每个序列在自己的 kv cache 上计算注意力
+        let mut row = 0;
+        for (cache, tokens, pos) in seqs.iter() {
+            let pos = *pos;
+            let tok_len = tokens.len();
+            let att_len = pos + tok_len;
+            // 只计算从 kv cache 保留的第一个位置所在的块开始的注意力
+            let start = cache.start();
+            let first = start / BLOCK_SIZE;
 
-            // x1 = rmsnorm(x0, rms_att_weight[l]);
-            rmsnorm(&mut s.x1, &s.x0, self.arguments.rms_att_weight(l));
-            // logger.log(
-            //     &[&log_prefix, &log_layer, "input_rmsnorm"],
-            //     &s.x1,
-            //     &[tok_len, dim],
-            // );
-            {
-                let k = dim;
-                let n = tok_len;
-                let b = s.x1.as_ptr();
-                let rsb = 1;
-                let csb = k as _;
-                let alpha = 1.;
-                let beta = 0.;
-                // q = wq[l] * x1;
-                let m = dim;
-                let rsa = k as _;
-                let csa = 1;
-                let rsc = 1;
-                let csc = m as _;
-                let a = self.arguments.wq(l).as_ptr();
-                let c = s.q.as_mut_ptr();
-                unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
-                // logger.log(&[&log_prefix, &log_layer, "q"], &s.q, &[tok_len, dim]);
-                // k = wk[l] * x1;
-                let m = kv_dim;
-                let rsa = k as _;
-                let csa = 1;
-                let rsc = 1;
-                let csc = m as _;
-                let a = self.arguments.wk(l).as_ptr();
-                let c = slice!(k_cache; kv_dim; [pos]).as_mut_ptr();
-                unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
-                // logger.log(&[&log_prefix, &log_layer, "k"], k_cache, &[seq_len, kv_dim]);
-                // v = wv[l] * x1;
-                let a = self.arguments.wv(l).as_ptr();
-                let c = slice!(v_cache; kv_dim; [pos]).as_mut_ptr();
-                unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
-                // logger.log(&[&log_prefix, &log_layer, "v"], v_cache, &[seq_len, kv_dim]);
-            }
             // rotary embeddings
             for i in 0..tok_len {
-                let pos = pos + i;
-                self.embedder.run(pos, &mut slice!(s.q    ; dim   ; [i]  ));
-                self.embedder.run(pos, &mut slice!(k_cache; kv_dim; [pos]));
+                embedder.run(pos + i, &mut slice!(s.q; q_dim ; [row + i]));
+                embedder.run(pos + i, &mut slice!(s.k; kv_dim; [row + i]));
             }
-            // logger.log(
-            //     &[&log_prefix, &log_layer, "rotary_q"],
-            //     &s.q,
-            //     &[tok_len, dim],
-            // );
-            // logger.log(
-            //     &[&log_prefix, &log_layer, "rotary_k"],
-            //     &k_cache,
-            //     &[seq_len, kv_dim],
-            // );
-            // clear x1 for multi-head attention.
+            let kv = row * kv_dim..(row + tok_len) * kv_dim;
+            cache.write(&mut pool, l, pos, &s.k[kv.clone()], &s.v[kv]);
+
+            // multi-head attention, write back to q.
+            let q = &s.q[row * q_dim..];
+            let blocks = cache.blocks(att_len);
+            let att_stride = max_len * seq_len;
+            // att = head_div * q * k; 逐块计算，每个块只反量化一次
+            for (j, &id) in blocks.iter().enumerate().skip(first) {
+                let k_block = pool.k(id, l, &mut buf);
+                for h in 0..n_head {
+                    let att = &mut slice!(s.attention; att_stride; [h]);
+                    let m = tok_len;
+                    let k = head_size;
+                    let n = BLOCK_SIZE.min(att_len - j * BLOCK_SIZE);
+                    let alpha = head_div;
+                    let beta = 0.;
+                    let a = slice!(q; head_size; [h]).as_ptr();
+                    let b = slice!(k_block; head_size; [h / kv_mul]).as_ptr();
+                    let c = att[j * BLOCK_SIZE..].as_mut_ptr();
+                    let rsa = q_dim as _;
+                    let csa = 1;
+                    let rsb = 1;
+                    let csb = kv_dim as _;
+                    let rsc = seq_len as _;
+                    let csc = 1;
+                    unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+                }
+            }
+            // att = softmax(att); 每个位置关注窗口内的位置
             for h in 0..n_head {
-                let att = &mut slice!(s.attention; tok_len * seq_len; [h]);
-                let att_len = pos + tok_len;
-                // att = head_div * q * k;
-                let m = tok_len;
-                let k = head_size;
-                let n = att_len;
-                let alpha = head_div;
-                let beta = 0.;
-                let a = slice!(s.q; head_size; [h]).as_ptr();
-                let b = slice!(k_cache; head_size; [h / kv_mul]).as_ptr();
-                let c = att.as_mut_ptr();
-                let rsa = dim as _;
-                let csa = 1;
-                let rsb = 1;
-                let csb = kv_dim as _;
-                let rsc = seq_len as _;
-                let csc = 1;
-                unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
-                // att = softmax(att);
+                let att = &mut slice!(s.attention; att_stride; [h]);
                 for i in 0..tok_len {
-                    let att = &mut slice!(att; seq_len; [i])[..att_len];
-                    let (att, tail) = att.split_at_mut(pos + i + 1);
+                    let att = &mut slice!(att; seq_len; [i])[first * BLOCK_SIZE..att_len];
+                    let begin = (pos + i + 1).saturating_sub(window).max(start);
+                    let (head, att) = att.split_at_mut(begin - first * BLOCK_SIZE);
+                    let (att, tail) = att.split_at_mut(pos + i + 1 - begin);
+                    head.fill(0.);
                     softmax(att);
                     tail.fill(0.);
                 }
-                // x1 = att * v;
-                let m = head_size;
-                let k = att_len;
-                let n = tok_len;
-                let alpha = 1.;
-                let beta = 0.;
-                let a = slice!(v_cache; head_size; [h / kv_mul]).as_ptr();
-                let b = att.as_ptr();
-                let c = slice!(s.x1; head_size; [h]).as_mut_ptr();
-                let rsa = 1;
-                let csa = kv_dim as _;
-                let rsb = 1;
-                let csb = seq_len as _;
-                let rsc = 1;
-                let csc = dim as _;
-                unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
             }
-            // logger.log(
-            //     &[&log_prefix, &log_layer, "after_attention"],
-            //     &s.x1,
-            //     &[tok_len, dim],
-            // );
-            // x0 += wo[l] * x1;
-            {
-                let m = dim;
-                let k = dim;
-                let n = tok_len;
-                let alpha = 1.;
-                let beta = 1.;
-                let a = self.arguments.wo(l).as_ptr();
-                let b = s.x1.as_ptr();
-                let c = s.x0.as_mut_ptr();
-                let rsa = k as _;
-                let csa = 1;
-                let rsb = 1;
-                let csb = k as _;
-                let rsc = 1;
-                let csc = m as _;
-                unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+            // q = att * v; 逐块累加，q 已经用完，x1 保留给并行结构的前馈网络
+            let o = &mut s.q[row * q_dim..];
+            for (j, &id) in blocks.iter().enumerate().skip(first) {
+                let v_block = pool.v(id, l, &mut buf);
+                for h in 0..n_head {
+                    let att = &slice!(s.attention; att_stride; [h]);
+                    let m = head_size;
+                    let k = BLOCK_SIZE.min(att_len - j * BLOCK_SIZE);
+                    let n = tok_len;
+                    let alpha = 1.;
+                    let beta = if j == first { 0. } else { 1. };
+                    let a = slice!(v_block; head_size; [h / kv_mul]).as_ptr();
+                    let b = att[j * BLOCK_SIZE..].as_ptr();
+                    let c = slice!(o; head_size; [h]).as_mut_ptr();
+                    let rsa = 1;
+                    let csa = kv_dim as _;
+                    let rsb = 1;
+                    let csb = seq_len as _;
+                    let rsc = 1;
+                    let csc = q_dim as _;
+                    unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+                }
             }
-            // logger.log(&[&log_prefix, &log_layer, "o"], &s.x0, &[tok_len, dim]);
-            // x1 = rmsnorm(x0, rms_ffn_weight[l]);
-            rmsnorm(&mut s.x1, &s.x0, self.arguments.rms_ffn_weight(l));
-            // logger.log(
-            //     &[&log_prefix, &log_layer, "post_norm"],
-            //     &s.x1,
-            //     &[tok_len, dim],
-            // );
+            row += tok_len;
+        }
+        // logger.log(
+        //     &[&log_prefix, &log_layer, "after_attention"],
+        //     &s.q,
+        //     &[tok_len, q_dim],
+        // );
+        // x0 += wo[l] * q;
+        {
+            let m = dim;
+            let k = q_dim;
+            let n = tok_len;
+            let alpha = 1.;
+            let beta = 1.;
+            let a = arguments.wo(l).as_ptr();
+            let b = s.q.as_ptr();
+            let c = s.x0.as_mut_ptr();
+            let rsa = k as _;
+            let csa = 1;
+            let rsb = 1;
+            let csb = k as _;
+            let rsc = 1;
+            let csc = m as _;
+            unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+        }
+        // x0 += lora_o(q);
+        low_rank(adapter, l, Proj::O, &s.q, q_dim, &mut s.x0, dim, tok_len);
+        // x0 += bo[l];
+        add_bias(&mut s.x0, arguments.bo(l));
+        // logger.log(&[&log_prefix, &log_layer, "o"], &s.x0, &[tok_len, dim]);
+        // 并行结构的前馈网络与注意力使用同一个归一化的输入
+        if !arch.parallel {
+            // x1 = norm(x0, rms_ffn_weight[l]);
+            let (w, b) = (arguments.rms_ffn_weight(l), arguments.ffn_norm_bias(l));
+            normalize(arch.norm, &mut s.x1, &s.x0, w, b, eps);
+        }
+        // logger.log(
+        //     &[&log_prefix, &log_layer, "post_norm"],
+        //     &s.x1,
+        //     &[tok_len, dim],
+        // );
+        if n_experts == 0 {
+            // x0 += ffn(x1);
+            feed_forward(arguments, adapter, l, None, &s.x1, &mut s.hidden, &mut s.x0);
+        } else {
+            // router = moe_gate[l] * x1;
             {
-                let m = hidden_dim;
+                let m = n_experts;
                 let k = dim;
                 let n = tok_len;
                 let alpha = 1.;
                 let beta = 0.;
+                let a = arguments.moe_gate(l).as_ptr();
                 let b = s.x1.as_ptr();
+                let c = router.as_mut_ptr();
                 let rsa = k as _;
                 let csa = 1;
                 let rsb = 1;
                 let csb = k as _;
                 let rsc = 1;
                 let csc = m as _;
-                // h0 = w1[l] * x1;
-                let a = self.arguments.w1(l).as_ptr();
-                let c = h.0.as_mut_ptr();
                 unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
-                // logger.log(
-                //     &[&log_prefix, &log_layer, "gate"],
-                //     h.0,
-                //     &[tok_len, hidden_dim],
-                // );
-                // h1 = w3[l] * x1;
-                let a = self.arguments.w3(l).as_ptr();
-                let c = h.1.as_mut_ptr();
-                unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
-                // logger.log(
-                //     &[&log_prefix, &log_layer, "up"],
-                //     h.1,
-                //     &[tok_len, hidden_dim],
-                // );
             }
-            // h0 *= sigmoid(h0) * h1;
-            zip(&mut *h.0, &*h.1).for_each(|(h0, h1)| *h0 *= sigmoid(*h0) * *h1);
-            // logger.log(
-            //     &[&log_prefix, &log_layer, "swiglu"],
-            //     h.0,
-            //     &[tok_len, hidden_dim],
-            // );
-            // x0 += w2[l] * h0;
-            {
-                let m = dim;
-                let k = hidden_dim;
-                let n = tok_len;
-                let alpha = 1.;
-                let beta = 1.;
-                let a = self.arguments.w2(l).as_ptr();
-                let b = h.0.as_ptr();
-                let c = s.x0.as_mut_ptr();
-                let rsa = k as _;
-                let csa = 1;
-                let rsb = 1;
-                let csb = k as _;
-                let rsc = 1;
-                let csc = m as _;
-                unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+            // 每个 token 选出得分最高的 k 个专家，对这 k 个得分做 softmax 作为专家的权重
+            let mut routes = vec![Vec::new(); n_experts];
+            for i in 0..tok_len {
+                let scores = &slice!(router; n_experts; [i]);
+                let mut top = (0..n_experts).collect::<Vec<_>>();
+                top.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+                top.truncate(arguments.n_experts_per_tok());
+                let mut weights = top.iter().map(|&e| scores[e]).collect::<Vec<_>>();
+                softmax(&mut weights);
+                for (e, w) in zip(top, weights) {
+                    routes[e].push((i, w));
+                }
+            }
+            // 每个专家只计算路由到它的 token，x0 += Σ weight * expert(x1);
+            for (e, routes) in routes.iter().enumerate() {
+                if routes.is_empty() {
+                    continue;
+                }
+                let n = routes.len();
+                let (x, y) = expert_buf.split_at_mut(tok_len * dim);
+                let (x, y) = (&mut x[..n * dim], &mut y[..n * dim]);
+                for (j, &(i, _)) in routes.iter().enumerate() {
+                    slice!(x; dim; [j]).copy_from_slice(&slice!(s.x1; dim; [i]));
+                }
+                y.fill(0.);
+                feed_forward(arguments, None, l, Some(e), x, &mut s.hidden, y);
+                for (j, &(i, w)) in routes.iter().enumerate() {
+                    zip(&mut slice!(s.x0; dim; [i]), &slice!(y; dim; [j]))
+                        .for_each(|(x, y)| *x += w * y);
+                }
             }
-            // logger.log(
-            //     &[&log_prefix, &log_layer, "mlp_down"],
-            //     &s.x0,
-            //     &[tok_len, dim],
-            // );
         }
+        // logger.log(
+        //     &[&log_prefix, &log_layer, "mlp_down"],
+        //     &s.x0,
+        //     &[tok_len, dim],
+        // );
+    }
+    // 之前写入的 pos 之后的位置作废
+    for (cache, tokens, pos) in seqs.iter_mut() {
+        cache.set_len(*pos + tokens.len());
+    }
+
+    Ok(s.x0)
+}
 
-        s.x0
+/// 前馈网络，`y += w2 * (act(w1 * x + b1) * (w3 * x + b3)) + b2`，`x` 和 `y` 为 `n x dim`，没有门控时没有 `w3` 的部分。
+///
+/// `expert` 为 `None` 时使用稠密模型的权重和偏置，否则使用 MoE 第 `expert` 个专家的权重。
+/// LoRA 适配器只作用于稠密模型的前馈网络。
+#[allow(clippy::too_many_arguments)]
+fn feed_forward(
+    arguments: &dyn Arguments,
+    adapter: Option<&Lora>,
+    layer: usize,
+    expert: Option<usize>,
+    x: &[f32],
+    hidden: &mut [f32],
+    y: &mut [f32],
+) {
+    let dim = arguments.dim();
+    let hidden_dim = arguments.hidden_dim();
+    let activation = arguments.architecture().activation;
+    let (w1, w2, b1, b2, b3) = match expert {
+        None => (
+            arguments.w1(layer),
+            arguments.w2(layer),
+            arguments.b1(layer),
+            arguments.b2(layer),
+            arguments.b3(layer),
+        ),
+        Some(e) => (
+            arguments.expert_w1(layer, e),
+            arguments.expert_w2(layer, e),
+            None,
+            None,
+            None,
+        ),
+    };
+    let len = x.len() / dim * hidden_dim;
+    let h = hidden[..len * 2].split_at_mut(len);
+    {
+        let m = hidden_dim;
+        let k = dim;
+        let n = x.len() / dim;
+        let alpha = 1.;
+        let beta = 0.;
+        let b = x.as_ptr();
+        let rsa = k as _;
+        let csa = 1;
+        let rsb = 1;
+        let csb = k as _;
+        let rsc = 1;
+        let csc = m as _;
+        // h0 = w1 * x;
+        let a = w1.as_ptr();
+        let c = h.0.as_mut_ptr();
+        unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+        if activation.gated() {
+            // h1 = w3 * x;
+            let a = match expert {
+                None => arguments.w3(layer),
+                Some(e) => arguments.expert_w3(layer, e),
+            }
+            .as_ptr();
+            let c = h.1.as_mut_ptr();
+            unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+        }
     }
+    // h0 += lora_gate(x); h1 += lora_up(x);
+    let n = x.len() / dim;
+    low_rank(adapter, layer, Proj::Gate, x, dim, h.0, hidden_dim, n);
+    low_rank(adapter, layer, Proj::Up, x, dim, h.1, hidden_dim, n);
+    // h0 += b1; h1 += b3;
+    add_bias(h.0, b1);
+    add_bias(h.1, b3);
+    match activation {
+        // h0 *= sigmoid(h0) * h1;
+        Activation::SiLU => zip(&mut *h.0, &*h.1).for_each(|(h0, h1)| *h0 *= sigmoid(*h0) * *h1),
+        // h0 = gelu(h0) * h1;
+        Activation::GeGLU => zip(&mut *h.0, &*h.1).for_each(|(h0, h1)| *h0 = gelu(*h0) * *h1),
+        // h0 = gelu(h0);
+        Activation::Gelu => h.0.iter_mut().for_each(|h0| *h0 = gelu(*h0)),
+    }
+    // y += w2 * h0;
+    {
+        let m = dim;
+        let k = hidden_dim;
+        let n = x.len() / dim;
+        let alpha = 1.;
+        let beta = 1.;
+        let a = w2.as_ptr();
+        let b = h.0.as_ptr();
+        let c = y.as_mut_ptr();
+        let rsa = k as _;
+        let csa = 1;
+        let rsb = 1;
+        let csb = k as _;
+        let rsc = 1;
+        let csc = m as _;
+        unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+    }
+    // y += lora_down(h0);
+    low_rank(adapter, layer, Proj::Down, h.0, hidden_dim, y, dim, n);
+    // y += b2;
+    add_bias(y, b2);
+}
 
-    pub fn forward(&mut self, token: utok, pos: upos, logger: &mut impl Logger) -> &mut [f32] {
-        let mut x = self.update(&[token], pos, logger);
+/// LoRA 的低秩旁路：`y += B * (A * x)`，`x` 为 `n x k`，`y` 为 `n x m`，`B` 已经乘上缩放系数。
+///
+/// 适配器没有第 `layer` 层的 `proj` 投影时什么也不做。
+#[allow(clippy::too_many_arguments)]
+fn low_rank(
+    adapter: Option<&Lora>,
+    layer: usize,
+    proj: Proj,
+    x: &[f32],
+    k: usize,
+    y: &mut [f32],
+    m: usize,
+    n: usize,
+) {
+    let Some((a, b)) = adapter.and_then(|adapter| adapter.get(layer, proj)) else {
+        return;
+    };
+    let r = a.len() / k;
+    let mut t = vec![0.; n * r];
+    // t = A * x;
+    {
+        let m = r;
+        let alpha = 1.;
+        let beta = 0.;
+        let a = a.as_ptr();
+        let b = x.as_ptr();
+        let c = t.as_mut_ptr();
+        let rsa = k as _;
+        let csa = 1;
+        let rsb = 1;
+        let csb = k as _;
+        let rsc = 1;
+        let csc = m as _;
+        unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+    }
+    // y += B * t;
+    {
+        let k = r;
+        let alpha = 1.;
+        let beta = 1.;
+        let a = b.as_ptr();
+        let b = t.as_ptr();
+        let c = y.as_mut_ptr();
+        let rsa = k as _;
+        let csa = 1;
+        let rsb = 1;
+        let csb = k as _;
+        let rsc = 1;
+        let csc = m as _;
+        unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+    }
+}
 
-        let log_prefix = format!("forward_pos={pos}");
+/// 按模型结构的归一化方式归一化 `x` 的每一行到 `o`，只有 layernorm 使用偏置。
+fn normalize(norm: Norm, o: &mut [f32], x: &[f32], w: &[f32], b: Option<&[f32]>, eps: f32) {
+    match norm {
+        Norm::RmsNorm => rmsnorm(o, x, w, eps),
+        Norm::GemmaRmsNorm => rmsnorm_unit_offset(o, x, w, eps),
+        Norm::LayerNorm => layernorm(o, x, w, b, eps),
+    }
+}
 
-        rmsnorm_inplace(&mut x, self.arguments.rms_final_weight());
-        // logger.log(&[&log_prefix, "model_norm"], &x, &[self.arguments.dim()]);
+fn new_pool(arguments: &dyn Arguments, dtype: KvDtype) -> Rc<RefCell<BlockPool>> {
+    Rc::new(RefCell::new(BlockPool::new(
+        arguments.n_layers(),
+        arguments.kv_dim(),
+        arguments.head_size(),
+        dtype,
+    )))
+}
 
-        // logits = wcls * x;
-        {
-            let m = self.logits.len();
-            let k = x.len();
-            let n = 1;
-            let alpha = 1.;
-            let beta = 0.;
-            let a = self.arguments.wcls().as_ptr();
-            let b = x.as_ptr();
-            let c = self.logits.as_mut_ptr();
-            let rsa = k as _;
-            let csa = 1;
-            let rsb = 1;
-            let csb = k as _;
-            let rsc = 1;
-            let csc = m as _;
-            unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+/// 由隐藏状态 `x`（`n x dim`）计算 logits（`n x vocab_size`）。
+fn logits(arguments: &dyn Arguments, x: &mut [f32], logits: &mut [f32]) {
+    let y = x.to_vec();
+    let (w, b) = (arguments.rms_final_weight(), arguments.final_norm_bias());
+    normalize(
+        arguments.architecture().norm,
+        x,
+        &y,
+        w,
+        b,
+        arguments.rms_norm_eps(),
+    );
+    // logger.log(&["model_norm"], x, &[x.len() / arguments.dim()]);
+
+    // logits = wcls * x;
+    {
+        let m = arguments.vocab_size();
+        let k = arguments.dim();
+        let n = x.len() / k;
+        let alpha = 1.;
+        let beta = 0.;
+        let a = arguments.wcls().as_ptr();
+        let b = x.as_ptr();
+        let c = logits.as_mut_ptr();
+        let rsa = k as _;
+        let csa = 1;
+        let rsb = 1;
+        let csb = k as _;
+        let rsc = 1;
+        let csc = m as _;
+        unsafe { gemm(m, k, n, alpha, a, rsa, csa, b, rsb, csb, beta, c, rsc, csc) };
+    }
+    // logits += bcls;
+    add_bias(logits, arguments.bcls());
+    // logger.log(&["logits"], logits, &[n, vocab_size]);
+}
+
+#[inline]
+fn as_bytes(data: &[f32]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr().cast(), std::mem::size_of_val(data)) }
+}
+
+#[inline]
+fn as_bytes_mut(data: &mut [f32]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr().cast(), std::mem::size_of_val(data)) }
+}
+
+#[test]
+fn test_kv_dtype_perplexity() {
+    use crate::arguments::TinyModel;
+
+    let model = TinyModel::new(serde_json::json!({}), 0x6b76);
+    let mut transformer = model.load("kv-dtype");
+    // 伪随机的 token 序列，跨越多个 kv cache 块
+    let tokens = (0..60)
+        .map(|i| (i * 7 + i * i % 5) as utok % transformer.vocab_size() as utok)
+        .collect::<Vec<_>>();
+
+    let mut run = |dtype| {
+        transformer.set_kv_dtype(dtype);
+        let mut logits = Vec::new();
+        let mut nll = 0.;
+        for (pos, pair) in tokens.windows(2).enumerate() {
+            let x = transformer.forward(pair[0], pos as _, &mut ()).unwrap();
+            logits.extend_from_slice(x);
+            softmax(x);
+            nll -= x[pair[1] as usize].ln();
         }
-        // logger.log(&[&log_prefix, "logits"], &self.logits, &[self.vocab_size()]);
+        (logits, (nll / (tokens.len() - 1) as f32).exp())
+    };
+    let (f32_logits, f32) = run(KvDtype::F32);
+    let max_diff = |logits: &[f32]| {
+        zip(logits, &f32_logits)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0., f32::max)
+    };
+    let (f16_logits, f16) = run(KvDtype::F16);
+    let (int8_logits, int8) = run(KvDtype::Int8);
+    assert!(max_diff(&f16_logits) < 1e-2);
+    assert!(max_diff(&int8_logits) < 1e-1);
+    assert!((f16 / f32 - 1.).abs() < 1e-3, "f32: {f32}, f16: {f16}");
+    assert!((int8 / f32 - 1.).abs() < 2e-2, "f32: {f32}, int8: {int8}");
+}
+
+#[test]
+fn test_forward_batch() {
+    use crate::arguments::TinyModel;
 
-        &mut self.logits
+    let model = TinyModel::new(serde_json::json!({}), 0xba7c);
+    let mut transformer = model.load("forward-batch");
+    // 长度不同的序列，其中一个跨越 kv cache 的块边界
+    let prompts: [&[utok]; 3] = [&[1, 4, 7], &[1; 20], &[9]];
+    let steps: [&[utok]; 3] = [&[3], &[5, 6, 7], &[11]];
+
+    // 逐个序列单独推理
+    let mut expected = Vec::new();
+    for (prompt, step) in zip(prompts, steps) {
+        let cache = transformer.new_cache();
+        let _ = transformer.replace_cache(cache);
+        for (tokens, pos) in [(prompt, 0), (step, prompt.len())] {
+            let logits = transformer.forward_all(tokens, pos as _, &mut ()).unwrap();
+            let vocab_size = transformer.vocab_size();
+            expected.push(logits[logits.len() - vocab_size..].to_vec());
+        }
+    }
+
+    // 批量推理，预填充和解码的长度混合
+    let mut caches = prompts.map(|_| transformer.new_cache());
+    let mut actual = vec![Vec::new(); expected.len()];
+    for round in 0..2 {
+        let mut batch = zip(&mut caches, zip(prompts, steps))
+            .map(|(cache, (prompt, step))| match round {
+                0 => (cache, prompt, 0),
+                _ => (cache, step, prompt.len() as upos),
+            })
+            .collect::<Vec<_>>();
+        let logits = transformer.forward_batch(&mut batch, &mut ()).unwrap();
+        for (i, logits) in logits.chunks(transformer.vocab_size()).enumerate() {
+            actual[2 * i + round] = logits.to_vec();
+        }
+    }
+    for (actual, expected) in zip(actual, expected) {
+        for (a, b) in zip(actual, expected) {
+            assert!((a - b).abs() < 1e-4, "batch: {a}, sequential: {b}");
+        }
+    }
+}
+
+#[test]
+fn test_moe() {
+    use crate::arguments::TinyModel;
+    use serde_json::json;
+
+    let (dim, hidden_dim, n_layers, n_experts) = (16, 32, 2, 4);
+    let tokens = (0..20).map(|i| i * 3 % 32).collect::<Vec<utok>>();
+    let check = |moe: &TinyModel, dense: &TinyModel, name: &str| {
+        let mut moe = moe.load(&format!("{name}-moe"));
+        let mut dense = dense.load(&format!("{name}-dense"));
+        let a = moe.forward_all(&tokens, 0, &mut ()).unwrap();
+        let b = dense.forward_all(&tokens, 0, &mut ()).unwrap();
+        for (a, b) in zip(a, b) {
+            assert!((a - b).abs() < 1e-4, "moe: {a}, dense: {b}");
+        }
+    };
+    // 把 MoE 模型第 `l` 层的前馈网络替换为稠密模型的 `gate`、`up`、`down`
+    let densify = |moe: &TinyModel, ffn: &dyn Fn(usize) -> [(Vec<usize>, Vec<f32>); 3]| {
+        let mut tensors = moe.tensors.clone();
+        tensors.retain(|name, _| !name.contains("block_sparse_moe"));
+        for l in 0..n_layers {
+            let [gate, up, down] = ffn(l);
+            let name = |s: &str| format!("model.layers.{l}.mlp.{s}.weight");
+            tensors.insert(name("gate_proj"), gate);
+            tensors.insert(name("up_proj"), up);
+            tensors.insert(name("down_proj"), down);
+        }
+        let mut config = moe.config.clone();
+        config["intermediate_size"] = json!(tensors["model.layers.0.mlp.up_proj.weight"].0[0]);
+        config.as_object_mut().unwrap().remove("num_local_experts");
+        config
+            .as_object_mut()
+            .unwrap()
+            .remove("num_experts_per_tok");
+        TinyModel { config, tensors }
+    };
+    let expert = |model: &TinyModel, l: usize, e: usize, w: &str| {
+        model.tensors[&format!("model.layers.{l}.block_sparse_moe.experts.{e}.{w}.weight")].clone()
+    };
+
+    // 所有专家相同时，无论路由到哪几个专家，加权和都等于稠密模型
+    let mut moe = TinyModel::new(
+        json!({ "num_local_experts": n_experts, "num_experts_per_tok": 2 }),
+        0x30e,
+    );
+    for l in 0..n_layers {
+        for e in 1..n_experts {
+            for w in ["w1", "w2", "w3"] {
+                let data = expert(&moe, l, 0, w);
+                moe.tensors.insert(
+                    format!("model.layers.{l}.block_sparse_moe.experts.{e}.{w}.weight"),
+                    data,
+                );
+            }
+        }
+    }
+    let dense = densify(&moe, &|l| ["w1", "w3", "w2"].map(|w| expert(&moe, l, 0, w)));
+    check(&moe, &dense, "moe-identical");
+
+    // 门控为零时每个专家的权重都是 1 / n_experts，
+    // 加权和等于把所有专家的隐藏层拼接起来、down 缩小 n_experts 倍的稠密模型
+    let mut moe = TinyModel::new(
+        json!({ "num_local_experts": n_experts, "num_experts_per_tok": n_experts }),
+        0x30f,
+    );
+    for l in 0..n_layers {
+        let gate = moe
+            .tensors
+            .get_mut(&format!("model.layers.{l}.block_sparse_moe.gate.weight"));
+        gate.unwrap().1.fill(0.);
+    }
+    let dense = densify(&moe, &|l| {
+        let stack = |w| {
+            let data = (0..n_experts)
+                .flat_map(|e| expert(&moe, l, e, w).1)
+                .collect();
+            (vec![n_experts * hidden_dim, dim], data)
+        };
+        let w2 = (0..n_experts)
+            .map(|e| expert(&moe, l, e, "w2").1)
+            .collect::<Vec<_>>();
+        let mut down = Vec::with_capacity(dim * n_experts * hidden_dim);
+        for row in 0..dim {
+            for w2 in &w2 {
+                let row = &slice!(w2; hidden_dim; [row]);
+                down.extend(row.iter().map(|x| x / n_experts as f32));
+            }
+        }
+        [
+            stack("w1"),
+            stack("w3"),
+            (vec![dim, n_experts * hidden_dim], down),
+        ]
+    });
+    check(&moe, &dense, "moe-uniform");
+}
+
+#[test]
+fn test_sliding_window() {
+    use crate::arguments::TinyModel;
+
+    // 只有一层时每个位置的 k、v 只取决于这个位置的 token，可以用无窗口的模型重算窗口内的注意力
+    let window = 20;
+    let full = TinyModel::new(serde_json::json!({ "num_hidden_layers": 1 }), 0x51d);
+    let mut windowed = TinyModel {
+        config: full.config.clone(),
+        tensors: full.tensors.clone(),
+    };
+    windowed.config["sliding_window"] = window.into();
+    let mut full = full.load("window-full");
+    let mut windowed = windowed.load("window-sliding");
+    let vocab_size = full.vocab_size();
+    let tokens = (0..50)
+        .map(|i| (i * 11 + i / 3) as utok % vocab_size as utok)
+        .collect::<Vec<_>>();
+
+    // 逐个 token 推理，滑出窗口的块被回收
+    let actual = tokens
+        .iter()
+        .enumerate()
+        .flat_map(|(pos, &t)| windowed.forward(t, pos as _, &mut ()).unwrap().to_vec())
+        .collect::<Vec<_>>();
+    assert_eq!(windowed.cache.start(), tokens.len() - window);
+    let expected = full.forward_all(&tokens, 0, &mut ()).unwrap();
+    let close =
+        |a: &[f32], b: &[f32], tolerance: f32| zip(a, b).all(|(a, b)| (a - b).abs() < tolerance);
+    for (pos, (a, b)) in zip(actual.chunks(vocab_size), expected.chunks(vocab_size)).enumerate() {
+        if pos < window {
+            // 窗口还没有填满时与完整的注意力相同
+            assert!(close(a, b, 1e-5), "pos {pos}");
+        } else {
+            // 窗口之外的位置不再参与注意力，rope 只与相对位置有关，
+            // 等于把窗口内的 token 从位置 0 开始输入无窗口的模型
+            assert!(!close(a, b, 1e-3), "pos {pos}");
+            let cache = full.new_cache();
+            let _ = full.replace_cache(cache);
+            let logits = full
+                .forward_all(&tokens[pos + 1 - window..=pos], 0, &mut ())
+                .unwrap();
+            assert!(
+                close(a, &logits[logits.len() - vocab_size..], 1e-3),
+                "pos {pos}"
+            );
+        }
+    }
+
+    // 不能推理或回退的位置报错，kv cache 不变，回退到 0 之后可以从头计算
+    let len = windowed.cache_len();
+    assert!(windowed
+        .forward(tokens[0], len as upos + 1, &mut ())
+        .is_err());
+    assert!(windowed.forward(tokens[0], 5, &mut ()).is_err());
+    assert!(windowed.forward_all(&tokens, len as _, &mut ()).is_err());
+    assert!(windowed.truncate(len + 1).is_err());
+    assert!(windowed.truncate(window).is_err());
+    assert_eq!(
+        (windowed.cache_len(), windowed.cache.start()),
+        (len, len - window)
+    );
+    windowed.truncate(0).unwrap();
+    let logits = windowed.forward(tokens[0], 0, &mut ()).unwrap().to_vec();
+    assert_eq!(logits, &actual[..vocab_size]);
+
+    // 位置不超过上下文长度，填满之后前移 kv cache 只保留窗口内的位置，之后的位置循环使用，
+    // 与把窗口内的 token 从位置 0 开始输入无窗口的模型相同
+    let seq_len = windowed.seq_len();
+    let long = (0..2 * seq_len)
+        .map(|i| tokens[i % tokens.len()])
+        .collect::<Vec<_>>();
+    windowed.update(&long[..seq_len], 0, &mut ()).unwrap();
+    assert!(windowed
+        .forward(long[seq_len], seq_len as _, &mut ())
+        .is_err());
+    let mut pos = seq_len;
+    for i in seq_len..long.len() {
+        if pos == seq_len {
+            windowed.shift_cache(0, seq_len - window, seq_len);
+            pos = window;
+        }
+        let a = windowed
+            .forward(long[i], pos as _, &mut ())
+            .unwrap()
+            .to_vec();
+        pos += 1;
+        let cache = full.new_cache();
+        let _ = full.replace_cache(cache);
+        let logits = full
+            .forward_all(&long[i + 1 - window..=i], 0, &mut ())
+            .unwrap();
+        assert!(close(&a, &logits[logits.len() - vocab_size..], 1e-3), "{i}");
+    }
+}
+
+#[test]
+fn test_architectures() {
+    use crate::arguments::TinyModel;
+    use serde_json::json;
+
+    // 与按 huggingface 的定义朴素计算的 logits 比较
+    let models = [
+        ("llama", json!({})),
+        // (1 + w) 的 rmsnorm、GeGLU、sqrt(hidden_size) 缩放的词嵌入、共享词嵌入，头大小不等于 hidden_size / n_heads
+        (
+            "gemma",
+            json!({ "architectures": ["GemmaForCausalLM"], "num_key_value_heads": 1, "head_dim": 8 }),
+        ),
+        // 带偏置的 layernorm、gelu、并行的注意力和前馈网络、一半维度旋转、所有线性层的偏置
+        (
+            "phi",
+            json!({ "architectures": ["PhiForCausalLM"], "hidden_size": 32, "layer_norm_eps": 1e-5 }),
+        ),
+        // llama 结构的部分维度旋转
+        (
+            "partial-rotary",
+            json!({ "hidden_size": 32, "partial_rotary_factor": 0.25 }),
+        ),
+    ];
+    let tokens = (0..20).map(|i| (i * 5 + 3) % 32).collect::<Vec<utok>>();
+    for (name, config) in models {
+        let model = TinyModel::new(config, 0xa7c4);
+        let mut transformer = model.load(&format!("arch-{name}"));
+        let actual = transformer.forward_all(&tokens, 0, &mut ()).unwrap();
+        let expected = model.forward(&tokens);
+        assert_eq!(actual.len(), expected.len());
+        for (a, b) in zip(actual, expected) {
+            assert!((a - b).abs() < 1e-4, "{name}: {a} != {b}");
+        }
     }
 }
@@ -0,0 +1,156 @@
+use super::utok;
+
+/// 按 token 序列保存 prefill 之后的 kv cache，供共享前缀的提示词复用。
+///
+/// 因为注意力是因果的，一个条目的 kv cache 对它的任何前缀都有效，所以查询时取公共前缀最长的条目。
+/// 总大小超过预算时淘汰最久未使用的条目。
+pub struct PrefixCache {
+    entries: Vec<Entry>,
+    /// 内存预算，字节。
+    capacity: usize,
+    /// 已使用的内存，字节。
+    used: usize,
+    /// 逻辑时钟，用于 LRU。
+    clock: u64,
+}
+
+pub(super) struct Entry {
+    pub tokens: Vec<utok>,
+    /// 每层的 key 和 value：`tokens.len() x kv_dim`。
+    pub layers: Vec<(Vec<f32>, Vec<f32>)>,
+    last_used: u64,
+}
+
+impl Entry {
+    #[inline]
+    fn size(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|(k, v)| std::mem::size_of_val(&k[..]) + std::mem::size_of_val(&v[..]))
+            .sum()
+    }
+}
+
+impl PrefixCache {
+    /// 创建预算为 `capacity` 字节的缓存。
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            capacity,
+            used: 0,
+            clock: 0,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 已使用的内存，字节。
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.used
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// 查找与 `tokens` 公共前缀最长的条目，返回条目和公共前缀的长度。
+    pub(super) fn lookup(&mut self, tokens: &[utok]) -> Option<(&Entry, usize)> {
+        self.clock += 1;
+        let (i, len) = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (i, common_prefix(&e.tokens, tokens)))
+            .max_by_key(|&(_, len)| len)
+            .filter(|&(_, len)| len > 0)?;
+        let entry = &mut self.entries[i];
+        entry.last_used = self.clock;
+        Some((entry, len))
+    }
+
+    /// 保存 `tokens` 的 kv cache，`layers` 按层给出 `tokens.len() x kv_dim` 的 key 和 value。
+    ///
+    /// 已有条目是新条目的前缀时被替换；新条目是已有条目的前缀时不重复保存。
+    pub(super) fn insert(
+        &mut self,
+        tokens: &[utok],
+        layers: impl FnOnce() -> Vec<(Vec<f32>, Vec<f32>)>,
+    ) {
+        self.clock += 1;
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|e| e.tokens.len() >= tokens.len() && e.tokens.starts_with(tokens))
+        {
+            entry.last_used = self.clock;
+            return;
+        }
+        let entry = Entry {
+            tokens: tokens.to_vec(),
+            layers: layers(),
+            last_used: self.clock,
+        };
+        let size = entry.size();
+        if size > self.capacity {
+            return;
+        }
+
+        let mut used = 0;
+        self.entries.retain(|e| {
+            let keep = !tokens.starts_with(&e.tokens);
+            if keep {
+                used += e.size();
+            }
+            keep
+        });
+        self.used = used;
+        while self.used + size > self.capacity {
+            let (i, _) = self
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, e)| e.last_used)
+                .unwrap();
+            self.used -= self.entries.swap_remove(i).size();
+        }
+        self.used += size;
+        self.entries.push(entry);
+    }
+}
+
+#[inline]
+fn common_prefix(a: &[utok], b: &[utok]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[test]
+fn test_lru() {
+    // 每个 token 占 2 x 4 字节
+    let layers = |n: usize| move || vec![(vec![0.; n], vec![0.; n])];
+    let mut cache = PrefixCache::new(8 * 6);
+
+    cache.insert(&[1, 2], layers(2));
+    cache.insert(&[1, 2, 3], layers(3));
+    // [1, 2] 被 [1, 2, 3] 替换
+    assert_eq!((cache.len(), cache.size()), (1, 24));
+    cache.insert(&[1, 2], layers(2));
+    assert_eq!(cache.len(), 1);
+
+    cache.insert(&[4, 5], layers(2));
+    assert_eq!(cache.lookup(&[1, 2, 9]).map(|(_, n)| n), Some(2));
+    // 超出预算，淘汰最久未使用的 [4, 5]
+    cache.insert(&[6, 7], layers(2));
+    assert_eq!((cache.len(), cache.size()), (2, 40));
+    assert!(cache.lookup(&[4, 5]).is_none());
+    assert_eq!(cache.lookup(&[1, 2, 3, 4]).map(|(_, n)| n), Some(3));
+}
@@ -1,88 +1,239 @@
-﻿use crate::{arguments::Arguments, kernel::slice};
+﻿use crate::{
+    arguments::{Arguments, RopeScaling},
+    kernel::slice,
+};
+use std::f32::consts::PI;
 
 pub(super) struct RunState {
     /// state buffer: `tok_len x dim`.
     pub x0: Vec<f32>,
     /// state buffer: `tok_len x dim`.
     pub x1: Vec<f32>,
-    /// query buffer: `tok_len x dim`.
+    /// query buffer: `tok_len x q_dim`, also the output of attention.
     pub q: Vec<f32>,
+    /// key buffer: `tok_len x kv_dim`.
+    pub k: Vec<f32>,
+    /// value buffer: `tok_len x kv_dim`.
+    pub v: Vec<f32>,
     /// hidden state buffer: `2 * tok_len x hidden_dim`.
     ///
     /// split to two buffers for using.
     pub hidden: Vec<f32>,
-    /// attention buffer: `n_heads x tok_len x seq_len`.
+    /// attention buffer: `n_heads x max_len x seq_len`, reused by each sequence.
     pub attention: Vec<f32>,
 }
 
 impl RunState {
-    pub fn new(
-        tok_len: usize,
-        dim: usize,
-        hidden_dim: usize,
-        n_heads: usize,
-        seq_len: usize,
-    ) -> Self {
+    pub fn new(arguments: &dyn Arguments, tok_len: usize, max_len: usize, seq_len: usize) -> Self {
+        let dim = arguments.dim();
+        let kv_dim = arguments.kv_dim();
         Self {
             x0: vec![0.; tok_len * dim],
             x1: vec![0.; tok_len * dim],
-            q: vec![0.; tok_len * dim],
-            hidden: vec![0.; tok_len * hidden_dim * 2],
-            attention: vec![0.; n_heads * tok_len * seq_len],
-        }
-    }
-}
-
-#[derive(Clone)]
-pub(super) struct Layer {
-    /// key cache: `seq_len x kv_dim`.
-    pub k_cache: Vec<f32>,
-    /// value cache: `seq_len x kv_dim`.
-    pub v_cache: Vec<f32>,
-}
-
-impl Layer {
-    pub fn new(config: &dyn Arguments) -> Self {
-        let len = config.seq_len() * config.kv_dim();
-        Self {
-            k_cache: vec![0.; len],
-            v_cache: vec![0.; len],
+            q: vec![0.; tok_len * arguments.q_dim()],
+            k: vec![0.; tok_len * kv_dim],
+            v: vec![0.; tok_len * kv_dim],
+            hidden: vec![0.; tok_len * arguments.hidden_dim() * 2],
+            attention: vec![0.; arguments.n_heads() * max_len * seq_len],
         }
     }
 }
 
 pub(super) struct RotaryEmbedder {
-    dim: usize,
+    head_size: usize,
+    /// 每个头中旋转的维度数，部分旋转的模型小于 `head_size`。
+    rotary_dim: usize,
     rotary: Vec<f32>,
 }
 
 impl RotaryEmbedder {
-    pub fn new(config: &dyn Arguments) -> Self {
-        let dim = config.dim();
-        let n_heads = config.n_heads();
-        let seq_len = config.seq_len();
-        let head_size = dim / n_heads;
-        let mut rotary = Vec::with_capacity(seq_len * dim);
+    /// 计算 `0..seq_len` 位置的旋转表，`seq_len` 可以超过模型的训练长度，此时应配合 rope 缩放使用。
+    pub fn new(config: &dyn Arguments, seq_len: usize) -> Self {
+        let head_size = config.head_size();
+        let rotary_dim = config.architecture().rotary_dim(head_size);
+        let (inv_freq, mscale) = frequencies(
+            config.rope_theta(),
+            config.rope_scaling(),
+            rotary_dim,
+            config.seq_len(),
+            seq_len,
+        );
+        let mut rotary = Vec::with_capacity(seq_len * rotary_dim);
         for pos in 0..seq_len {
-            for i in (0..dim).step_by(2) {
-                let freq = 1e4f32.powf(-((i % head_size) as f32 / head_size as f32));
+            for freq in &inv_freq {
                 let (sin, cos) = (pos as f32 * freq).sin_cos();
-                rotary.push(cos);
-                rotary.push(sin);
+                rotary.push(cos * mscale);
+                rotary.push(sin * mscale);
             }
         }
-        Self { dim, rotary }
+        Self {
+            head_size,
+            rotary_dim,
+            rotary,
+        }
+    }
+
+    /// 旋转表覆盖的位置数，即推理的上下文长度。
+    #[inline]
+    pub fn seq_len(&self) -> usize {
+        self.rotary.len() / self.rotary_dim
     }
 
+    /// 旋转 `data` 中每个头的前 `rotary_dim` 维。
     pub fn run(&self, pos: usize, data: &mut [f32]) {
-        let rotary = &slice!(self.rotary; self.dim; [pos]);
-        for i in 0..data.len() / 2 {
-            let x = &mut slice!(data; 2; [i]);
-            let w = &slice!(rotary; 2; [i]);
-            x.copy_from_slice(&[
-                x[0] * w[0] - x[1] * w[1], //
-                x[1] * w[0] + x[0] * w[1],
-            ]);
+        let rotary = &slice!(self.rotary; self.rotary_dim; [pos]);
+        for head in data.chunks_exact_mut(self.head_size) {
+            for i in 0..self.rotary_dim / 2 {
+                let x = &mut slice!(head; 2; [i]);
+                let w = &slice!(rotary; 2; [i]);
+                x.copy_from_slice(&[
+                    x[0] * w[0] - x[1] * w[1], //
+                    x[1] * w[0] + x[0] * w[1],
+                ]);
+            }
+        }
+    }
+
+    /// 反向旋转 `pos` 个位置，用于把已经旋转的 key 移动到更靠前的位置。
+    ///
+    /// YaRN 的旋转表带有缩放，反向旋转时除掉两次缩放，key 只保留一次。
+    pub fn run_inverse(&self, pos: usize, data: &mut [f32]) {
+        let rotary = &slice!(self.rotary; self.rotary_dim; [pos]);
+        for head in data.chunks_exact_mut(self.head_size) {
+            for i in 0..self.rotary_dim / 2 {
+                let x = &mut slice!(head; 2; [i]);
+                let w = &slice!(rotary; 2; [i]);
+                let norm = w[0] * w[0] + w[1] * w[1];
+                x.copy_from_slice(&[
+                    (x[0] * w[0] + x[1] * w[1]) / norm, //
+                    (x[1] * w[0] - x[0] * w[1]) / norm,
+                ]);
+            }
         }
     }
 }
+
+/// 计算每对维度的旋转频率和 YaRN 的注意力缩放，与 huggingface transformers 的 rope 初始化一致。
+///
+/// `rotary_dim` 为每个头中旋转的维度数。
+/// dynamic NTK 按推理时的上下文长度 `seq_len` 与训练长度 `trained` 计算一次 base，保证 kv cache 中所有位置使用同样的频率。
+fn frequencies(
+    theta: f32,
+    scaling: Option<RopeScaling>,
+    rotary_dim: usize,
+    trained: usize,
+    seq_len: usize,
+) -> (Vec<f32>, f32) {
+    let d = rotary_dim as f32;
+    let inv_freq = |theta: f32| {
+        (0..rotary_dim / 2)
+            .map(|j| theta.powf(-((2 * j) as f32 / d)))
+            .collect::<Vec<_>>()
+    };
+    match scaling {
+        None => (inv_freq(theta), 1.),
+        Some(RopeScaling::Linear { factor }) => {
+            let freq = inv_freq(theta).into_iter().map(|f| f / factor).collect();
+            (freq, 1.)
+        }
+        Some(RopeScaling::Dynamic { factor }) => {
+            let theta = if seq_len > trained {
+                let scale = factor * seq_len as f32 / trained as f32 - (factor - 1.);
+                theta * scale.powf(d / (d - 2.))
+            } else {
+                theta
+            };
+            (inv_freq(theta), 1.)
+        }
+        Some(RopeScaling::Yarn {
+            factor,
+            original_max_position_embeddings,
+            beta_fast,
+            beta_slow,
+            attention_factor,
+        }) => {
+            // 在训练长度内旋转 `rotations` 圈的维度
+            let correction = |rotations: f32| {
+                d * (original_max_position_embeddings as f32 / (rotations * 2. * PI)).ln()
+                    / (2. * theta.ln())
+            };
+            let low = correction(beta_fast).floor().max(0.);
+            let high = correction(beta_slow).ceil().min(d - 1.);
+            let high = if low == high { high + 1e-3 } else { high };
+            let freq = inv_freq(theta)
+                .into_iter()
+                .enumerate()
+                .map(|(j, f)| {
+                    let ramp = ((j as f32 - low) / (high - low)).clamp(0., 1.);
+                    f / factor * ramp + f * (1. - ramp)
+                })
+                .collect();
+            (freq, attention_factor)
+        }
+        Some(RopeScaling::Llama3 {
+            factor,
+            original_max_position_embeddings,
+            low_freq_factor,
+            high_freq_factor,
+        }) => {
+            let original = original_max_position_embeddings as f32;
+            let low_freq_wavelen = original / low_freq_factor;
+            let high_freq_wavelen = original / high_freq_factor;
+            let freq = inv_freq(theta)
+                .into_iter()
+                .map(|f| {
+                    let wavelen = 2. * PI / f;
+                    if wavelen < high_freq_wavelen {
+                        f
+                    } else if wavelen > low_freq_wavelen {
+                        f / factor
+                    } else {
+                        let smooth = (original / wavelen - low_freq_factor)
+                            / (high_freq_factor - low_freq_factor);
+                        (1. - smooth) * f / factor + smooth * f
+                    }
+                })
+                .collect();
+            (freq, 1.)
+        }
+    }
+}
+
+#[test]
+fn test_rope_scaling() {
+    let (base, _) = frequencies(1e4, None, 64, 2048, 2048);
+    assert_eq!(base[0], 1.);
+
+    let linear = Some(RopeScaling::Linear { factor: 4. });
+    let (freq, _) = frequencies(1e4, linear, 64, 2048, 8192);
+    assert!(std::iter::zip(&freq, &base).all(|(f, b)| *f == b / 4.));
+
+    // 训练长度内 dynamic NTK 不改变频率
+    let dynamic = Some(RopeScaling::Dynamic { factor: 2. });
+    assert_eq!(frequencies(1e4, dynamic, 64, 2048, 2048).0, base);
+    assert!(frequencies(1e4, dynamic, 64, 2048, 8192).0[31] < base[31]);
+
+    // YaRN 和 llama3 的高频维度不变，低频维度除以 factor
+    let yarn = Some(RopeScaling::Yarn {
+        factor: 4.,
+        original_max_position_embeddings: 2048,
+        beta_fast: 32.,
+        beta_slow: 1.,
+        attention_factor: 0.1 * 4f32.ln() + 1.,
+    });
+    let (freq, mscale) = frequencies(1e4, yarn, 64, 2048, 8192);
+    assert_eq!(freq[0], base[0]);
+    assert_eq!(freq[31], base[31] / 4.);
+    assert!((mscale - 1.138629).abs() < 1e-6);
+
+    let llama3 = Some(RopeScaling::Llama3 {
+        factor: 8.,
+        original_max_position_embeddings: 8192,
+        low_freq_factor: 1.,
+        high_freq_factor: 4.,
+    });
+    let (base, _) = frequencies(5e5, None, 128, 8192, 8192);
+    let (freq, _) = frequencies(5e5, llama3, 128, 131072, 131072);
+    assert_eq!(freq[0], base[0]);
+    assert_eq!(freq[63], base[63] / 8.);
+}